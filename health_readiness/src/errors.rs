@@ -11,6 +11,12 @@ pub enum HealthReadinessError {
     #[error("mqtt broker connection error")]
     MqttError,
 
+    #[error("messaging publisher circuit is open")]
+    CircuitOpenError,
+
+    #[error("messaging dispatcher is not running")]
+    DispatcherError,
+
     #[error("health readiness server error")]
     ServerError,
 }
@@ -1,4 +1,6 @@
 mod dynamodb;
+#[cfg(feature = "messaging")]
+mod messaging;
 #[cfg(feature = "mqtt")]
 mod mqtt;
 #[cfg(feature = "postgres")]
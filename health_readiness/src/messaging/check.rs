@@ -0,0 +1,72 @@
+use crate::{errors::HealthReadinessError, HealthChecker};
+use ::messaging::{
+    resilience::{CircuitBreaker, CircuitState},
+    supervisor::{DispatcherGroup, DispatcherStatus},
+};
+use std::sync::Arc;
+use tracing::debug;
+
+pub struct CircuitBreakerHealthChecker {
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakerHealthChecker {
+    pub fn new(breaker: Arc<CircuitBreaker>) -> Arc<CircuitBreakerHealthChecker> {
+        Arc::new(CircuitBreakerHealthChecker { breaker })
+    }
+}
+
+#[async_trait::async_trait]
+impl HealthChecker for CircuitBreakerHealthChecker {
+    fn name(&self) -> String {
+        format!("{} circuit breaker", self.breaker.name())
+    }
+
+    fn description(&self) -> String {
+        "Publisher circuit breaker health readiness".to_owned()
+    }
+
+    //the half-open circuit is probing the broker, it is not ready until closed
+    async fn check(&self) -> Result<(), HealthReadinessError> {
+        match self.breaker.state() {
+            CircuitState::Closed => Ok(()),
+            _ => Err(HealthReadinessError::CircuitOpenError),
+        }
+    }
+}
+
+pub struct DispatcherGroupHealthChecker {
+    group: Arc<DispatcherGroup>,
+}
+
+impl DispatcherGroupHealthChecker {
+    pub fn new(group: Arc<DispatcherGroup>) -> Arc<DispatcherGroupHealthChecker> {
+        Arc::new(DispatcherGroupHealthChecker { group })
+    }
+}
+
+#[async_trait::async_trait]
+impl HealthChecker for DispatcherGroupHealthChecker {
+    fn name(&self) -> String {
+        "Dispatchers health readiness".to_owned()
+    }
+
+    fn description(&self) -> String {
+        "Dispatchers health readiness".to_owned()
+    }
+
+    async fn check(&self) -> Result<(), HealthReadinessError> {
+        for (name, status) in self.group.statuses() {
+            if status != DispatcherStatus::Running {
+                debug!(
+                    name = name,
+                    status = format!("{:?}", status),
+                    "dispatcher not running"
+                );
+                return Err(HealthReadinessError::DispatcherError);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,14 +1,30 @@
 use crate::{errors::HealthReadinessError, HealthChecker};
+use ::rabbitmq::connection::{AmqpConnection, ConnectionStatus};
 use lapin::Connection;
 use std::sync::Arc;
+use tracing::warn;
+
+enum Checked {
+    Connection(Arc<Connection>),
+    Managed(Arc<AmqpConnection>),
+}
 
 pub struct RabbitMqHealthChecker {
-    conn: Arc<Connection>,
+    conn: Checked,
 }
 
 impl RabbitMqHealthChecker {
     pub fn new(conn: Arc<Connection>) -> Arc<RabbitMqHealthChecker> {
-        Arc::new(RabbitMqHealthChecker { conn })
+        Arc::new(RabbitMqHealthChecker {
+            conn: Checked::Connection(conn),
+        })
+    }
+
+    /// Checks the reconnecting connection, unhealthy while it is reconnecting.
+    pub fn managed(conn: Arc<AmqpConnection>) -> Arc<RabbitMqHealthChecker> {
+        Arc::new(RabbitMqHealthChecker {
+            conn: Checked::Managed(conn),
+        })
     }
 }
 
@@ -23,8 +39,21 @@ impl HealthChecker for RabbitMqHealthChecker {
     }
 
     async fn check(&self) -> Result<(), HealthReadinessError> {
-        let status = self.conn.status();
-        if status.connected() {
+        let connected = match &self.conn {
+            Checked::Connection(conn) => conn.status().connected(),
+            Checked::Managed(conn) => {
+                let status = conn.status();
+                if status != ConnectionStatus::Connected {
+                    warn!(
+                        status = format!("{:?}", status),
+                        "amqp connection not ready"
+                    );
+                }
+                status == ConnectionStatus::Connected && conn.is_connected()
+            }
+        };
+
+        if connected {
             return Ok(());
         }
 
@@ -1,4 +1,6 @@
 use crate::errors::HealthReadinessError;
+#[cfg(feature = "messaging")]
+use crate::messaging::{CircuitBreakerHealthChecker, DispatcherGroupHealthChecker};
 #[cfg(feature = "mqtt")]
 use crate::mqtt::MqttHealthChecker;
 #[cfg(feature = "postgres")]
@@ -10,8 +12,12 @@ use async_trait::async_trait;
 use deadpool_postgres::Pool;
 #[cfg(feature = "rabbitmq")]
 use lapin::Connection;
+#[cfg(feature = "messaging")]
+use messaging::{resilience::CircuitBreaker, supervisor::DispatcherGroup};
 #[cfg(feature = "mqtt")]
 use paho_mqtt::AsyncClient;
+#[cfg(feature = "rabbitmq")]
+use rabbitmq::connection::AmqpConnection;
 use std::{sync::Arc, vec};
 use tracing::error;
 #[async_trait]
@@ -53,11 +59,30 @@ impl HealthReadinessServiceImpl {
         self
     }
 
+    #[cfg(feature = "rabbitmq")]
+    pub fn amqp_connection(mut self, conn: Arc<AmqpConnection>) -> Self {
+        self.checkers.push(RabbitMqHealthChecker::managed(conn));
+        self
+    }
+
     #[cfg(feature = "postgres")]
     pub fn postgres(mut self, pool: Arc<Pool>) -> Self {
         self.checkers.push(PostgresHealthChecker::new(pool));
         self
     }
+
+    #[cfg(feature = "messaging")]
+    pub fn circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.checkers
+            .push(CircuitBreakerHealthChecker::new(breaker));
+        self
+    }
+
+    #[cfg(feature = "messaging")]
+    pub fn dispatcher_group(mut self, group: Arc<DispatcherGroup>) -> Self {
+        self.checkers.push(DispatcherGroupHealthChecker::new(group));
+        self
+    }
 }
 
 #[async_trait]
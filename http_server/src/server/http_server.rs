@@ -1,4 +1,6 @@
 use crate::errors::HTTPServerError;
+#[cfg(feature = "openapi")]
+use actix_web::{http::header::ContentType, HttpResponse};
 use actix_web::{
     http::KeepAlive,
     middleware::{self as actix_middleware, Logger},
@@ -16,6 +18,8 @@ use http_components::{
     CustomServiceConfigure,
 };
 use opentelemetry::global;
+#[cfg(feature = "openapi")]
+use serde_json::Value;
 use std::{sync::Arc, time::Duration};
 use tracing::error;
 #[cfg(feature = "openapi")]
@@ -23,11 +27,41 @@ use utoipa::openapi::OpenApi;
 #[cfg(feature = "openapi")]
 use utoipa_swagger_ui::SwaggerUi;
 
+/// Page rendering the AsyncAPI document with the given release of the AsyncAPI react
+/// component, the browser checks the assets against their integrity hashes.
+#[cfg(feature = "openapi")]
+fn asyncapi_ui(version: &str, script_integrity: &str, stylesheet_integrity: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <link rel="stylesheet" href="https://unpkg.com/@asyncapi/react-component@{version}/styles/default.min.css" integrity="{stylesheet_integrity}" crossorigin="anonymous" />
+  </head>
+  <body>
+    <div id="asyncapi"></div>
+    <script src="https://unpkg.com/@asyncapi/react-component@{version}/browser/standalone/index.js" integrity="{script_integrity}" crossorigin="anonymous"></script>
+    <script>
+      AsyncApiStandalone.render(
+        {{ schema: {{ url: "/asyncapi/asyncapi.json" }}, config: {{ show: {{ sidebar: true }} }} }},
+        document.getElementById("asyncapi"),
+      );
+    </script>
+  </body>
+</html>
+"#
+    )
+}
+
 pub struct HTTPServer {
     addr: String,
     services: Vec<Arc<CustomServiceConfigure>>,
     #[cfg(feature = "openapi")]
     openapi: Option<OpenApi>,
+    #[cfg(feature = "openapi")]
+    asyncapi: Option<Value>,
+    #[cfg(feature = "openapi")]
+    asyncapi_ui: Option<String>,
     health_check: Option<Arc<dyn HealthReadinessService>>,
 }
 
@@ -38,6 +72,10 @@ impl HTTPServer {
             services: vec![],
             #[cfg(feature = "openapi")]
             openapi: None,
+            #[cfg(feature = "openapi")]
+            asyncapi: None,
+            #[cfg(feature = "openapi")]
+            asyncapi_ui: None,
             health_check: None,
         }
     }
@@ -55,6 +93,27 @@ impl HTTPServer {
         self
     }
 
+    /// Serves the AsyncAPI document of the messaging at /asyncapi/asyncapi.json.
+    #[cfg(feature = "openapi")]
+    pub fn asyncapi(mut self, asyncapi: &Value) -> Self {
+        self.asyncapi = Some(asyncapi.to_owned());
+        self
+    }
+
+    /// Serves the UI of the AsyncAPI document at /asyncapi/, loading the given version of the
+    /// AsyncAPI react component from unpkg. The integrity hashes are the subresource integrity
+    /// of its browser/standalone/index.js and styles/default.min.css files, as sha384-<base64>.
+    #[cfg(feature = "openapi")]
+    pub fn asyncapi_ui(
+        mut self,
+        version: &str,
+        script_integrity: &str,
+        stylesheet_integrity: &str,
+    ) -> Self {
+        self.asyncapi_ui = Some(asyncapi_ui(version, script_integrity, stylesheet_integrity));
+        self
+    }
+
     pub fn health_check(mut self, service: Arc<dyn HealthReadinessService>) -> Self {
         self.health_check = Some(service);
         self
@@ -64,6 +123,10 @@ impl HTTPServer {
         ActixHttpServer::new({
             #[cfg(feature = "openapi")]
             let openapi = self.openapi.clone();
+            #[cfg(feature = "openapi")]
+            let asyncapi = self.asyncapi.clone();
+            #[cfg(feature = "openapi")]
+            let asyncapi_ui = self.asyncapi_ui.clone();
 
             let health_check_service = match self.health_check.clone() {
                 Some(check) => check,
@@ -102,6 +165,32 @@ impl HTTPServer {
                     );
                 }
 
+                #[cfg(feature = "openapi")]
+                if let Some(asyncapi) = asyncapi.clone() {
+                    app = app.route(
+                        "/asyncapi/asyncapi.json",
+                        web::get().to(move || {
+                            let asyncapi = asyncapi.clone();
+                            async move { HttpResponse::Ok().json(asyncapi) }
+                        }),
+                    );
+                }
+
+                #[cfg(feature = "openapi")]
+                if let Some(page) = asyncapi_ui.clone() {
+                    app = app.route(
+                        "/asyncapi/",
+                        web::get().to(move || {
+                            let page = page.clone();
+                            async move {
+                                HttpResponse::Ok()
+                                    .content_type(ContentType::html())
+                                    .body(page)
+                            }
+                        }),
+                    );
+                }
+
                 app.default_service(web::to(middlewares::not_found::not_found))
                     .wrap(Logger::default())
             }
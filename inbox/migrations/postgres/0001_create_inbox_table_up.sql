@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS inbox (
+    consumer VARCHAR NOT NULL,
+    message_id VARCHAR NOT NULL,
+    processed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (consumer, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS inbox_processed_at_idx ON inbox (processed_at);
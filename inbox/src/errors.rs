@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InboxError {
+    #[error("internal error")]
+    InternalError,
+
+    #[error("db connection error")]
+    DbConnectionErr,
+
+    #[error("delete error")]
+    DeleteErr,
+}
@@ -0,0 +1,6 @@
+pub mod errors;
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
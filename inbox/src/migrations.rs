@@ -0,0 +1,16 @@
+pub const INBOX_TABLE: &str = "inbox";
+
+/// Directories containing the inbox migrations, they can be given to the migrator crate.
+pub const POSTGRES_MIGRATIONS_PATH: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/migrations/postgres/");
+pub const SQLITE_MIGRATIONS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations/sqlite/");
+
+pub const POSTGRES_CREATE_INBOX_TABLE_UP: &str =
+    include_str!("../migrations/postgres/0001_create_inbox_table_up.sql");
+pub const POSTGRES_CREATE_INBOX_TABLE_DOWN: &str =
+    include_str!("../migrations/postgres/0001_create_inbox_table_down.sql");
+
+pub const SQLITE_CREATE_INBOX_TABLE_UP: &str =
+    include_str!("../migrations/sqlite/0001_create_inbox_table_up.sql");
+pub const SQLITE_CREATE_INBOX_TABLE_DOWN: &str =
+    include_str!("../migrations/sqlite/0001_create_inbox_table_down.sql");
@@ -0,0 +1,353 @@
+use crate::errors::InboxError;
+use async_trait::async_trait;
+use deadpool_postgres::{Pool, Transaction};
+use messaging::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    middlewares::ConsumerMiddleware,
+};
+use opentelemetry::Context;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+const MARK_QUERY: &str =
+    "INSERT INTO inbox (consumer, message_id) VALUES ($1, $2) ON CONFLICT DO NOTHING";
+const CLEANUP_QUERY: &str =
+    "DELETE FROM inbox WHERE processed_at < CURRENT_TIMESTAMP - make_interval(secs => $1)";
+
+/// Handler executed inside the transaction used to mark the message as processed,
+/// so the handler's own changes are committed together with the inbox mark.
+#[async_trait]
+pub trait TransactionalConsumerHandler: Send + Sync {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+        tx: &Transaction<'_>,
+    ) -> Result<Disposition, MessagingError>;
+}
+
+/// Deduplicates the consumed messages by recording the processed message ids in the inbox table.
+///
+/// The mark is committed only when the handler acks or rejects the message, retried and
+/// dead lettered messages are processed again when redelivered. Messages with no id are
+/// handled without deduplication.
+#[derive(Clone)]
+pub struct PostgresInbox {
+    pool: Arc<Pool>,
+    consumer: String,
+    retention: Duration,
+}
+
+enum InboxHandler {
+    Plain(Arc<dyn ConsumerHandler>),
+    Transactional(Arc<dyn TransactionalConsumerHandler>),
+}
+
+struct IdempotentHandler {
+    inbox: PostgresInbox,
+    handler: InboxHandler,
+}
+
+impl PostgresInbox {
+    pub fn new<T>(pool: Arc<Pool>, consumer: T) -> PostgresInbox
+    where
+        T: Into<String>,
+    {
+        PostgresInbox {
+            pool,
+            consumer: consumer.into(),
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn transactional(
+        &self,
+        handler: Arc<dyn TransactionalConsumerHandler>,
+    ) -> Arc<dyn ConsumerHandler> {
+        Arc::new(IdempotentHandler {
+            inbox: self.clone(),
+            handler: InboxHandler::Transactional(handler),
+        })
+    }
+
+    pub async fn run_cleanup(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            if let Err(err) = self.cleanup().await {
+                error!(error = err.to_string(), "failure to cleanup the inbox");
+            }
+        }
+    }
+
+    /// Removes the marks older than the retention window, returning how many were removed.
+    pub async fn cleanup(&self) -> Result<u64, InboxError> {
+        let conn = match self.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(InboxError::DbConnectionErr)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        match conn
+            .execute(CLEANUP_QUERY, &[&self.retention.as_secs_f64()])
+            .await
+        {
+            Err(err) => {
+                error!(error = err.to_string(), "error to cleanup the inbox");
+                Err(InboxError::DeleteErr)
+            }
+            Ok(count) => {
+                debug!(count = count, "inbox cleaned up");
+                Ok(count)
+            }
+        }
+    }
+}
+
+impl ConsumerMiddleware for PostgresInbox {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(IdempotentHandler {
+            inbox: self.clone(),
+            handler: InboxHandler::Plain(handler),
+        })
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for IdempotentHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let mut conn = match self.inbox.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(MessagingError::ConnectionError)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        let tx = match conn.transaction().await {
+            Err(err) => {
+                error!(error = err.to_string(), "error to begin transaction");
+                Err(MessagingError::InternalError)
+            }
+            Ok(tx) => Ok(tx),
+        }?;
+
+        match msg.message_id() {
+            Some(message_id) => {
+                match tx
+                    .execute(MARK_QUERY, &[&self.inbox.consumer, &message_id])
+                    .await
+                {
+                    Err(err) => {
+                        error!(error = err.to_string(), "error to mark the message");
+                        return Err(MessagingError::InternalError);
+                    }
+                    Ok(0) => {
+                        debug!(
+                            consumer = self.inbox.consumer,
+                            message_id = message_id,
+                            "message already processed, skipping"
+                        );
+                        return Ok(Disposition::Ack);
+                    }
+                    _ => {}
+                }
+            }
+            _ => warn!(
+                consumer = self.inbox.consumer,
+                msg_type = msg.msg_type,
+                "message with no id, skipping deduplication"
+            ),
+        }
+
+        let result = match &self.handler {
+            InboxHandler::Plain(handler) => handler.exec(ctx, msg).await,
+            InboxHandler::Transactional(handler) => handler.exec(ctx, msg, &tx).await,
+        };
+
+        match result {
+            Ok(Disposition::Ack) | Ok(Disposition::Reject) => {}
+            _ => {
+                if let Err(err) = tx.rollback().await {
+                    error!(error = err.to_string(), "error to rollback transaction");
+                }
+                return result;
+            }
+        };
+
+        match tx.commit().await {
+            Err(err) => {
+                error!(error = err.to_string(), "error to commit transaction");
+                Err(MessagingError::InternalError)
+            }
+            _ => result,
+        }
+    }
+}
+
+//run against the database configured by the POSTGRES_* env vars:
+//cargo test -p inbox --features postgres -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::POSTGRES_CREATE_INBOX_TABLE_UP;
+    use deadpool_postgres::{
+        tokio_postgres::{Config, NoTls},
+        Manager,
+    };
+    use messaging::handler::MESSAGE_ID_HEADER_KEY;
+    use std::{
+        collections::HashMap,
+        env,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    struct CountingHandler {
+        calls: AtomicUsize,
+        disposition: Option<Disposition>,
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for CountingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.disposition
+                .clone()
+                .ok_or(MessagingError::InternalError)
+        }
+    }
+
+    fn env_or(key: &str, default: &str) -> String {
+        env::var(key).unwrap_or_else(|_| default.to_owned())
+    }
+
+    //each test uses its own consumer, the marks of the previous runs are removed
+    async fn inbox(consumer: &str) -> PostgresInbox {
+        let mut cfg = Config::new();
+        cfg.host(env_or("POSTGRES_HOST", "localhost"));
+        cfg.port(env_or("POSTGRES_PORT", "5432").parse().unwrap());
+        cfg.dbname(env_or("POSTGRES_DB", "postgres"));
+        cfg.user(env_or("POSTGRES_USER", "postgres"));
+        cfg.password(env_or("POSTGRES_PASSWORD", "postgres"));
+
+        let pool = Pool::builder(Manager::new(cfg, NoTls)).build().unwrap();
+
+        let conn = pool.get().await.unwrap();
+        conn.batch_execute(POSTGRES_CREATE_INBOX_TABLE_UP)
+            .await
+            .unwrap();
+        conn.execute("DELETE FROM inbox WHERE consumer = $1", &[&consumer])
+            .await
+            .unwrap();
+
+        PostgresInbox::new(Arc::new(pool), consumer)
+    }
+
+    fn message(id: &str) -> ConsumerMessage {
+        let headers = HashMap::from([(MESSAGE_ID_HEADER_KEY.to_owned(), id.to_owned())]);
+        ConsumerMessage::new("orders", "created", b"data", Some(headers))
+    }
+
+    fn handler(disposition: Option<Disposition>) -> Arc<CountingHandler> {
+        Arc::new(CountingHandler {
+            calls: AtomicUsize::new(0),
+            disposition,
+        })
+    }
+
+    async fn calls_of_twice_consumed(consumer: &str, disposition: Option<Disposition>) -> usize {
+        let inbox = inbox(consumer).await;
+        let handler = handler(disposition);
+        let idempotent = inbox.wrap(handler.clone());
+
+        for _ in 0..2 {
+            let _ = idempotent.exec(&Context::new(), &message("id")).await;
+        }
+
+        handler.calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a postgres database"]
+    async fn should_skip_the_processed_messages() {
+        let calls =
+            calls_of_twice_consumed("should_skip_the_processed_messages", Some(Disposition::Ack))
+                .await;
+
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a postgres database"]
+    async fn should_process_the_retried_messages_again() {
+        let calls = calls_of_twice_consumed(
+            "should_process_the_retried_messages_again",
+            Some(Disposition::retry()),
+        )
+        .await;
+
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a postgres database"]
+    async fn should_process_the_dead_lettered_messages_again() {
+        let calls = calls_of_twice_consumed(
+            "should_process_the_dead_lettered_messages_again",
+            Some(Disposition::dead_letter("reason")),
+        )
+        .await;
+
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a postgres database"]
+    async fn should_process_the_failed_messages_again() {
+        let calls = calls_of_twice_consumed("should_process_the_failed_messages_again", None).await;
+
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a postgres database"]
+    async fn should_return_the_result_of_the_handler() {
+        let inbox = inbox("should_return_the_result_of_the_handler").await;
+
+        let res = inbox
+            .wrap(handler(Some(Disposition::dead_letter("reason"))))
+            .exec(&Context::new(), &message("dead"))
+            .await;
+        assert_eq!(res, Ok(Disposition::dead_letter("reason")));
+
+        let res = inbox
+            .wrap(handler(None))
+            .exec(&Context::new(), &message("failed"))
+            .await;
+        assert_eq!(res, Err(MessagingError::InternalError));
+    }
+}
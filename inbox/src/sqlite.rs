@@ -0,0 +1,413 @@
+use crate::errors::InboxError;
+use async_trait::async_trait;
+use deadpool_sqlite::{rusqlite::params, Object, Pool};
+use messaging::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    middlewares::ConsumerMiddleware,
+};
+use opentelemetry::Context;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+const MARK_QUERY: &str = "INSERT OR IGNORE INTO inbox (consumer, message_id) VALUES (?1, ?2)";
+const CLEANUP_QUERY: &str = "DELETE FROM inbox WHERE processed_at < datetime('now', ?1)";
+
+/// Handler executed while the transaction used to mark the message as processed is open,
+/// the changes made through the given connection are committed together with the inbox mark.
+#[async_trait]
+pub trait TransactionalConsumerHandler: Send + Sync {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+        conn: &Object,
+    ) -> Result<Disposition, MessagingError>;
+}
+
+/// Deduplicates the consumed messages by recording the processed message ids in the inbox table.
+///
+/// The mark is committed only when the handler acks or rejects the message, retried and
+/// dead lettered messages are processed again when redelivered. Messages with no id are
+/// handled without deduplication.
+#[derive(Clone)]
+pub struct SqliteInbox {
+    pool: Arc<Pool>,
+    consumer: String,
+    retention: Duration,
+}
+
+enum InboxHandler {
+    Plain(Arc<dyn ConsumerHandler>),
+    Transactional(Arc<dyn TransactionalConsumerHandler>),
+}
+
+struct IdempotentHandler {
+    inbox: SqliteInbox,
+    handler: InboxHandler,
+}
+
+impl SqliteInbox {
+    pub fn new<T>(pool: Arc<Pool>, consumer: T) -> SqliteInbox
+    where
+        T: Into<String>,
+    {
+        SqliteInbox {
+            pool,
+            consumer: consumer.into(),
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn transactional(
+        &self,
+        handler: Arc<dyn TransactionalConsumerHandler>,
+    ) -> Arc<dyn ConsumerHandler> {
+        Arc::new(IdempotentHandler {
+            inbox: self.clone(),
+            handler: InboxHandler::Transactional(handler),
+        })
+    }
+
+    pub async fn run_cleanup(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            if let Err(err) = self.cleanup().await {
+                error!(error = err.to_string(), "failure to cleanup the inbox");
+            }
+        }
+    }
+
+    /// Removes the marks older than the retention window, returning how many were removed.
+    pub async fn cleanup(&self) -> Result<u64, InboxError> {
+        let conn = match self.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(InboxError::DbConnectionErr)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        let modifier = format!("-{} seconds", self.retention.as_secs());
+
+        match conn
+            .interact(move |conn| conn.execute(CLEANUP_QUERY, [modifier]))
+            .await
+        {
+            Err(err) => {
+                error!(error = err.to_string(), "unsuspected error");
+                Err(InboxError::InternalError)
+            }
+            Ok(Err(err)) => {
+                error!(error = err.to_string(), "error to cleanup the inbox");
+                Err(InboxError::DeleteErr)
+            }
+            Ok(Ok(count)) => {
+                debug!(count = count, "inbox cleaned up");
+                Ok(count as u64)
+            }
+        }
+    }
+}
+
+impl ConsumerMiddleware for SqliteInbox {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(IdempotentHandler {
+            inbox: self.clone(),
+            handler: InboxHandler::Plain(handler),
+        })
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for IdempotentHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let conn = match self.inbox.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(MessagingError::ConnectionError)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        let tx = Transaction::begin(conn).await?;
+        let conn = tx.conn();
+
+        if let Some(message_id) = msg.message_id() {
+            let consumer = self.inbox.consumer.clone();
+            let id = message_id.to_owned();
+
+            let marked = match conn
+                .interact(move |conn| conn.execute(MARK_QUERY, params![consumer, id]))
+                .await
+            {
+                Ok(Ok(count)) => Ok(count),
+                Ok(Err(err)) => {
+                    error!(error = err.to_string(), "error to mark the message");
+                    Err(MessagingError::InternalError)
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "unsuspected error");
+                    Err(MessagingError::InternalError)
+                }
+            };
+
+            match marked {
+                Ok(0) => {
+                    debug!(
+                        consumer = self.inbox.consumer,
+                        message_id = message_id,
+                        "message already processed, skipping"
+                    );
+                    tx.finish("ROLLBACK TRANSACTION").await?;
+                    return Ok(Disposition::Ack);
+                }
+                Err(err) => {
+                    tx.finish("ROLLBACK TRANSACTION").await?;
+                    return Err(err);
+                }
+                _ => {}
+            }
+        } else {
+            warn!(
+                consumer = self.inbox.consumer,
+                msg_type = msg.msg_type,
+                "message with no id, skipping deduplication"
+            );
+        }
+
+        let result = match &self.handler {
+            InboxHandler::Plain(handler) => handler.exec(ctx, msg).await,
+            InboxHandler::Transactional(handler) => handler.exec(ctx, msg, conn).await,
+        };
+
+        match result {
+            Ok(Disposition::Ack) | Ok(Disposition::Reject) => {
+                tx.finish("COMMIT TRANSACTION").await?;
+            }
+            _ => {
+                tx.finish("ROLLBACK TRANSACTION").await?;
+            }
+        };
+
+        result
+    }
+}
+
+/// Transaction opened with a manual BEGIN, the connection is discarded instead of returned
+/// to the pool when the transaction is not finished, as when the handler panics or its future
+/// is dropped, closing the connection rolls the transaction back.
+struct Transaction {
+    conn: Option<Object>,
+}
+
+impl Transaction {
+    async fn begin(conn: Object) -> Result<Transaction, MessagingError> {
+        execute(&conn, "BEGIN TRANSACTION").await?;
+        Ok(Transaction { conn: Some(conn) })
+    }
+
+    fn conn(&self) -> &Object {
+        self.conn.as_ref().unwrap()
+    }
+
+    async fn finish(mut self, query: &'static str) -> Result<(), MessagingError> {
+        execute(self.conn(), query).await?;
+        //returned to the pool only once the transaction is closed
+        self.conn.take();
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            warn!("inbox transaction not finished, discarding the connection");
+            drop(Object::take(conn));
+        }
+    }
+}
+
+async fn execute(conn: &Object, query: &'static str) -> Result<(), MessagingError> {
+    match conn.interact(move |conn| conn.execute(query, [])).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => {
+            error!(
+                error = err.to_string(),
+                query = query,
+                "error to execute query"
+            );
+            Err(MessagingError::InternalError)
+        }
+        Err(err) => {
+            error!(error = err.to_string(), "unsuspected error");
+            Err(MessagingError::InternalError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::SQLITE_CREATE_INBOX_TABLE_UP;
+    use deadpool_sqlite::{Config, Runtime};
+    use messaging::handler::MESSAGE_ID_HEADER_KEY;
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    struct CountingHandler {
+        calls: AtomicUsize,
+        disposition: Disposition,
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for CountingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.disposition.clone())
+        }
+    }
+
+    struct PanickingHandler;
+
+    #[async_trait]
+    impl ConsumerHandler for PanickingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            panic!("handler failure")
+        }
+    }
+
+    //the in memory database is shared by the pooled connections while one of them is open
+    async fn inbox(name: &str) -> (SqliteInbox, Object) {
+        let pool = Config::new(format!("file:{}?mode=memory&cache=shared", name))
+            .create_pool(Runtime::Tokio1)
+            .unwrap();
+
+        let keeper = pool.get().await.unwrap();
+        keeper
+            .interact(|conn| conn.execute_batch(SQLITE_CREATE_INBOX_TABLE_UP))
+            .await
+            .unwrap()
+            .unwrap();
+
+        (SqliteInbox::new(Arc::new(pool), "consumer"), keeper)
+    }
+
+    fn message(id: &str) -> ConsumerMessage {
+        let headers = HashMap::from([(MESSAGE_ID_HEADER_KEY.to_owned(), id.to_owned())]);
+        ConsumerMessage::new("orders", "created", b"data", Some(headers))
+    }
+
+    fn handler(disposition: Disposition) -> Arc<CountingHandler> {
+        Arc::new(CountingHandler {
+            calls: AtomicUsize::new(0),
+            disposition,
+        })
+    }
+
+    #[tokio::test]
+    async fn should_skip_the_processed_messages() {
+        let (inbox, _keeper) = inbox("should_skip_the_processed_messages").await;
+        let handler = handler(Disposition::Ack);
+        let idempotent = inbox.wrap(handler.clone());
+
+        for _ in 0..2 {
+            let res = idempotent.exec(&Context::new(), &message("id")).await;
+            assert_eq!(res.unwrap(), Disposition::Ack);
+        }
+
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn should_process_the_retried_messages_again() {
+        let (inbox, _keeper) = inbox("should_process_the_retried_messages_again").await;
+        let handler = handler(Disposition::Retry { after: None });
+        let idempotent = inbox.wrap(handler.clone());
+
+        for _ in 0..2 {
+            idempotent
+                .exec(&Context::new(), &message("id"))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn should_rollback_the_mark_of_the_panicking_handler() {
+        let (inbox, _keeper) = inbox("should_rollback_the_mark_of_the_panicking_handler").await;
+
+        let panicking = inbox.wrap(Arc::new(PanickingHandler));
+        let res =
+            tokio::spawn(async move { panicking.exec(&Context::new(), &message("id")).await })
+                .await;
+        assert!(res.is_err());
+
+        let handler = handler(Disposition::Ack);
+        let res = inbox
+            .wrap(handler.clone())
+            .exec(&Context::new(), &message("id"))
+            .await;
+
+        assert_eq!(res.unwrap(), Disposition::Ack);
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn should_remove_the_marks_older_than_the_retention() {
+        let (inbox, keeper) = inbox("should_remove_the_marks_older_than_the_retention").await;
+        keeper
+            .interact(|conn| {
+                conn.execute(
+                    "INSERT INTO inbox (consumer, message_id, processed_at) VALUES ('consumer', 'old', datetime('now', '-2 days'))",
+                    [],
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        inbox
+            .wrap(handler(Disposition::Ack))
+            .exec(&Context::new(), &message("new"))
+            .await
+            .unwrap();
+
+        let removed = inbox
+            .retention(Duration::from_secs(24 * 60 * 60))
+            .cleanup()
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 1);
+    }
+}
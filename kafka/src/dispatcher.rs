@@ -1,32 +1,71 @@
 use async_trait::async_trait;
+use chrono::DateTime;
 use configs::{Configs, DynamicConfigs, Environment};
 use messaging::{
+    batch::{self, Batch, BatchConsumerHandler},
+    cloudevents, compression,
     dispatcher::{Dispatcher, DispatcherDefinition},
     errors::MessagingError,
-    handler::{ConsumerHandler, ConsumerMessage},
+    handler::{
+        ConsumerHandler, ConsumerMessage, Disposition, MessageMetadata, MESSAGE_ID_HEADER_KEY,
+    },
+    headers,
+    metrics::MessagingMetrics,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
+    shutdown::{drain, CancellationToken, Deadline, DEFAULT_SHUTDOWN_TIMEOUT},
+    workers::Workers,
 };
 use opentelemetry::{
     global::{self, BoxedTracer},
     Context,
 };
 use rdkafka::{
-    consumer::StreamConsumer,
-    message::{BorrowedHeaders, Headers},
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    message::{BorrowedHeaders, BorrowedMessage, Headers},
     ClientConfig, Message,
 };
 use std::str;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tracing::{debug, error, warn};
 
-use crate::otel;
+use crate::{offsets::Offsets, otel, publisher::CONTENT_TYPE_HEADER_KEY};
+
+/// Header carrying the reason of the dead lettered messages.
+pub const DEAD_LETTER_REASON_HEADER_KEY: &str = "dead-letter-reason";
+
+/// Headers carrying where the dead lettered message was consumed from.
+pub const DEAD_LETTER_TOPIC_HEADER_KEY: &str = "dead-letter-topic";
+pub const DEAD_LETTER_PARTITION_HEADER_KEY: &str = "dead-letter-partition";
+pub const DEAD_LETTER_OFFSET_HEADER_KEY: &str = "dead-letter-offset";
 
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Ordered definitions handle the messages of the same partition sequentially. Otherwise with
+/// a concurrency greater than 1 the messages are handled out of order, but the offsets are
+/// only stored up to the first message of the partition not handled yet.
 pub struct KafkaDispatcher {
     consumer: Arc<StreamConsumer>,
     dispatchers: HashMap<String, Arc<dyn ConsumerHandler>>,
+    batch_dispatchers: HashMap<String, Arc<dyn BatchConsumerHandler>>,
+    definitions: HashMap<String, DispatcherDefinition>,
+    shutdown_timeout: Duration,
+    unmatched: Disposition,
+    max_attempts: u32,
+    dead_letter: Option<(String, Arc<dyn Publisher>)>,
+    metrics: MessagingMetrics,
+    consuming: AtomicBool,
 }
 
 impl KafkaDispatcher {
-    pub fn new<T>(cfgs: &Configs<T>) -> Result<Arc<Self>, MessagingError>
+    pub fn new<T>(cfgs: &Configs<T>) -> Result<Self, MessagingError>
     where
         T: DynamicConfigs,
     {
@@ -41,6 +80,7 @@ impl KafkaDispatcher {
                 format!("{}:{}", cfgs.kafka.host, cfgs.kafka.port),
             )
             .set("client.id", cfgs.app.name.clone())
+            .set("enable.auto.offset.store", "false")
             .set("message.timeout.ms", cfgs.kafka.timeout.to_string())
             .set("security.protocol", cfgs.kafka.security_protocol.clone()) //security.protocol=SASL_PLAINTEXT or SASL_SSL
             .set("sasl.mechanism", cfgs.kafka.sasl_mechanisms.clone()) //sasl.mechanism=PLAIN
@@ -56,10 +96,48 @@ impl KafkaDispatcher {
             }
         }?;
 
-        Ok(Arc::new(Self {
+        Ok(Self {
             consumer: Arc::new(consumer),
             dispatchers: HashMap::new(),
-        }))
+            batch_dispatchers: HashMap::new(),
+            definitions: HashMap::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            unmatched: Disposition::Ack,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            dead_letter: None,
+            metrics: MessagingMetrics::new("kafka"),
+            consuming: AtomicBool::new(false),
+        })
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Disposition of the messages with no handler registered for their msg_type,
+    /// defaults to ack, skipping them.
+    pub fn unmatched(mut self, disposition: Disposition) -> Self {
+        self.unmatched = disposition;
+        self
+    }
+
+    /// Deliveries of a retried message, including the first one, before it is dead lettered,
+    /// defaults to 5.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Topic receiving the dead lettered messages, with the original payload and headers and
+    /// the dead-letter-* headers. The offset is only stored once the message is published,
+    /// without a topic the dead lettered messages are dropped.
+    pub fn dead_letter<T>(mut self, topic: T, publisher: Arc<dyn Publisher>) -> Self
+    where
+        T: Into<String>,
+    {
+        self.dead_letter = Some((topic.into(), publisher));
+        self
     }
 }
 
@@ -70,106 +148,584 @@ impl Dispatcher for KafkaDispatcher {
         definition: &DispatcherDefinition,
         handler: Arc<dyn ConsumerHandler>,
     ) -> Self {
+        if self.batch_dispatchers.contains_key(&definition.msg_type) {
+            error!(
+                msg_type = definition.msg_type,
+                "the msg_type already has a batch handler, ignoring the handler"
+            );
+            return self;
+        }
+
         self.dispatchers
             .insert(definition.msg_type.clone(), handler);
+        self.definitions
+            .insert(definition.msg_type.clone(), definition.clone());
 
         self
     }
 
-    async fn consume_blocking(&self) -> Result<(), MessagingError> {
-        tokio::spawn({
-            let consumer = self.consumer.clone();
-            let dispatchers = self.dispatchers.clone();
-            let tracer = global::tracer("kafka-consume-blocking");
+    fn register_batch(
+        mut self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn BatchConsumerHandler>,
+    ) -> Self {
+        if self.dispatchers.contains_key(&definition.msg_type) {
+            error!(
+                msg_type = definition.msg_type,
+                "the msg_type already has a handler, ignoring the batch handler"
+            );
+            return self;
+        }
+
+        self.batch_dispatchers
+            .insert(definition.msg_type.clone(), handler);
+        self.definitions
+            .insert(definition.msg_type.clone(), definition.clone());
+
+        self
+    }
 
-            async move {
-                loop {
-                    let received = match consumer.recv().await {
-                        Ok(m) => m,
-                        Err(err) => {
-                            error!(error = err.to_string(), "failure to consume message");
+    async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+        let consumer = self.consumer.clone();
+        let offsets = Offsets::new(&consumer);
+        let dispatchers = &self.dispatchers;
+        let definitions = &self.definitions;
+        let tracer = global::tracer("kafka-consume-blocking");
+        let mut workers = Workers::new();
+        let deadline = Deadline::new(&shutdown, self.shutdown_timeout);
+        //the batched messages stay pending in the offsets until their batch is handled, so the
+        //other msg_types of the partition do not store the offsets past them
+        let mut batches: HashMap<&str, Batch<BatchItem>> = self
+            .batch_dispatchers
+            .keys()
+            .map(|msg_type| {
+                let definition = &definitions[msg_type];
+                (
+                    msg_type.as_str(),
+                    Batch::new(definition.batch_size, definition.batch_linger),
+                )
+            })
+            .collect();
+
+        self.consuming.store(true, Ordering::SeqCst);
+
+        'consume: loop {
+            let next_deadline = batches.values().filter_map(|batch| batch.deadline()).min();
+
+            let received = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                Some(_) = workers.next() => continue,
+                _ = batch::linger(next_deadline) => {
+                    for (msg_type, batch) in batches.iter_mut() {
+                        if !batch.is_expired() {
                             continue;
                         }
-                    };
-
-                    let topic = received.topic();
-
-                    debug!("topic: {} - received message", topic);
-
-                    let msg_type = match received.key() {
-                        Some(k) => match str::from_utf8(k) {
-                            Ok(tpy) => tpy,
-                            Err(err) => {
-                                error!(
-                                    error = err.to_string(),
-                                    topic = topic,
-                                    "key conversion to utf8 error"
-                                );
-                                continue;
-                            }
-                        },
-                        _ => {
-                            error!(
-                                topic = topic,
-                                "ignoring message - message with no key (msg_type)"
-                            );
-                            continue;
+
+                        let flushed = self
+                            .flush(&mut workers, &offsets, msg_type, batch.take(), &shutdown, &deadline)
+                        .await;
+                        if !flushed {
+                            break 'consume;
                         }
-                    };
+                    }
+                    continue;
+                },
+                received = consumer.recv() => received,
+            };
+
+            let received = match received {
+                Ok(m) => m,
+                Err(err) => {
+                    error!(error = err.to_string(), "failure to consume message");
+                    continue;
+                }
+            };
 
-                    if received.payload().is_none() {
-                        warn!(
+            offsets.received(&received);
+            let topic = received.topic();
+
+            debug!("topic: {} - received message", topic);
+
+            let msg_type = match received.key() {
+                Some(k) => match str::from_utf8(k) {
+                    Ok(tpy) => tpy,
+                    Err(err) => {
+                        error!(
+                            error = err.to_string(),
                             topic = topic,
-                            msg_type = msg_type,
-                            "ignoring msg - message with no payload"
+                            "key conversion to utf8 error"
                         );
+                        offsets.store(&received);
                         continue;
                     }
+                },
+                _ => {
+                    error!(
+                        topic = topic,
+                        "ignoring message - message with no key (msg_type)"
+                    );
+                    offsets.store(&received);
+                    continue;
+                }
+            };
 
-                    let handler = match dispatchers.get(msg_type) {
-                        Some(h) => h,
-                        _ => {
-                            warn!(
-                                topic = topic,
-                                msg_type = msg_type,
-                                "ignoring message - there is no handler registered for this msg_type",
-                            );
+            self.metrics.received(topic, msg_type);
 
-                            continue;
-                        }
-                    };
-
-                    let (ctx, headers) = explode(topic, msg_type, &tracer, received.headers());
-                    let consumer_msg =
-                        ConsumerMessage::new(topic, msg_type, received.payload().unwrap(), headers);
-
-                    match handler.exec(&ctx, &consumer_msg).await {
-                        Err(err) => {
-                            error!(
-                                error = err.to_string(),
-                                topic = topic,
-                                msg_type = msg_type,
-                                "error whiling processing message"
-                            );
-                            continue;
-                        }
-                        _ => {
-                            debug!(
-                                topic = topic,
-                                msg_type = msg_type,
-                                "message processed succeffly"
-                            )
-                        }
-                    };
+            if received.payload().is_none() {
+                warn!(
+                    topic = topic,
+                    msg_type = msg_type,
+                    "ignoring msg - message with no payload"
+                );
+                offsets.store(&received);
+                continue;
+            }
+
+            if let Some(batch) = batches.get_mut(msg_type) {
+                let (ctx, headers) = explode(topic, msg_type, &tracer, received.headers());
+                let mut consumer_msg = consumer_message(&received, msg_type, headers);
+                consumer_msg.metadata.delivery_count = offsets.attempts(&received);
+                let msg_type = consumer_msg.msg_type.clone();
+
+                batch.push((received, ctx, consumer_msg));
+                if !batch.is_full() {
+                    continue;
+                }
+
+                let flushed = self
+                    .flush(
+                        &mut workers,
+                        &offsets,
+                        &msg_type,
+                        batch.take(),
+                        &shutdown,
+                        &deadline,
+                    )
+                    .await;
+                if !flushed {
+                    break;
+                }
+                continue;
+            }
+
+            let handler = match dispatchers.get(msg_type) {
+                Some(h) => h,
+                _ => {
+                    warn!(
+                        topic = topic,
+                        msg_type = msg_type,
+                        disposition = format!("{:?}", self.unmatched),
+                        "there is no handler registered for this msg_type",
+                    );
+
+                    let (_, headers) = explode(topic, msg_type, &tracer, received.headers());
+                    let mut consumer_msg = consumer_message(&received, msg_type, headers);
+                    consumer_msg.metadata.delivery_count = offsets.attempts(&received);
+                    self.dispose(
+                        &offsets,
+                        &received,
+                        &consumer_msg,
+                        self.unmatched.clone(),
+                        &shutdown,
+                    )
+                    .await;
+                    continue;
                 }
+            };
+
+            let definition = &definitions[msg_type];
+            let key = definition
+                .ordered
+                .then(|| format!("{}/{}", topic, received.partition()));
+
+            let reserved = drain(
+                &deadline,
+                workers.reserve(msg_type, definition.concurrency, key.as_deref()),
+            )
+            .await;
+            //abandoned messages have no offset stored, being consumed again after the restart
+            if reserved.is_none() {
+                break;
             }
-        });
+
+            let (ctx, headers) = explode(topic, msg_type, &tracer, received.headers());
+            let mut consumer_msg = consumer_message(&received, msg_type, headers);
+            consumer_msg.metadata.delivery_count = offsets.attempts(&received);
+
+            let pool = msg_type.to_owned();
+            workers.spawn(
+                &pool,
+                key.as_deref(),
+                self.handle(&offsets, handler, received, ctx, consumer_msg, &shutdown),
+            );
+        }
+
+        self.consuming.store(false, Ordering::SeqCst);
+        drain(&deadline, workers.wait_all()).await;
+
+        //commits the offsets stored by the processed messages before leaving the group
+        if let Err(err) = consumer.commit_consumer_state(CommitMode::Sync) {
+            warn!(
+                error = err.to_string(),
+                "failure to commit the consumer offsets"
+            );
+        }
+        consumer.unsubscribe();
+
+        debug!("kafka consumer stopped");
 
         Ok(())
     }
+
+    fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::SeqCst)
+    }
+}
+
+type BatchItem<'a> = (BorrowedMessage<'a>, Context, ConsumerMessage);
+
+impl KafkaDispatcher {
+    async fn handle(
+        &self,
+        offsets: &Offsets<'_>,
+        handler: &Arc<dyn ConsumerHandler>,
+        received: BorrowedMessage<'_>,
+        ctx: Context,
+        consumer_msg: ConsumerMessage,
+        shutdown: &CancellationToken,
+    ) {
+        let topic = received.topic();
+        let msg_type = consumer_msg.msg_type.as_str();
+
+        //handler errors keep the previous behavior, skipping the message
+        let disposition = match compression::undecompressed(&consumer_msg) {
+            Some(disposition) => disposition,
+            None => match self
+                .metrics
+                .exec(handler.as_ref(), &ctx, &consumer_msg)
+                .await
+            {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        topic = topic,
+                        msg_type = msg_type,
+                        "error whiling processing message"
+                    );
+                    Disposition::Reject
+                }
+                Ok(disposition) => disposition,
+            },
+        };
+
+        self.dispose(offsets, &received, &consumer_msg, disposition, shutdown)
+            .await;
+    }
+
+    async fn dispose(
+        &self,
+        offsets: &Offsets<'_>,
+        received: &BorrowedMessage<'_>,
+        consumer_msg: &ConsumerMessage,
+        disposition: Disposition,
+        shutdown: &CancellationToken,
+    ) {
+        let topic = received.topic();
+        let msg_type = consumer_msg.msg_type.as_str();
+
+        match self.escalate(consumer_msg, disposition) {
+            Disposition::Ack => {
+                debug!(
+                    topic = topic,
+                    msg_type = msg_type,
+                    "message processed succeffly"
+                );
+                offsets.store(received);
+            }
+            Disposition::Reject => {
+                warn!(
+                    topic = topic,
+                    msg_type = msg_type,
+                    "message rejected, skipping it"
+                );
+                offsets.store(received);
+            }
+            Disposition::DeadLetter { reason } => {
+                if self
+                    .publish_dead_letter(received, consumer_msg, &reason)
+                    .await
+                {
+                    offsets.store(received);
+                } else {
+                    offsets.seek_back(received);
+                }
+            }
+            Disposition::Retry { after } => {
+                warn!(
+                    topic = topic,
+                    msg_type = msg_type,
+                    attempt = consumer_msg.metadata.delivery_count,
+                    "requeuing message, seeking back to the message offset"
+                );
+                self.metrics.retried(consumer_msg);
+                if let Some(after) = after {
+                    tokio::select! {
+                        _ = tokio::time::sleep(after) => {},
+                        _ = shutdown.cancelled() => {},
+                    }
+                }
+                offsets.seek_back(received);
+            }
+        };
+    }
+
+    //the retries past the max attempts are dead lettered, a poison message would stall the partition
+    fn escalate(&self, consumer_msg: &ConsumerMessage, disposition: Disposition) -> Disposition {
+        match disposition {
+            Disposition::Retry { .. }
+                if consumer_msg.metadata.delivery_count >= self.max_attempts =>
+            {
+                warn!(
+                    msg_type = consumer_msg.msg_type,
+                    attempts = consumer_msg.metadata.delivery_count,
+                    "message retried too many times, dead lettering it"
+                );
+                Disposition::dead_letter("max attempts exceeded")
+            }
+            disposition => disposition,
+        }
+    }
+
+    /// Publishes the message to the dead letter topic, returns false when it must be consumed
+    /// again because the publish failed.
+    async fn publish_dead_letter(
+        &self,
+        received: &BorrowedMessage<'_>,
+        consumer_msg: &ConsumerMessage,
+        reason: &str,
+    ) -> bool {
+        let topic = received.topic();
+        let msg_type = consumer_msg.msg_type.as_str();
+        self.metrics.dead_lettered(consumer_msg);
+
+        let Some((dead_letter_topic, publisher)) = &self.dead_letter else {
+            error!(
+                topic = topic,
+                msg_type = msg_type,
+                reason = reason,
+                "message dead lettered with no dead letter topic, dropping it"
+            );
+            return true;
+        };
+
+        let mut headers = consumer_msg.metadata.headers.clone();
+        headers.insert(DEAD_LETTER_REASON_HEADER_KEY.to_owned(), reason.into());
+        headers.insert(DEAD_LETTER_TOPIC_HEADER_KEY.to_owned(), topic.into());
+        headers.insert(
+            DEAD_LETTER_PARTITION_HEADER_KEY.to_owned(),
+            HeaderValue::LongInt(received.partition()),
+        );
+        headers.insert(
+            DEAD_LETTER_OFFSET_HEADER_KEY.to_owned(),
+            HeaderValue::LongLongInt(received.offset()),
+        );
+
+        //the original payload, still compressed when it was
+        let mut msg = PublishMessage::new(
+            "",
+            dead_letter_topic,
+            msg_type,
+            msg_type,
+            received.payload().unwrap_or_default(),
+            Some(headers),
+        );
+        msg.content_type = consumer_msg.metadata.content_type.clone();
+
+        match publisher.publish(&Context::new(), &msg).await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    topic = topic,
+                    msg_type = msg_type,
+                    "failure to publish the dead lettered message, consuming it again"
+                );
+                false
+            }
+            Ok(()) => {
+                warn!(
+                    topic = topic,
+                    msg_type = msg_type,
+                    reason = reason,
+                    dead_letter_topic = dead_letter_topic,
+                    "message dead lettered"
+                );
+                true
+            }
+        }
+    }
+
+    /// Hands the batch to the workers, returns false when the shutdown timeout elapsed
+    /// waiting the previous batch of the same msg_type.
+    async fn flush<'a>(
+        &'a self,
+        workers: &mut Workers<'a>,
+        offsets: &'a Offsets<'a>,
+        msg_type: &str,
+        items: Vec<BatchItem<'a>>,
+        shutdown: &'a CancellationToken,
+        deadline: &Deadline,
+    ) -> bool {
+        //batches of the same msg_type are handled sequentially, keeping the offsets in order
+        let reserved = drain(deadline, workers.reserve(msg_type, 1, None)).await;
+        if reserved.is_none() {
+            return false;
+        }
+
+        let handler = &self.batch_dispatchers[msg_type];
+        workers.spawn(
+            msg_type,
+            None,
+            self.handle_batch(offsets, handler, items, shutdown),
+        );
+
+        true
+    }
+
+    async fn handle_batch(
+        &self,
+        offsets: &Offsets<'_>,
+        handler: &Arc<dyn BatchConsumerHandler>,
+        items: Vec<BatchItem<'_>>,
+        shutdown: &CancellationToken,
+    ) {
+        let mut received = Vec::with_capacity(items.len());
+        let mut msgs = Vec::with_capacity(items.len());
+        let mut ctx = Context::new();
+
+        for (idx, (message, message_ctx, msg)) in items.into_iter().enumerate() {
+            if idx == 0 {
+                ctx = message_ctx;
+            }
+            received.push(message);
+            msgs.push(msg);
+        }
+
+        let ctx = &ctx;
+        let results = compression::exec_batch(&msgs, |msgs| async move {
+            self.metrics.exec_batch(handler.as_ref(), ctx, &msgs).await
+        })
+        .await;
+
+        //after a retry the following messages of the partition are consumed again, so their offsets are not stored
+        let mut retrying = HashSet::new();
+        let mut retry_after = None;
+        let mut seek_backs = vec![];
+
+        for ((message, msg), result) in received.iter().zip(&msgs).zip(results) {
+            let partition = (message.topic(), message.partition());
+            if retrying.contains(&partition) {
+                continue;
+            }
+
+            let disposition = match result {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        topic = message.topic(),
+                        msg_type = msg.msg_type,
+                        "error whiling processing message"
+                    );
+                    Disposition::Reject
+                }
+                Ok(disposition) => disposition,
+            };
+
+            match self.escalate(msg, disposition) {
+                Disposition::Retry { after } => {
+                    warn!(
+                        topic = message.topic(),
+                        msg_type = msg.msg_type,
+                        attempt = msg.metadata.delivery_count,
+                        "requeuing message, seeking back to the message offset"
+                    );
+                    self.metrics.retried(msg);
+                    retrying.insert(partition);
+                    retry_after = retry_after.max(after);
+                    seek_backs.push(message);
+                }
+                Disposition::DeadLetter { reason } => {
+                    if self.publish_dead_letter(message, msg, &reason).await {
+                        offsets.store(message);
+                    } else {
+                        retrying.insert(partition);
+                        seek_backs.push(message);
+                    }
+                }
+                _ => offsets.store(message),
+            }
+        }
+
+        if let Some(after) = retry_after {
+            tokio::select! {
+                _ = tokio::time::sleep(after) => {},
+                _ = shutdown.cancelled() => {},
+            }
+        }
+
+        for message in seek_backs {
+            offsets.seek_back(message);
+        }
+    }
+}
+
+pub(crate) fn consumer_message(
+    received: &BorrowedMessage,
+    msg_type: &str,
+    mut headers: Option<HashMap<String, String>>,
+) -> ConsumerMessage {
+    //kafka headers are bytes, the types come with the header-types header
+    let typed = headers
+        .as_mut()
+        .map(headers::from_strings)
+        .unwrap_or_default();
+
+    let header = |key: &str| {
+        headers
+            .as_ref()
+            .and_then(|headers| headers.get(key))
+            .cloned()
+    };
+
+    let metadata = MessageMetadata {
+        message_id: header(MESSAGE_ID_HEADER_KEY),
+        correlation_id: header(CORRELATION_ID_HEADER_KEY),
+        reply_to: header(REPLY_TO_HEADER_KEY),
+        content_type: header(CONTENT_TYPE_HEADER_KEY),
+        timestamp: received
+            .timestamp()
+            .to_millis()
+            .and_then(DateTime::from_timestamp_millis),
+        delivery_count: 1,
+        partition: Some(received.partition()),
+        offset: Some(received.offset()),
+        headers: typed,
+        ..Default::default()
+    };
+    let content_type = metadata.content_type.clone();
+
+    let msg = ConsumerMessage::new(
+        received.topic(),
+        msg_type,
+        received.payload().unwrap_or_default(),
+        headers,
+    )
+    .with_metadata(metadata);
+
+    cloudevents::parse(compression::decompress(msg), content_type.as_deref())
 }
 
-fn explode(
+pub(crate) fn explode(
     topic: &str,
     msg_type: &str,
     tracer: &BoxedTracer,
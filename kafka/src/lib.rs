@@ -1,5 +1,7 @@
 pub mod connection;
 pub mod dispatcher;
 pub mod errors;
+mod offsets;
 pub mod otel;
 pub mod publisher;
+pub mod request_reply;
@@ -0,0 +1,201 @@
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    message::BorrowedMessage,
+    Message, Offset,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+use tracing::error;
+
+/// Offsets of the messages being handled, the handlers finish out of order with a concurrency
+/// greater than 1, in batches or with different msg_types in the same partition.
+///
+/// Only the offsets below the first message of the partition not handled yet are stored, so a
+/// crash never commits the offset of a message still in flight. The retried messages seek
+/// back to the first one not handled, the messages handled after it are consumed again.
+pub(crate) struct Offsets<'a> {
+    consumer: &'a StreamConsumer,
+    partitions: Mutex<HashMap<(String, i32), Partition>>,
+}
+
+impl<'a> Offsets<'a> {
+    pub fn new(consumer: &'a StreamConsumer) -> Offsets<'a> {
+        Offsets {
+            consumer,
+            partitions: Mutex::default(),
+        }
+    }
+
+    /// Must be called for each message read from the consumer, before handling it.
+    pub fn received(&self, received: &BorrowedMessage) {
+        self.partitions
+            .lock()
+            .unwrap()
+            .entry((received.topic().to_owned(), received.partition()))
+            .or_default()
+            .received(received.offset());
+    }
+
+    /// The message was handled, storing the offset when the handled messages of the partition
+    /// are contiguous up to it.
+    pub fn store(&self, received: &BorrowedMessage) {
+        let handled = self
+            .partitions
+            .lock()
+            .unwrap()
+            .entry((received.topic().to_owned(), received.partition()))
+            .or_default()
+            .handled(received.offset());
+
+        let Some(offset) = handled else {
+            return;
+        };
+
+        if let Err(err) = self
+            .consumer
+            .store_offset(received.topic(), received.partition(), offset)
+        {
+            error!(
+                error = err.to_string(),
+                topic = received.topic(),
+                "failure to store message offset"
+            );
+        }
+    }
+
+    /// Deliveries of the message, 1 for the first one, counting the seek backs.
+    pub fn attempts(&self, received: &BorrowedMessage) -> u32 {
+        self.partitions
+            .lock()
+            .unwrap()
+            .get(&(received.topic().to_owned(), received.partition()))
+            .and_then(|partition| partition.retries.get(&received.offset()).copied())
+            .unwrap_or_default()
+            + 1
+    }
+
+    /// The message must be consumed again, seeking back to the first message of the partition
+    /// not handled yet.
+    pub fn seek_back(&self, received: &BorrowedMessage) {
+        let offset = self
+            .partitions
+            .lock()
+            .unwrap()
+            .entry((received.topic().to_owned(), received.partition()))
+            .or_default()
+            .retry(received.offset());
+
+        if let Err(err) = self.consumer.seek(
+            received.topic(),
+            received.partition(),
+            Offset::Offset(offset),
+            Duration::from_secs(5),
+        ) {
+            error!(
+                error = err.to_string(),
+                topic = received.topic(),
+                "failure to seek back to the message offset"
+            );
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Partition {
+    pending: BTreeSet<i64>,
+    retries: HashMap<i64, u32>,
+    last_received: Option<i64>,
+    stored: Option<i64>,
+}
+
+impl Partition {
+    fn received(&mut self, offset: i64) {
+        //the previous offsets were handled before the partition was assigned
+        if self.stored.is_none() {
+            self.stored = Some(offset - 1);
+        }
+        self.pending.insert(offset);
+        self.last_received = self.last_received.max(Some(offset));
+    }
+
+    //the last offset handled with every previous one handled as well, when it advanced
+    fn handled(&mut self, offset: i64) -> Option<i64> {
+        self.pending.remove(&offset);
+        self.retries.remove(&offset);
+
+        let contiguous = match self.pending.first() {
+            Some(first) => Some(first - 1),
+            None => self.last_received,
+        }
+        .filter(|contiguous| Some(*contiguous) > self.stored)?;
+
+        self.stored = Some(contiguous);
+        Some(contiguous)
+    }
+
+    //the retried message stays pending until it is consumed and handled again
+    fn retry(&mut self, offset: i64) -> i64 {
+        self.pending.insert(offset);
+        *self.retries.entry(offset).or_default() += 1;
+        self.pending.first().copied().unwrap_or(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_store_only_the_contiguous_handled_offsets() {
+        let mut partition = Partition::default();
+        for offset in 10..14 {
+            partition.received(offset);
+        }
+
+        assert_eq!(partition.handled(12), None);
+        assert_eq!(partition.handled(11), None);
+        assert_eq!(partition.handled(10), Some(12));
+        assert_eq!(partition.handled(13), Some(13));
+    }
+
+    #[test]
+    fn should_seek_back_to_the_first_message_not_handled() {
+        let mut partition = Partition::default();
+        for offset in 10..14 {
+            partition.received(offset);
+        }
+
+        assert_eq!(partition.retry(11), 10);
+        assert_eq!(partition.handled(13), None);
+        assert_eq!(partition.retry(12), 10);
+        assert_eq!(partition.handled(10), Some(10));
+        assert_eq!(partition.retries, HashMap::from([(11, 1), (12, 1)]));
+
+        //consumed again from the first message not handled when seeking back
+        for offset in 10..14 {
+            partition.received(offset);
+        }
+        for offset in 10..14 {
+            partition.handled(offset);
+        }
+        assert_eq!(partition.stored, Some(13));
+        assert!(partition.retries.is_empty());
+    }
+
+    #[test]
+    fn should_hold_back_the_offsets_while_the_batch_is_filling() {
+        let mut partition = Partition::default();
+
+        //10 and 12 are batched, 11 is another msg_type handled right away
+        for offset in 10..13 {
+            partition.received(offset);
+        }
+        assert_eq!(partition.handled(11), None);
+
+        assert_eq!(partition.handled(10), Some(11));
+        assert_eq!(partition.handled(12), Some(12));
+    }
+}
@@ -1,8 +1,12 @@
 use async_trait::async_trait;
 use configs::{Configs, DynamicConfigs, Environment};
 use messaging::{
+    compression,
     errors::MessagingError,
-    publisher::{HeaderValues, PublishMessage, Publisher},
+    handler::MESSAGE_ID_HEADER_KEY,
+    headers::{self, HeaderValue},
+    metrics::MessagingMetrics,
+    publisher::{PublishMessage, Publisher},
 };
 use opentelemetry::{
     global::{self, BoxedTracer},
@@ -20,6 +24,7 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tracing::error;
+use uuid::Uuid;
 
 use crate::otel;
 
@@ -32,9 +37,12 @@ pub const TIMESTAMP_HEADER_KEY: &str = "kafka-timestamp";
 /// LongLongUint
 pub const QUEUE_TIMEOUT_KEY: &str = "kafka-queue-timeout";
 
+pub const CONTENT_TYPE_HEADER_KEY: &str = "content-type";
+
 pub struct KafkaPublisher {
     producer: Arc<FutureProducer>,
     tracer: BoxedTracer,
+    metrics: MessagingMetrics,
 }
 
 impl KafkaPublisher {
@@ -72,6 +80,7 @@ impl KafkaPublisher {
         Ok(Arc::new(Self {
             producer: Arc::new(producer),
             tracer: global::tracer("kafka-publisher"),
+            metrics: MessagingMetrics::new("kafka"),
         }))
     }
 }
@@ -79,6 +88,24 @@ impl KafkaPublisher {
 #[async_trait]
 impl Publisher for KafkaPublisher {
     async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        self.metrics.publish(msg, self.send(ctx, msg)).await
+    }
+}
+
+impl KafkaPublisher {
+    async fn send(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        //there is no delayed delivery in the broker, the OutboxPublisher schedules the messages
+        if msg.delay().is_some() {
+            error!(
+                to = msg.to,
+                "delayed delivery is not supported, use the outbox to schedule the message"
+            );
+            return Err(MessagingError::SchedulingError);
+        }
+
+        let compressed = compression::compress(msg)?;
+        let msg = compressed.as_ref();
+
         let (partition, timestamp, queue_timeout) = self.publish_configs(&msg.headers);
         let headers = self.headers(ctx, msg);
 
@@ -106,12 +133,10 @@ impl Publisher for KafkaPublisher {
             _ => Ok(()),
         }
     }
-}
 
-impl KafkaPublisher {
     fn publish_configs(
         &self,
-        headers: &Option<HashMap<String, HeaderValues>>,
+        headers: &Option<HashMap<String, HeaderValue>>,
     ) -> (Option<i32>, i64, Duration) {
         if headers.is_none() {
             return (None, now(), Duration::from_secs(0));
@@ -121,7 +146,7 @@ impl KafkaPublisher {
 
         let partition = match headers.get(PARTITION_HEADER_KEY) {
             Some(v) => {
-                if let HeaderValues::LongInt(p) = v {
+                if let HeaderValue::LongInt(p) = v {
                     Some(p.to_owned())
                 } else {
                     None
@@ -132,7 +157,7 @@ impl KafkaPublisher {
 
         let timestamp = match headers.get(TIMESTAMP_HEADER_KEY) {
             Some(v) => {
-                if let HeaderValues::LongLongInt(t) = v {
+                if let HeaderValue::LongLongInt(t) = v {
                     t.to_owned()
                 } else {
                     now()
@@ -143,7 +168,7 @@ impl KafkaPublisher {
 
         let queue_timeout = match headers.get(QUEUE_TIMEOUT_KEY) {
             Some(v) => {
-                if let HeaderValues::LongLongUint(t) = v {
+                if let HeaderValue::LongLongUint(t) = v {
                     Duration::from_millis(t.to_owned())
                 } else {
                     Duration::from_secs(0)
@@ -156,25 +181,44 @@ impl KafkaPublisher {
     }
 
     fn headers(&self, ctx: &Context, msg: &PublishMessage) -> OwnedHeaders {
-        let Some(headers) = msg.headers.clone() else {
-            return OwnedHeaders::new();
-        };
-
         let mut kafka_headers = OwnedHeaders::new();
 
-        for (key, value) in headers {
-            if key.eq(PARTITION_HEADER_KEY)
-                || key.eq(TIMESTAMP_HEADER_KEY)
-                || key.eq(QUEUE_TIMEOUT_KEY)
-            {
-                continue;
-            }
+        if let Some(content_type) = &msg.content_type {
+            kafka_headers = kafka_headers.insert(Header {
+                key: CONTENT_TYPE_HEADER_KEY,
+                value: Some(content_type),
+            });
+        }
 
-            let vv: String = value.into();
+        let has_message_id = msg
+            .headers
+            .as_ref()
+            .is_some_and(|headers| headers.contains_key(MESSAGE_ID_HEADER_KEY));
+        if !has_message_id {
+            kafka_headers = kafka_headers.insert(Header {
+                key: MESSAGE_ID_HEADER_KEY,
+                value: Some(&Uuid::new_v4().to_string()),
+            });
+        }
+
+        let Some(headers) = msg.headers.clone() else {
+            return otel::inject_context(ctx, &msg.to, &msg.msg_type, &self.tracer, kafka_headers);
+        };
+
+        let headers = headers
+            .into_iter()
+            .filter(|(key, _)| {
+                !(key.eq(PARTITION_HEADER_KEY)
+                    || key.eq(TIMESTAMP_HEADER_KEY)
+                    || key.eq(QUEUE_TIMEOUT_KEY))
+            })
+            .collect();
 
+        //kafka headers are bytes, the typed values are sent along with the header-types header
+        for (key, value) in headers::to_strings(&headers) {
             kafka_headers = kafka_headers.insert(Header {
                 key: &key,
-                value: Some(&vv),
+                value: Some(&value),
             })
         }
 
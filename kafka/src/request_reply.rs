@@ -0,0 +1,182 @@
+use crate::{
+    dispatcher::{consumer_message, explode},
+    publisher::KafkaPublisher,
+};
+use async_trait::async_trait;
+use configs::{Configs, DynamicConfigs};
+use messaging::{
+    errors::MessagingError,
+    handler::ConsumerMessage,
+    publisher::PublishMessage,
+    request_reply::{PendingReplies, RequestReplyClient},
+};
+use opentelemetry::{global, Context};
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+use std::{str, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::error;
+use uuid::Uuid;
+
+/// RequestReplyClient publishing the requests with the reply topic in the reply-to header.
+///
+/// Each client reads every partition of the reply topic from the end it had when the client
+/// was created, receiving every reply published in the reply topic and ignoring the replies
+/// of the requests made by other instances, so a reply topic per service instance is
+/// recommended. The replies stop being consumed when the client is dropped.
+pub struct KafkaRequestReplyClient {
+    publisher: Arc<KafkaPublisher>,
+    pending: Arc<PendingReplies>,
+    reply_topic: String,
+    replies: JoinHandle<()>,
+}
+
+impl KafkaRequestReplyClient {
+    pub fn new<T>(cfgs: &Configs<T>, reply_topic: &str) -> Result<Arc<Self>, MessagingError>
+    where
+        T: DynamicConfigs,
+    {
+        let publisher = KafkaPublisher::new(cfgs)?;
+
+        let consumer = match ClientConfig::new()
+            .set(
+                "bootstrap.servers",
+                format!("{}:{}", cfgs.kafka.host, cfgs.kafka.port),
+            )
+            .set("client.id", cfgs.app.name.clone())
+            .set(
+                "group.id",
+                format!("{}-replies-{}", cfgs.app.name, Uuid::new_v4()),
+            )
+            .set("auto.offset.reset", "latest")
+            .set("enable.auto.commit", "false")
+            .set("security.protocol", cfgs.kafka.security_protocol.clone())
+            .set("sasl.mechanism", cfgs.kafka.sasl_mechanisms.clone())
+            .set("sasl.username", cfgs.kafka.user.clone())
+            .set("sasl.password", cfgs.kafka.password.clone())
+            .create::<StreamConsumer>()
+        {
+            Ok(c) => Ok(c),
+            Err(err) => {
+                error!(error = err.to_string(), "failure to create kafka consumer");
+                Err(MessagingError::ConnectionError {})
+            }
+        }?;
+
+        //the partitions are assigned before returning, a group rebalance would miss the
+        //replies published before the assignment
+        let assignment = match reply_assignment(&consumer, reply_topic) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to read the partitions of the reply topic"
+                );
+                Err(MessagingError::CreatingConsumerError)
+            }
+            Ok(assignment) => Ok(assignment),
+        }?;
+
+        if let Err(err) = consumer.assign(&assignment) {
+            error!(error = err.to_string(), "failure to assign the reply topic");
+            return Err(MessagingError::CreatingConsumerError);
+        }
+
+        let pending = PendingReplies::new();
+
+        let replies = tokio::spawn({
+            let pending = pending.clone();
+
+            async move {
+                let tracer = global::tracer("kafka-replies");
+
+                loop {
+                    let received = match consumer.recv().await {
+                        Ok(m) => m,
+                        Err(err) => {
+                            error!(error = err.to_string(), "failure to consume reply");
+                            continue;
+                        }
+                    };
+
+                    let topic = received.topic();
+                    let msg_type = received
+                        .key()
+                        .and_then(|key| str::from_utf8(key).ok())
+                        .unwrap_or_default();
+
+                    let (_, headers) = explode(topic, msg_type, &tracer, received.headers());
+
+                    pending.complete(consumer_message(&received, msg_type, headers));
+                }
+            }
+        });
+
+        Ok(Arc::new(KafkaRequestReplyClient {
+            publisher,
+            pending,
+            reply_topic: reply_topic.to_owned(),
+            replies,
+        }))
+    }
+}
+
+impl Drop for KafkaRequestReplyClient {
+    fn drop(&mut self) {
+        //the consumer is owned by the task, aborting it leaves the reply topic
+        self.replies.abort();
+    }
+}
+
+/// Every partition of the reply topic at its high watermark.
+fn reply_assignment(
+    consumer: &StreamConsumer,
+    reply_topic: &str,
+) -> Result<TopicPartitionList, String> {
+    let metadata = consumer
+        .fetch_metadata(Some(reply_topic), Duration::from_secs(5))
+        .map_err(|err| err.to_string())?;
+
+    let mut assignment = TopicPartitionList::new();
+    for topic in metadata.topics() {
+        if let Some(err) = topic.error() {
+            return Err(format!("{:?}", err));
+        }
+
+        for partition in topic.partitions() {
+            let (_, high) = consumer
+                .fetch_watermarks(reply_topic, partition.id(), Duration::from_secs(5))
+                .map_err(|err| err.to_string())?;
+
+            assignment
+                .add_partition_offset(reply_topic, partition.id(), Offset::Offset(high))
+                .map_err(|err| err.to_string())?;
+        }
+    }
+
+    match assignment.count() {
+        0 => Err(format!("the topic {} has no partitions", reply_topic)),
+        _ => Ok(assignment),
+    }
+}
+
+#[async_trait]
+impl RequestReplyClient for KafkaRequestReplyClient {
+    async fn request(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        timeout: Duration,
+    ) -> Result<ConsumerMessage, MessagingError> {
+        self.pending
+            .request(
+                self.publisher.as_ref(),
+                ctx,
+                msg,
+                &self.reply_topic,
+                timeout,
+            )
+            .await
+    }
+}
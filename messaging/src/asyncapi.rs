@@ -0,0 +1,311 @@
+use crate::{dispatcher::DispatcherDefinition, errors::MessagingError};
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use tracing::error;
+
+pub const ASYNCAPI_VERSION: &str = "3.0.0";
+
+const SCHEMAS_PATH: &str = "#/components/schemas/";
+
+/// Builds an AsyncAPI 3 document describing the messages consumed by the dispatchers and
+/// sent by the publishers, with the payload schemas generated from the message types.
+///
+/// The broker crates fill the channels and their bindings from their topology. The channel,
+/// operation, message and schema keys are the names with the characters not allowed in the
+/// AsyncAPI keys replaced by `_`, the addresses and message names keep the given names.
+#[derive(Debug, Clone, Default)]
+pub struct AsyncApi {
+    title: String,
+    version: String,
+    description: Option<String>,
+    content_type: Option<String>,
+    servers: BTreeMap<String, Value>,
+    channels: BTreeMap<String, Map<String, Value>>,
+    operations: BTreeMap<String, Value>,
+    schemas: BTreeMap<String, Value>,
+}
+
+impl AsyncApi {
+    pub fn new<T>(title: T, version: T) -> AsyncApi
+    where
+        T: Into<String>,
+    {
+        AsyncApi {
+            title: title.into(),
+            version: version.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn description<T>(mut self, description: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Content type of the messages, defaults to application/json.
+    pub fn content_type<T>(mut self, content_type: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn server<T>(mut self, name: T, host: T, protocol: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.servers.insert(
+            name.into(),
+            json!({ "host": host.into(), "protocol": protocol.into() }),
+        );
+        self
+    }
+
+    /// Declares the channel, the queue, exchange or topic the messages go through.
+    pub fn channel<T>(mut self, name: T, address: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.channel_entry(name.into())
+            .insert("address".to_owned(), Value::String(address.into()));
+        self
+    }
+
+    /// Sets the protocol binding of the channel, as defined by the AsyncAPI bindings.
+    pub fn channel_binding<T>(mut self, name: T, protocol: T, binding: Value) -> Self
+    where
+        T: Into<String>,
+    {
+        let bindings = self
+            .channel_entry(name.into())
+            .entry("bindings")
+            .or_insert_with(|| json!({}));
+        bindings[protocol.into()] = binding;
+        self
+    }
+
+    /// Sets a specification extension of the channel, the key must start with x-.
+    pub fn channel_extension<T>(mut self, name: T, key: T, value: Value) -> Self
+    where
+        T: Into<String>,
+    {
+        self.channel_entry(name.into()).insert(key.into(), value);
+        self
+    }
+
+    /// Documents the messages of the dispatcher consumed from the channel.
+    pub fn receive<T>(self, channel: &str, definition: &DispatcherDefinition) -> Self
+    where
+        T: JsonSchema,
+    {
+        let operation = json!({
+            "x-concurrency": definition.concurrency,
+            "x-ordered": definition.ordered,
+        });
+        self.operation::<T>(
+            "receive",
+            &definition.name,
+            channel,
+            &definition.msg_type,
+            operation,
+        )
+    }
+
+    /// Documents the messages of the given msg_type published to the channel.
+    pub fn send<T>(self, channel: &str, msg_type: &str) -> Self
+    where
+        T: JsonSchema,
+    {
+        let name = format!("send-{}", msg_type);
+        self.operation::<T>("send", &name, channel, msg_type, json!({}))
+    }
+
+    pub fn document(&self) -> Value {
+        let mut info = json!({ "title": self.title, "version": self.version });
+        if let Some(description) = &self.description {
+            info["description"] = Value::String(description.clone());
+        }
+
+        let mut document = json!({
+            "asyncapi": ASYNCAPI_VERSION,
+            "info": info,
+            "defaultContentType": self.content_type.as_deref().unwrap_or("application/json"),
+            "channels": self.channels,
+            "operations": self.operations,
+            "components": { "schemas": self.schemas },
+        });
+        if !self.servers.is_empty() {
+            document["servers"] = json!(self.servers);
+        }
+
+        document
+    }
+
+    pub fn to_json(&self) -> Result<String, MessagingError> {
+        match serde_json::to_string_pretty(&self.document()) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to serialize the asyncapi document"
+                );
+                Err(MessagingError::SerializingError)
+            }
+            Ok(json) => Ok(json),
+        }
+    }
+
+    fn channel_entry(&mut self, name: String) -> &mut Map<String, Value> {
+        self.channels.entry(key(&name)).or_insert_with(|| {
+            let mut channel = Map::new();
+            channel.insert("address".to_owned(), Value::String(name));
+            channel
+        })
+    }
+
+    fn operation<T>(
+        mut self,
+        action: &str,
+        name: &str,
+        channel: &str,
+        msg_type: &str,
+        mut operation: Value,
+    ) -> Self
+    where
+        T: JsonSchema,
+    {
+        let payload = self.schema::<T>();
+
+        let messages = self
+            .channel_entry(channel.to_owned())
+            .entry("messages")
+            .or_insert_with(|| json!({}));
+        messages[key(msg_type)] = json!({ "name": msg_type, "payload": payload });
+
+        operation["action"] = json!(action);
+        operation["channel"] = json!({ "$ref": format!("#/channels/{}", key(channel)) });
+        operation["messages"] = json!([{
+            "$ref": format!("#/channels/{}/messages/{}", key(channel), key(msg_type))
+        }]);
+        self.operations.insert(key(name), operation);
+
+        self
+    }
+
+    //the payload references the type schema, the nested types go to the components as well
+    fn schema<T>(&mut self) -> Value
+    where
+        T: JsonSchema,
+    {
+        let generator = SchemaSettings::draft07()
+            .with(|settings| {
+                settings.definitions_path = SCHEMAS_PATH.to_owned();
+                settings.meta_schema = None;
+            })
+            .into_generator();
+        let root = generator.into_root_schema_for::<T>();
+
+        for (name, schema) in root.definitions {
+            self.schemas.insert(key(&name), json!(schema));
+        }
+
+        let name = key(&T::schema_name());
+        self.schemas.insert(name.clone(), json!(root.schema));
+
+        json!({ "$ref": format!("{}{}", SCHEMAS_PATH, name) })
+    }
+}
+
+/// The AsyncAPI key of the name, the keys must match ^[A-Za-z0-9_\-]+$ while the MQTT topics
+/// carry slashes and the msg_types dots. The keys need no escaping in the json pointers.
+fn key(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct OrderCreated {
+        id: u32,
+        items: Vec<Item>,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Item {
+        sku: String,
+    }
+
+    #[test]
+    fn should_document_the_received_and_sent_messages() {
+        let document = AsyncApi::new("orders", "1.0.0")
+            .server("production", "rabbitmq:5672", "amqp")
+            .channel("orders", "orders-queue")
+            .receive::<OrderCreated>(
+                "orders",
+                &DispatcherDefinition::new("order-created", "order.created"),
+            )
+            .send::<OrderCreated>("orders/events", "order.created")
+            .document();
+
+        assert_eq!(document["asyncapi"], ASYNCAPI_VERSION);
+        assert_eq!(document["channels"]["orders"]["address"], "orders-queue");
+        assert_eq!(
+            document["operations"]["order-created"]["channel"]["$ref"],
+            "#/channels/orders"
+        );
+        assert_eq!(
+            document["operations"]["send-order_created"]["messages"][0]["$ref"],
+            "#/channels/orders_events/messages/order_created"
+        );
+        assert_eq!(
+            document["channels"]["orders_events"]["address"],
+            "orders/events"
+        );
+
+        let message = &document["channels"]["orders"]["messages"]["order_created"];
+        assert_eq!(message["name"], "order.created");
+        assert_eq!(
+            message["payload"]["$ref"],
+            "#/components/schemas/OrderCreated"
+        );
+        assert!(document["components"]["schemas"]["Item"].is_object());
+    }
+
+    #[test]
+    fn should_generate_the_keys_allowed_by_asyncapi() {
+        let document = AsyncApi::new("orders", "1.0.0")
+            .receive::<Vec<OrderCreated>>(
+                "orders/eu/+",
+                &DispatcherDefinition::new("orders/eu/+", "order.created"),
+            )
+            .document();
+
+        let valid = |key: &String| {
+            key.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        for section in [
+            &document["channels"],
+            &document["operations"],
+            &document["components"]["schemas"],
+            &document["channels"]["orders_eu__"]["messages"],
+        ] {
+            let keys = section.as_object().unwrap().keys().collect::<Vec<_>>();
+            assert!(!keys.is_empty());
+            assert!(keys.into_iter().all(valid));
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use opentelemetry::Context;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tracing::error;
+
+#[cfg(feature = "mocks")]
+use mockall::*;
+
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+pub const DEFAULT_BATCH_LINGER: Duration = Duration::from_secs(1);
+
+#[cfg_attr(feature = "mocks", automock)]
+#[async_trait]
+pub trait BatchConsumerHandler: Send + Sync {
+    /// Handles the batch returning one result per message, in the same order of the messages.
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msgs: &[ConsumerMessage],
+    ) -> Vec<Result<Disposition, MessagingError>>;
+}
+
+/// Executes the handler making sure there is one result per message, the missing results
+/// are considered failures.
+pub async fn exec(
+    handler: &dyn BatchConsumerHandler,
+    ctx: &Context,
+    msgs: &[ConsumerMessage],
+) -> Vec<Result<Disposition, MessagingError>> {
+    let mut results = handler.exec(ctx, msgs).await;
+
+    if results.len() != msgs.len() {
+        error!(
+            expected = msgs.len(),
+            received = results.len(),
+            "batch handler returned an unexpected number of results"
+        );
+        results.resize_with(msgs.len(), || Err(MessagingError::HandlerError));
+    }
+
+    results
+}
+
+/// Collects up to size items from the stream, waiting at most the linger after the first item.
+///
+/// Returns None when the stream is closed before receiving any item.
+pub async fn collect<S>(stream: &mut S, size: usize, linger: Duration) -> Option<Vec<S::Item>>
+where
+    S: Stream + Unpin,
+{
+    let first = stream.next().await?;
+
+    let mut items = Vec::with_capacity(size);
+    items.push(first);
+
+    let deadline = tokio::time::sleep(linger);
+    tokio::pin!(deadline);
+
+    while items.len() < size {
+        tokio::select! {
+            biased;
+            item = stream.next() => match item {
+                Some(item) => items.push(item),
+                _ => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+
+    Some(items)
+}
+
+/// Buffer used by the dispatchers receiving the batch messages from a shared stream,
+/// it is flushed when full or when the linger elapsed after the first item.
+pub struct Batch<T> {
+    items: Vec<T>,
+    size: usize,
+    linger: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<T> Batch<T> {
+    pub fn new(size: usize, linger: Duration) -> Batch<T> {
+        Batch {
+            items: Vec::with_capacity(size),
+            size: size.max(1),
+            linger,
+            deadline: None,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.items.is_empty() {
+            self.deadline = Some(Instant::now() + self.linger);
+        }
+
+        self.items.push(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.items.len() >= self.size
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn take(&mut self) -> Vec<T> {
+        self.deadline = None;
+        std::mem::replace(&mut self.items, Vec::with_capacity(self.size))
+    }
+}
+
+/// Waits until the nearest deadline, never completing when there is no deadline.
+pub async fn linger(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// Adapts a BatchConsumerHandler into a ConsumerHandler, handling batches of one message.
+pub struct SingleMessageBatch {
+    handler: Arc<dyn BatchConsumerHandler>,
+}
+
+impl SingleMessageBatch {
+    pub fn new(handler: Arc<dyn BatchConsumerHandler>) -> Arc<SingleMessageBatch> {
+        Arc::new(SingleMessageBatch { handler })
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for SingleMessageBatch {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        exec(self.handler.as_ref(), ctx, std::slice::from_ref(msg))
+            .await
+            .remove(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    struct PartialHandler;
+
+    #[async_trait]
+    impl BatchConsumerHandler for PartialHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msgs: &[ConsumerMessage],
+        ) -> Vec<Result<Disposition, MessagingError>> {
+            vec![Ok(Disposition::Ack)]
+        }
+    }
+
+    #[tokio::test]
+    async fn should_collect_up_to_the_batch_size() {
+        let mut items = stream::iter(1..=5);
+
+        let batch = collect(&mut items, 3, Duration::from_secs(1)).await;
+        assert_eq!(batch, Some(vec![1, 2, 3]));
+
+        let batch = collect(&mut items, 3, Duration::from_secs(1)).await;
+        assert_eq!(batch, Some(vec![4, 5]));
+
+        let batch = collect(&mut items, 3, Duration::from_secs(1)).await;
+        assert_eq!(batch, None);
+    }
+
+    #[tokio::test]
+    async fn should_flush_the_batch_after_the_linger() {
+        let mut items = stream::iter(1..=2).chain(stream::pending());
+
+        let batch = collect(&mut items, 3, Duration::from_millis(10)).await;
+
+        assert_eq!(batch, Some(vec![1, 2]));
+    }
+
+    #[tokio::test]
+    async fn should_fill_the_missing_results_with_errors() {
+        let msgs = vec![ConsumerMessage::default(), ConsumerMessage::default()];
+
+        let results = exec(&PartialHandler, &Context::new(), &msgs).await;
+
+        assert_eq!(
+            results,
+            vec![Ok(Disposition::Ack), Err(MessagingError::HandlerError)]
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{
+    errors::MessagingError,
+    handler::{
+        ConsumerHandler, ConsumerMessage, Disposition, MessageMetadata, MESSAGE_ID_HEADER_KEY,
+    },
+    middlewares::ConsumerMiddleware,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use opentelemetry::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+//the headers of the signing module, the signature covers the message id so it is stripped
+//with it, the messages can be signed again by republishing them with a SigningPublisher
+const SIGNATURE_HEADERS: [&str; 3] = ["signature", "signature-key-id", "signature-algorithm"];
+
+/// Consumed message as stored in the capture files, one json document per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedMessage {
+    pub captured_at: DateTime<Utc>,
+    pub from: String,
+    pub msg_type: String,
+    #[serde(with = "payload")]
+    pub data: Vec<u8>,
+    pub headers: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub metadata: MessageMetadata,
+    /// Disposition returned by the handler, None when the handler failed.
+    pub disposition: Option<String>,
+    pub error: Option<String>,
+}
+
+impl CapturedMessage {
+    pub fn message(&self) -> ConsumerMessage {
+        ConsumerMessage::new(
+            self.from.as_str(),
+            self.msg_type.as_str(),
+            &self.data,
+            self.headers.clone(),
+        )
+        .with_metadata(self.metadata.clone())
+    }
+}
+
+mod payload {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// ConsumerMiddleware appending the consumed messages and the handler results to a
+/// json-lines capture file, to be replayed later with the Replayer.
+///
+/// The file is written in a blocking task after each message, it is meant to be enabled while
+/// investigating an incident rather than kept on in the hot path.
+#[derive(Clone)]
+pub struct CaptureMiddleware {
+    file: Arc<Mutex<File>>,
+    failures_only: bool,
+}
+
+impl CaptureMiddleware {
+    /// Opens the capture file in append mode, creating it when it does not exist.
+    pub fn create<P>(path: P) -> Result<CaptureMiddleware, MessagingError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    path = path.as_ref().display().to_string(),
+                    "failure to open the capture file"
+                );
+                Err(MessagingError::CaptureError)
+            }
+            Ok(file) => Ok(file),
+        }?;
+
+        Ok(CaptureMiddleware {
+            file: Arc::new(Mutex::new(file)),
+            failures_only: false,
+        })
+    }
+
+    /// Captures only the messages whose handler failed or did not ack them.
+    pub fn failures_only(mut self) -> Self {
+        self.failures_only = true;
+        self
+    }
+
+    async fn capture(&self, msg: &ConsumerMessage, result: &Result<Disposition, MessagingError>) {
+        if self.failures_only && matches!(result, Ok(Disposition::Ack)) {
+            return;
+        }
+
+        let captured = CapturedMessage {
+            captured_at: Utc::now(),
+            from: msg.from.clone(),
+            msg_type: msg.msg_type.clone(),
+            data: msg.data.to_vec(),
+            headers: msg.headers.clone(),
+            metadata: msg.metadata.clone(),
+            disposition: result.as_ref().ok().map(|d| format!("{:?}", d)),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        };
+
+        let mut line = match serde_json::to_vec(&captured) {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to serialize the message");
+                return;
+            }
+            Ok(line) => line,
+        };
+        line.push(b'\n');
+
+        //a single write per line keeps the lines whole when many handlers share the file
+        let file = self.file.clone();
+        let written = tokio::task::spawn_blocking(move || file.lock().unwrap().write_all(&line));
+
+        match written.await {
+            Err(err) => error!(error = err.to_string(), "unsuspected error"),
+            Ok(Err(err)) => error!(error = err.to_string(), "failure to write the capture file"),
+            _ => {}
+        }
+    }
+}
+
+impl ConsumerMiddleware for CaptureMiddleware {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(CapturingHandler {
+            middleware: self.clone(),
+            inner: handler,
+        })
+    }
+}
+
+struct CapturingHandler {
+    middleware: CaptureMiddleware,
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for CapturingHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let result = self.inner.exec(ctx, msg).await;
+        self.middleware.capture(msg, &result).await;
+        result
+    }
+}
+
+/// Feeds the messages of a capture file back into a handler, or republishes them,
+/// optionally filtered by msg_type and by the time they were captured.
+#[derive(Debug, Clone, Default)]
+pub struct Replayer {
+    messages: Vec<CapturedMessage>,
+    msg_types: Vec<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    new_message_ids: bool,
+}
+
+impl Replayer {
+    pub fn new(messages: Vec<CapturedMessage>) -> Replayer {
+        Replayer {
+            messages,
+            ..Default::default()
+        }
+    }
+
+    pub fn open<P>(path: P) -> Result<Replayer, MessagingError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = match File::open(&path) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    path = path.as_ref().display().to_string(),
+                    "failure to open the capture file"
+                );
+                Err(MessagingError::CaptureError)
+            }
+            Ok(file) => Ok(file),
+        }?;
+
+        let mut messages = vec![];
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Err(err) => {
+                    error!(error = err.to_string(), "failure to read the capture file");
+                    Err(MessagingError::CaptureError)
+                }
+                Ok(line) => Ok(line),
+            }?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let captured = match serde_json::from_str(&line) {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        line = number + 1,
+                        "failure to deserialize the captured message"
+                    );
+                    Err(MessagingError::CaptureError)
+                }
+                Ok(captured) => Ok(captured),
+            }?;
+
+            messages.push(captured);
+        }
+
+        Ok(Replayer::new(messages))
+    }
+
+    /// Replays only the given msg_types, can be called many times.
+    pub fn msg_type<T>(mut self, msg_type: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.msg_types.push(msg_type.into());
+        self
+    }
+
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Republishes the messages with a new message id and without the signature headers,
+    /// otherwise the inbox of the consumers skips them as already processed.
+    pub fn new_message_ids(mut self) -> Self {
+        self.new_message_ids = true;
+        self
+    }
+
+    /// The captured messages matching the filters, in the capture order.
+    pub fn captured(&self) -> Vec<&CapturedMessage> {
+        self.messages
+            .iter()
+            .filter(|captured| {
+                self.msg_types.is_empty() || self.msg_types.contains(&captured.msg_type)
+            })
+            .filter(|captured| self.since.is_none_or(|since| captured.captured_at >= since))
+            .filter(|captured| self.until.is_none_or(|until| captured.captured_at <= until))
+            .collect()
+    }
+
+    pub fn messages(&self) -> Vec<ConsumerMessage> {
+        self.captured()
+            .into_iter()
+            .map(CapturedMessage::message)
+            .collect()
+    }
+
+    /// Executes the handler with each message, returning the handler results in order.
+    pub async fn replay(
+        &self,
+        ctx: &Context,
+        handler: &dyn ConsumerHandler,
+    ) -> Vec<Result<Disposition, MessagingError>> {
+        let mut results = vec![];
+        for msg in self.messages() {
+            debug!(
+                from = msg.from,
+                msg_type = msg.msg_type,
+                "replaying message"
+            );
+            results.push(handler.exec(ctx, &msg).await);
+        }
+        results
+    }
+
+    /// Publishes each message to the destination using its msg_type as key, returning the
+    /// number of published messages.
+    pub async fn republish(
+        &self,
+        ctx: &Context,
+        publisher: &dyn Publisher,
+        to: &str,
+    ) -> Result<usize, MessagingError> {
+        let captured = self.captured();
+
+        for msg in &captured {
+            let mut headers = match msg.metadata.headers.is_empty() {
+                false => Some(msg.metadata.headers.clone()),
+                _ => msg.headers.as_ref().map(|headers| {
+                    headers
+                        .iter()
+                        .map(|(key, value)| (key.clone(), HeaderValue::LongString(value.clone())))
+                        .collect()
+                }),
+            };
+
+            if self.new_message_ids {
+                let headers = headers.get_or_insert_with(HashMap::new);
+                for key in SIGNATURE_HEADERS {
+                    headers.remove(key);
+                }
+                headers.insert(
+                    MESSAGE_ID_HEADER_KEY.to_owned(),
+                    HeaderValue::ShortString(Uuid::new_v4().to_string()),
+                );
+            }
+
+            let mut republished =
+                PublishMessage::new("", to, &msg.msg_type, &msg.msg_type, &msg.data, headers);
+            republished.content_type = msg.metadata.content_type.clone();
+
+            publisher.publish(ctx, &republished).await?;
+        }
+
+        Ok(captured.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingHandler;
+
+    #[async_trait]
+    impl ConsumerHandler for FailingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            match msg.msg_type.as_str() {
+                "order.created" => Err(MessagingError::HandlerError),
+                _ => Ok(Disposition::Ack),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_replay_the_captured_failures() {
+        let path = std::env::temp_dir().join(format!("{}.jsonl", uuid::Uuid::new_v4()));
+        let ctx = Context::new();
+
+        let handler = CaptureMiddleware::create(&path)
+            .unwrap()
+            .failures_only()
+            .wrap(Arc::new(FailingHandler));
+
+        let headers = HashMap::from([("tenant".to_owned(), "acme".to_owned())]);
+        for msg_type in ["order.created", "order.paid"] {
+            let msg = ConsumerMessage::new("orders", msg_type, b"\x00{}", Some(headers.clone()));
+            let _ = handler.exec(&ctx, &msg).await;
+        }
+
+        let replayer = Replayer::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let captured = replayer.captured();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(
+            captured[0].error,
+            Some("error to handle message".to_owned())
+        );
+
+        let msg = &replayer.messages()[0];
+        assert_eq!(&*msg.data, b"\x00{}");
+        assert_eq!(msg.headers, Some(headers));
+
+        let results = replayer.replay(&ctx, &FailingHandler).await;
+        assert_eq!(results, vec![Err(MessagingError::HandlerError)]);
+    }
+
+    struct RecordingPublisher(Mutex<Vec<PublishMessage>>);
+
+    #[async_trait]
+    impl Publisher for RecordingPublisher {
+        async fn publish(
+            &self,
+            _ctx: &Context,
+            msg: &PublishMessage,
+        ) -> Result<(), MessagingError> {
+            self.0.lock().unwrap().push(msg.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_republish_with_new_message_ids() {
+        let headers = HashMap::from([
+            (MESSAGE_ID_HEADER_KEY.to_owned(), "id".to_owned()),
+            ("signature".to_owned(), "c2lnbmF0dXJl".to_owned()),
+            ("tenant".to_owned(), "acme".to_owned()),
+        ]);
+        let captured = CapturedMessage {
+            captured_at: Utc::now(),
+            from: "orders".to_owned(),
+            msg_type: "order.created".to_owned(),
+            data: vec![],
+            headers: Some(headers),
+            metadata: MessageMetadata::default(),
+            disposition: None,
+            error: None,
+        };
+        let publisher = RecordingPublisher(Mutex::new(vec![]));
+        let replayer = Replayer::new(vec![captured]);
+
+        replayer
+            .republish(&Context::new(), &publisher, "orders")
+            .await
+            .unwrap();
+        replayer
+            .new_message_ids()
+            .republish(&Context::new(), &publisher, "orders")
+            .await
+            .unwrap();
+
+        let published = publisher.0.lock().unwrap();
+        let kept = published[0].headers.as_ref().unwrap();
+        assert_eq!(
+            kept.get(MESSAGE_ID_HEADER_KEY),
+            Some(&HeaderValue::LongString("id".to_owned()))
+        );
+
+        let regenerated = published[1].headers.as_ref().unwrap();
+        assert_ne!(
+            regenerated.get(MESSAGE_ID_HEADER_KEY),
+            Some(&HeaderValue::LongString("id".to_owned()))
+        );
+        assert!(!regenerated.contains_key("signature"));
+        assert!(regenerated.contains_key("tenant"));
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn should_strip_the_headers_of_the_signing_module() {
+        use crate::signing::{
+            SIGNATURE_ALGORITHM_HEADER_KEY, SIGNATURE_HEADER_KEY, SIGNATURE_KEY_ID_HEADER_KEY,
+        };
+
+        assert_eq!(
+            SIGNATURE_HEADERS,
+            [
+                SIGNATURE_HEADER_KEY,
+                SIGNATURE_KEY_ID_HEADER_KEY,
+                SIGNATURE_ALGORITHM_HEADER_KEY
+            ]
+        );
+    }
+
+    #[test]
+    fn should_filter_by_msg_type_and_time_range() {
+        let now = Utc::now();
+        let captured = |msg_type: &str, minutes: i64| CapturedMessage {
+            captured_at: now - chrono::Duration::minutes(minutes),
+            from: "orders".to_owned(),
+            msg_type: msg_type.to_owned(),
+            data: vec![],
+            headers: None,
+            metadata: MessageMetadata::default(),
+            disposition: None,
+            error: None,
+        };
+
+        let replayer = Replayer::new(vec![
+            captured("order.created", 30),
+            captured("order.created", 10),
+            captured("order.paid", 10),
+        ])
+        .msg_type("order.created")
+        .since(now - chrono::Duration::minutes(20));
+
+        let captured = replayer.captured();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].captured_at, now - chrono::Duration::minutes(10));
+    }
+}
@@ -0,0 +1,486 @@
+use crate::{
+    codec::Codec,
+    errors::MessagingError,
+    handler::{ConsumerMessage, MESSAGE_ID_HEADER_KEY},
+    publisher::{HeaderValue, PublishMessage},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tracing::{error, warn};
+
+pub const SPEC_VERSION: &str = "1.0";
+pub const CLOUDEVENTS_JSON_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// Prefix of the CloudEvents attributes in the PublishMessage and ConsumerMessage headers,
+/// the publishers and dispatchers translate it to the protocol binding convention.
+pub const CE_HEADER_PREFIX: &str = "ce_";
+
+const SPEC_VERSION_ATTR: &str = "specversion";
+const ID_ATTR: &str = "id";
+const SOURCE_ATTR: &str = "source";
+const TYPE_ATTR: &str = "type";
+const SUBJECT_ATTR: &str = "subject";
+const TIME_ATTR: &str = "time";
+const DATA_CONTENT_TYPE_ATTR: &str = "datacontenttype";
+const DATA_ATTR: &str = "data";
+const DATA_BASE64_ATTR: &str = "data_base64";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentMode {
+    /// The attributes are sent as protocol headers and the data as the message body.
+    #[default]
+    Binary,
+    /// The whole event is sent as an application/cloudevents+json body.
+    Structured,
+}
+
+/// CloudEvents 1.0 event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloudEvent {
+    pub id: String,
+    pub source: String,
+    pub ty: String,
+    pub subject: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+    pub data_content_type: Option<String>,
+    pub extensions: HashMap<String, String>,
+    pub data: Box<[u8]>,
+}
+
+impl CloudEvent {
+    pub fn new<T>(id: T, source: T, ty: T, data: &[u8]) -> Self
+    where
+        T: Into<String>,
+    {
+        CloudEvent {
+            id: id.into(),
+            source: source.into(),
+            ty: ty.into(),
+            time: Some(Utc::now()),
+            data: data.into(),
+            ..CloudEvent::default()
+        }
+    }
+
+    pub fn encode<T, C, S>(
+        codec: &C,
+        id: S,
+        source: S,
+        ty: S,
+        payload: &T,
+    ) -> Result<Self, MessagingError>
+    where
+        C: Codec<T>,
+        S: Into<String>,
+    {
+        let data = codec.encode(payload)?;
+
+        Ok(CloudEvent::new(id, source, ty, &data).data_content_type(codec.content_type()))
+    }
+
+    pub fn subject<T>(mut self, subject: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    pub fn time(mut self, time: DateTime<Utc>) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn data_content_type<T>(mut self, content_type: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.data_content_type = Some(content_type.into());
+        self
+    }
+
+    /// Extension attributes names must be lowercase alphanumeric.
+    pub fn extension<T>(mut self, name: T, value: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.extensions.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn decode<T, C>(&self, codec: &C) -> Result<T, MessagingError>
+    where
+        C: Codec<T>,
+    {
+        codec.decode(&self.data)
+    }
+
+    /// Maps the event into a PublishMessage sent to the given destination.
+    pub fn to_publish_message<T>(
+        &self,
+        to: T,
+        key: T,
+        mode: ContentMode,
+    ) -> Result<PublishMessage, MessagingError>
+    where
+        T: Into<String>,
+    {
+        let mut headers = HashMap::from([(
+            MESSAGE_ID_HEADER_KEY.to_owned(),
+            HeaderValue::LongString(self.id.clone()),
+        )]);
+
+        if mode == ContentMode::Structured {
+            let data = self.to_structured()?;
+
+            return Ok(PublishMessage::new(
+                self.source.clone(),
+                to.into(),
+                key.into(),
+                self.ty.clone(),
+                &data,
+                Some(headers),
+            )
+            .with_content_type(CLOUDEVENTS_JSON_CONTENT_TYPE));
+        }
+
+        for (name, value) in self.attributes() {
+            headers.insert(
+                format!("{}{}", CE_HEADER_PREFIX, name),
+                HeaderValue::LongString(value),
+            );
+        }
+
+        let msg = PublishMessage::new(
+            self.source.clone(),
+            to.into(),
+            key.into(),
+            self.ty.clone(),
+            &self.data,
+            Some(headers),
+        );
+
+        Ok(match &self.data_content_type {
+            Some(content_type) => msg.with_content_type(content_type),
+            _ => msg,
+        })
+    }
+
+    /// Reads the event from the CloudEvents headers of a message parsed by the dispatchers.
+    pub fn from_consumer_message(msg: &ConsumerMessage) -> Option<CloudEvent> {
+        let headers = msg.headers.as_ref()?;
+
+        let mut attributes: HashMap<&str, &str> = headers
+            .iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix(CE_HEADER_PREFIX)
+                    .map(|name| (name, value.as_str()))
+            })
+            .collect();
+
+        attributes.remove(SPEC_VERSION_ATTR)?;
+
+        let mut event = CloudEvent {
+            id: attributes.remove(ID_ATTR)?.to_owned(),
+            source: attributes.remove(SOURCE_ATTR)?.to_owned(),
+            ty: attributes.remove(TYPE_ATTR)?.to_owned(),
+            subject: attributes.remove(SUBJECT_ATTR).map(|v| v.to_owned()),
+            time: attributes.remove(TIME_ATTR).and_then(parse_time),
+            data_content_type: attributes
+                .remove(DATA_CONTENT_TYPE_ATTR)
+                .map(|v| v.to_owned()),
+            data: msg.data.clone(),
+            ..CloudEvent::default()
+        };
+
+        for (name, value) in attributes {
+            event.extensions.insert(name.to_owned(), value.to_owned());
+        }
+
+        Some(event)
+    }
+
+    fn attributes(&self) -> Vec<(String, String)> {
+        let mut attributes = vec![
+            (SPEC_VERSION_ATTR.to_owned(), SPEC_VERSION.to_owned()),
+            (ID_ATTR.to_owned(), self.id.clone()),
+            (SOURCE_ATTR.to_owned(), self.source.clone()),
+            (TYPE_ATTR.to_owned(), self.ty.clone()),
+        ];
+
+        if let Some(subject) = &self.subject {
+            attributes.push((SUBJECT_ATTR.to_owned(), subject.clone()));
+        }
+
+        if let Some(time) = &self.time {
+            attributes.push((
+                TIME_ATTR.to_owned(),
+                time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            ));
+        }
+
+        for (name, value) in &self.extensions {
+            attributes.push((name.clone(), value.clone()));
+        }
+
+        attributes
+    }
+
+    fn to_structured(&self) -> Result<Vec<u8>, MessagingError> {
+        let mut envelope: Map<String, Value> = self
+            .attributes()
+            .into_iter()
+            .map(|(name, value)| (name, Value::String(value)))
+            .collect();
+
+        if let Some(content_type) = &self.data_content_type {
+            envelope.insert(
+                DATA_CONTENT_TYPE_ATTR.to_owned(),
+                Value::String(content_type.clone()),
+            );
+        }
+
+        //json data is embedded as is, any other content is base64 encoded
+        let json = match &self.data_content_type {
+            Some(content_type) => is_json(content_type),
+            _ => true,
+        };
+
+        match serde_json::from_slice::<Value>(&self.data) {
+            Ok(data) if json => envelope.insert(DATA_ATTR.to_owned(), data),
+            _ if self.data.is_empty() => None,
+            _ => envelope.insert(
+                DATA_BASE64_ATTR.to_owned(),
+                Value::String(STANDARD.encode(&self.data)),
+            ),
+        };
+
+        match serde_json::to_vec(&envelope) {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to serialize cloud event");
+                Err(MessagingError::SerializingError)
+            }
+            Ok(data) => Ok(data),
+        }
+    }
+
+    fn from_structured(data: &[u8]) -> Result<CloudEvent, MessagingError> {
+        let mut envelope = match serde_json::from_slice::<Map<String, Value>>(data) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to deserialize cloud event"
+                );
+                Err(MessagingError::DeserializingError)
+            }
+            Ok(envelope) => Ok(envelope),
+        }?;
+
+        let mut attribute = |name: &str| match envelope.remove(name) {
+            Some(Value::String(value)) => Some(value),
+            Some(value) => Some(value.to_string()),
+            _ => None,
+        };
+
+        if attribute(SPEC_VERSION_ATTR).is_none() {
+            error!("cloud event with no specversion");
+            return Err(MessagingError::DeserializingError);
+        }
+
+        let (Some(id), Some(source), Some(ty)) = (
+            attribute(ID_ATTR),
+            attribute(SOURCE_ATTR),
+            attribute(TYPE_ATTR),
+        ) else {
+            error!("cloud event with no id, source or type");
+            return Err(MessagingError::DeserializingError);
+        };
+
+        let subject = attribute(SUBJECT_ATTR);
+        let time = attribute(TIME_ATTR).and_then(|time| parse_time(&time));
+        let data_content_type = attribute(DATA_CONTENT_TYPE_ATTR);
+
+        let data = match (
+            envelope.remove(DATA_ATTR),
+            envelope.remove(DATA_BASE64_ATTR),
+        ) {
+            (_, Some(Value::String(encoded))) => match STANDARD.decode(encoded) {
+                Err(err) => {
+                    error!(error = err.to_string(), "failure to decode data_base64");
+                    Err(MessagingError::DeserializingError)
+                }
+                Ok(data) => Ok(data),
+            }?,
+            (Some(Value::String(data)), _)
+                if !data_content_type.as_deref().is_some_and(is_json) =>
+            {
+                data.into_bytes()
+            }
+            (Some(data), _) => data.to_string().into_bytes(),
+            _ => vec![],
+        };
+
+        let extensions = envelope
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => (name, value),
+                value => (name, value.to_string()),
+            })
+            .collect();
+
+        Ok(CloudEvent {
+            id,
+            source,
+            ty,
+            subject,
+            time,
+            data_content_type,
+            extensions,
+            data: data.into(),
+        })
+    }
+}
+
+/// Populates the ConsumerMessage with the CloudEvent carried in the message, if any.
+///
+/// Structured events have their data unwrapped, and both modes have the attributes
+/// exposed as `ce_` headers, the msg_type filled with the event type when empty and
+/// the message id filled with the event id when missing. The dispatchers must
+/// translate the protocol binding headers to the `ce_` prefix before calling it.
+pub fn parse(mut msg: ConsumerMessage, content_type: Option<&str>) -> ConsumerMessage {
+    let structured = content_type
+        .is_some_and(|content_type| content_type.starts_with(CLOUDEVENTS_JSON_CONTENT_TYPE));
+
+    let mut headers = msg.headers.take().unwrap_or_default();
+
+    let (id, ty) = if structured {
+        let event = match CloudEvent::from_structured(&msg.data) {
+            Err(_) => {
+                warn!(from = msg.from, "ignoring malformed structured cloud event");
+                msg.headers = Some(headers);
+                return msg;
+            }
+            Ok(event) => event,
+        };
+
+        for (name, value) in event.attributes() {
+            headers.insert(format!("{}{}", CE_HEADER_PREFIX, name), value);
+        }
+        if let Some(content_type) = &event.data_content_type {
+            headers.insert(
+                format!("{}{}", CE_HEADER_PREFIX, DATA_CONTENT_TYPE_ATTR),
+                content_type.clone(),
+            );
+        }
+
+        msg.data = event.data;
+        (event.id, event.ty)
+    } else {
+        let spec_version = format!("{}{}", CE_HEADER_PREFIX, SPEC_VERSION_ATTR);
+        if !headers.contains_key(&spec_version) {
+            msg.headers = (!headers.is_empty()).then_some(headers);
+            return msg;
+        }
+
+        if let Some(content_type) = content_type {
+            headers.insert(
+                format!("{}{}", CE_HEADER_PREFIX, DATA_CONTENT_TYPE_ATTR),
+                content_type.to_owned(),
+            );
+        }
+
+        let attribute = |name: &str| {
+            headers
+                .get(&format!("{}{}", CE_HEADER_PREFIX, name))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        (attribute(ID_ATTR), attribute(TYPE_ATTR))
+    };
+
+    if msg.msg_type.is_empty() {
+        msg.msg_type = ty;
+    }
+
+    if !id.is_empty() {
+        headers
+            .entry(MESSAGE_ID_HEADER_KEY.to_owned())
+            .or_insert(id);
+    }
+
+    msg.headers = Some(headers);
+    msg
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(time) {
+        Err(err) => {
+            warn!(
+                error = err.to_string(),
+                time = time,
+                "invalid cloud event time"
+            );
+            None
+        }
+        Ok(time) => Some(time.with_timezone(&Utc)),
+    }
+}
+
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type == "application/json" || media_type.ends_with("+json") || media_type == "text/json"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(msg: &PublishMessage) -> HashMap<String, String> {
+        msg.headers
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect()
+    }
+
+    #[test]
+    fn should_round_trip_binary_event() {
+        let event = CloudEvent::new("1", "/orders", "order.created", br#"{"id":1}"#)
+            .subject("order/1")
+            .data_content_type("application/json")
+            .extension("tenant", "acme");
+
+        let published = event
+            .to_publish_message("orders", "key", ContentMode::Binary)
+            .unwrap();
+        let received = parse(
+            ConsumerMessage::new("orders", "", &published.data, Some(headers(&published))),
+            published.content_type.as_deref(),
+        );
+
+        assert_eq!(received.msg_type, "order.created");
+        assert_eq!(received.message_id(), Some("1"));
+        assert_eq!(CloudEvent::from_consumer_message(&received), Some(event));
+    }
+
+    #[test]
+    fn should_round_trip_structured_event() {
+        let event = CloudEvent::new("1", "/files", "file.uploaded", &[0, 159, 146])
+            .data_content_type("application/octet-stream");
+
+        let published = event
+            .to_publish_message("files", "key", ContentMode::Structured)
+            .unwrap();
+        let received = parse(
+            ConsumerMessage::new("files", "", &published.data, Some(headers(&published))),
+            published.content_type.as_deref(),
+        );
+
+        assert_eq!(&*received.data, &[0, 159, 146]);
+        assert_eq!(CloudEvent::from_consumer_message(&received), Some(event));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::errors::MessagingError;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Codec used to encode a typed payload into the message bytes and to decode it back.
+pub trait Codec<T>: Send + Sync {
+    fn content_type(&self) -> &'static str;
+    fn encode(&self, payload: &T) -> Result<Vec<u8>, MessagingError>;
+    fn decode(&self, data: &[u8]) -> Result<T, MessagingError>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl<T> Codec<T> for JsonCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn content_type(&self) -> &'static str {
+        JSON_CONTENT_TYPE
+    }
+
+    fn encode(&self, payload: &T) -> Result<Vec<u8>, MessagingError> {
+        match serde_json::to_vec(payload) {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to serialize json payload");
+                Err(MessagingError::SerializingError)
+            }
+            Ok(data) => Ok(data),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<T, MessagingError> {
+        match serde_json::from_slice(data) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to deserialize json payload"
+                );
+                Err(MessagingError::DeserializingError)
+            }
+            Ok(payload) => Ok(payload),
+        }
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T> Codec<T> for MsgPackCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn content_type(&self) -> &'static str {
+        MSGPACK_CONTENT_TYPE
+    }
+
+    fn encode(&self, payload: &T) -> Result<Vec<u8>, MessagingError> {
+        match rmp_serde::to_vec_named(payload) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to serialize msgpack payload"
+                );
+                Err(MessagingError::SerializingError)
+            }
+            Ok(data) => Ok(data),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<T, MessagingError> {
+        match rmp_serde::from_slice(data) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to deserialize msgpack payload"
+                );
+                Err(MessagingError::DeserializingError)
+            }
+            Ok(payload) => Ok(payload),
+        }
+    }
+}
+
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtobufCodec;
+
+#[cfg(feature = "protobuf")]
+impl<T> Codec<T> for ProtobufCodec
+where
+    T: prost::Message + Default,
+{
+    fn content_type(&self) -> &'static str {
+        PROTOBUF_CONTENT_TYPE
+    }
+
+    fn encode(&self, payload: &T) -> Result<Vec<u8>, MessagingError> {
+        Ok(payload.encode_to_vec())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<T, MessagingError> {
+        match T::decode(data) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to deserialize protobuf payload"
+                );
+                Err(MessagingError::DeserializingError)
+            }
+            Ok(payload) => Ok(payload),
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T> Codec<T> for CborCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn content_type(&self) -> &'static str {
+        CBOR_CONTENT_TYPE
+    }
+
+    fn encode(&self, payload: &T) -> Result<Vec<u8>, MessagingError> {
+        let mut data = vec![];
+
+        match ciborium::into_writer(payload, &mut data) {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to serialize cbor payload");
+                Err(MessagingError::SerializingError)
+            }
+            Ok(_) => Ok(data),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<T, MessagingError> {
+        match ciborium::from_reader(data) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failure to deserialize cbor payload"
+                );
+                Err(MessagingError::DeserializingError)
+            }
+            Ok(payload) => Ok(payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn should_encode_and_decode_json() {
+        let payload = Payload {
+            id: 1,
+            name: "name".to_owned(),
+        };
+
+        let data = JsonCodec.encode(&payload).unwrap();
+        let decoded: Payload = JsonCodec.decode(&data).unwrap();
+
+        assert_eq!(decoded, payload);
+        assert_eq!(
+            Codec::<Payload>::content_type(&JsonCodec),
+            JSON_CONTENT_TYPE
+        );
+    }
+
+    #[test]
+    fn should_map_json_decode_failure_to_deserializing_error() {
+        let res: Result<Payload, MessagingError> = JsonCodec.decode(b"{");
+
+        assert_eq!(res.unwrap_err(), MessagingError::DeserializingError);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerMessage, Disposition},
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::{borrow::Cow, collections::HashMap, future::Future, sync::Arc};
+use tracing::{debug, error};
+
+/// Header carrying the algorithm used to compress the payload, messages with no
+/// content encoding are not compressed.
+pub const CONTENT_ENCODING_HEADER_KEY: &str = "content-encoding";
+
+pub const GZIP_ENCODING: &str = "gzip";
+pub const ZSTD_ENCODING: &str = "zstd";
+pub const LZ4_ENCODING: &str = "lz4";
+
+/// Largest payload accepted when decompressing, protecting the consumers from the
+/// compression bombs.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Reason of the dead letters whose payload could not be decompressed.
+pub const DECOMPRESSION_FAILED_REASON: &str = "failure to decompress the payload";
+
+/// Payload compression algorithms, each one requires the feature with the same name,
+/// otherwise compressing and decompressing fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => GZIP_ENCODING,
+            Compression::Zstd => ZSTD_ENCODING,
+            Compression::Lz4 => LZ4_ENCODING,
+        }
+    }
+
+    pub fn from_encoding(encoding: &str) -> Option<Compression> {
+        match encoding {
+            GZIP_ENCODING => Some(Compression::Gzip),
+            ZSTD_ENCODING => Some(Compression::Zstd),
+            LZ4_ENCODING => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "zstd", feature = "lz4")),
+        allow(unused_variables)
+    )]
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, MessagingError> {
+        let compressed = match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                use std::io::Write;
+
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder
+                    .write_all(data)
+                    .and_then(|_| encoder.finish().map_err(std::io::Error::from))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(self.disabled()),
+        };
+
+        match compressed {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    encoding = self.encoding(),
+                    "failure to compress payload"
+                );
+                Err(MessagingError::CompressionError)
+            }
+            Ok(compressed) => Ok(compressed),
+        }
+    }
+
+    /// Decompresses the payload, failing when it exceeds MAX_DECOMPRESSED_SIZE.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, MessagingError> {
+        self.decompress_limited(data, MAX_DECOMPRESSED_SIZE)
+    }
+
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "zstd", feature = "lz4")),
+        allow(unused_variables)
+    )]
+    fn decompress_limited(&self, data: &[u8], limit: u64) -> Result<Vec<u8>, MessagingError> {
+        let decompressed = match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => read_limited(flate2::read::GzDecoder::new(data), limit),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| read_limited(decoder, limit)),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => read_limited(lz4_flex::frame::FrameDecoder::new(data), limit),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.disabled()),
+        };
+
+        match decompressed {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    encoding = self.encoding(),
+                    "failure to decompress payload"
+                );
+                Err(MessagingError::CompressionError)
+            }
+            Ok(decompressed) => Ok(decompressed),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn disabled(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("the {} feature is disabled", self.encoding()),
+        )
+    }
+}
+
+/// Reads the decompressed payload, failing when it is larger than the limit.
+#[allow(dead_code)]
+fn read_limited<R>(reader: R, limit: u64) -> std::io::Result<Vec<u8>>
+where
+    R: std::io::Read,
+{
+    use std::io::Read;
+
+    let mut decompressed = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut decompressed)?;
+
+    if decompressed.len() as u64 > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("the decompressed payload exceeds {} bytes", limit),
+        ));
+    }
+
+    Ok(decompressed)
+}
+
+/// Compresses the payload with the message compression, setting the content encoding header.
+pub fn compress(msg: &PublishMessage) -> Result<Cow<'_, PublishMessage>, MessagingError> {
+    let Some(compression) = msg.compression else {
+        return Ok(Cow::Borrowed(msg));
+    };
+
+    let mut compressed = msg.clone();
+    compressed.data = compression.compress(&msg.data)?.into();
+    compressed.headers.get_or_insert_with(HashMap::new).insert(
+        CONTENT_ENCODING_HEADER_KEY.to_owned(),
+        HeaderValue::ShortString(compression.encoding().to_owned()),
+    );
+
+    Ok(Cow::Owned(compressed))
+}
+
+/// Decompresses the payload of the messages with the content encoding header, removing it.
+///
+/// Messages failing to decompress keep the payload and the header as they were received,
+/// the dispatchers dead letter them with `undecompressed` instead of calling the handler.
+/// Content encodings other than the compressions are kept as they are.
+pub fn decompress(mut msg: ConsumerMessage) -> ConsumerMessage {
+    let Some(encoding) = msg
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(CONTENT_ENCODING_HEADER_KEY))
+    else {
+        return msg;
+    };
+
+    //other producers may set content encodings which are not compressions, as utf-8
+    let Some(compression) = Compression::from_encoding(encoding) else {
+        debug!(
+            from = msg.from,
+            encoding = encoding,
+            "ignoring the content encoding with no compression"
+        );
+        return msg;
+    };
+
+    if let Ok(data) = compression.decompress(&msg.data) {
+        msg.data = data.into();
+        if let Some(headers) = msg.headers.as_mut() {
+            headers.remove(CONTENT_ENCODING_HEADER_KEY);
+        }
+    }
+
+    msg
+}
+
+/// The dead letter disposition of the messages still carrying a compression in the content
+/// encoding header, their payload could not be decompressed. None for the other messages.
+pub fn undecompressed(msg: &ConsumerMessage) -> Option<Disposition> {
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get(CONTENT_ENCODING_HEADER_KEY))
+        .and_then(|encoding| Compression::from_encoding(encoding))
+        .map(|_| Disposition::dead_letter(DECOMPRESSION_FAILED_REASON))
+}
+
+/// Executes the batch with the messages that could be decompressed, the others get the
+/// dead letter disposition. The results are in the same order of the messages.
+pub async fn exec_batch<'a, F, Fut>(
+    msgs: &'a [ConsumerMessage],
+    exec: F,
+) -> Vec<Result<Disposition, MessagingError>>
+where
+    F: FnOnce(Cow<'a, [ConsumerMessage]>) -> Fut,
+    Fut: Future<Output = Vec<Result<Disposition, MessagingError>>>,
+{
+    let undecompressed: Vec<Option<Disposition>> = msgs.iter().map(undecompressed).collect();
+    if undecompressed.iter().all(Option::is_none) {
+        return exec(Cow::Borrowed(msgs)).await;
+    }
+
+    let decompressed: Vec<ConsumerMessage> = msgs
+        .iter()
+        .zip(&undecompressed)
+        .filter(|(_, disposition)| disposition.is_none())
+        .map(|(msg, _)| msg.clone())
+        .collect();
+
+    let mut results = match decompressed.is_empty() {
+        true => Vec::new(),
+        _ => exec(Cow::Owned(decompressed)).await,
+    }
+    .into_iter();
+
+    undecompressed
+        .into_iter()
+        .map(|disposition| match disposition {
+            Some(disposition) => Ok(disposition),
+            None => results.next().unwrap_or(Err(MessagingError::HandlerError)),
+        })
+        .collect()
+}
+
+/// Publisher compressing the messages with no compression set.
+pub struct CompressingPublisher {
+    compression: Compression,
+    inner: Arc<dyn Publisher>,
+}
+
+impl CompressingPublisher {
+    pub fn new(compression: Compression, publisher: Arc<dyn Publisher>) -> Arc<Self> {
+        Arc::new(CompressingPublisher {
+            compression,
+            inner: publisher,
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for CompressingPublisher {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        if msg.compression.is_some() {
+            return self.inner.publish(ctx, msg).await;
+        }
+
+        let msg = msg.clone().with_compression(self.compression);
+        self.inner.publish(ctx, &msg).await
+    }
+}
+
+#[cfg(all(test, feature = "gzip", feature = "zstd", feature = "lz4"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decompress_the_compressed_payload() {
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let msg = PublishMessage::new("", "exchange", "", "event", b"{\"id\":1}", None)
+                .with_compression(compression);
+
+            let compressed = compress(&msg).unwrap();
+            let headers = compressed
+                .headers
+                .clone()
+                .unwrap()
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect();
+
+            let received = decompress(ConsumerMessage::new(
+                "queue",
+                "event",
+                &compressed.data,
+                Some(headers),
+            ));
+
+            assert_eq!(&*received.data, b"{\"id\":1}");
+            assert!(received.headers.unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn should_keep_the_messages_with_no_content_encoding() {
+        let msg = ConsumerMessage::new("queue", "event", b"{}", None);
+        let received = decompress(msg);
+
+        assert_eq!(&*received.data, b"{}");
+        assert_eq!(undecompressed(&received), None);
+    }
+
+    #[test]
+    fn should_dead_letter_the_messages_failing_to_decompress() {
+        for encoding in [GZIP_ENCODING, ZSTD_ENCODING, LZ4_ENCODING] {
+            let headers =
+                HashMap::from([(CONTENT_ENCODING_HEADER_KEY.to_owned(), encoding.to_owned())]);
+
+            let received = decompress(ConsumerMessage::new(
+                "queue",
+                "event",
+                b"not compressed",
+                Some(headers),
+            ));
+
+            assert_eq!(&*received.data, b"not compressed");
+            assert_eq!(
+                undecompressed(&received),
+                Some(Disposition::dead_letter(
+                    "failure to decompress the payload"
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn should_keep_the_messages_with_a_content_encoding_with_no_compression() {
+        let headers = HashMap::from([(CONTENT_ENCODING_HEADER_KEY.to_owned(), "utf-8".to_owned())]);
+
+        let received = decompress(ConsumerMessage::new("queue", "event", b"{}", Some(headers)));
+
+        assert_eq!(&*received.data, b"{}");
+        assert_eq!(undecompressed(&received), None);
+    }
+
+    #[tokio::test]
+    async fn should_dead_letter_the_batch_messages_failing_to_decompress() {
+        let headers = HashMap::from([(
+            CONTENT_ENCODING_HEADER_KEY.to_owned(),
+            GZIP_ENCODING.to_owned(),
+        )]);
+        let msgs = vec![
+            ConsumerMessage::new("queue", "event", b"{}", Some(headers)),
+            ConsumerMessage::new("queue", "event", b"{}", None),
+        ];
+
+        let results = exec_batch(&msgs, |msgs| async move {
+            assert_eq!(msgs.len(), 1);
+            vec![Ok(Disposition::Ack)]
+        })
+        .await;
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(Disposition::dead_letter(DECOMPRESSION_FAILED_REASON)),
+                Ok(Disposition::Ack)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_fail_to_decompress_the_payloads_exceeding_the_limit() {
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let compressed = compression.compress(&[0; 1025]).unwrap();
+
+            assert_eq!(
+                compression.decompress_limited(&compressed, 1024),
+                Err(MessagingError::CompressionError)
+            );
+            assert_eq!(
+                compression.decompress_limited(&compressed, 1025).unwrap(),
+                vec![0; 1025]
+            );
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use crate::{errors::MessagingError, handler::ConsumerHandler};
+use crate::{
+    batch::{BatchConsumerHandler, DEFAULT_BATCH_LINGER, DEFAULT_BATCH_SIZE},
+    codec::Codec,
+    errors::MessagingError,
+    handler::{ConsumerHandler, TypedConsumerHandler, TypedHandler},
+    middlewares::Middlewares,
+    shutdown::CancellationToken,
+};
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[cfg(feature = "mocks")]
 use mockall::*;
@@ -9,6 +16,15 @@ use mockall::*;
 pub struct DispatcherDefinition {
     pub name: String,
     pub msg_type: String,
+    /// Maximum number of messages handled at the same time, defaults to 1.
+    pub concurrency: usize,
+    /// When enabled the messages sharing the same key (routing key, partition or topic)
+    /// are handled sequentially even when the concurrency is greater than 1.
+    pub ordered: bool,
+    /// Maximum number of messages given to a BatchConsumerHandler at once.
+    pub batch_size: usize,
+    /// Maximum time waiting the batch to be filled after its first message.
+    pub batch_linger: Duration,
 }
 
 impl DispatcherDefinition {
@@ -19,8 +35,28 @@ impl DispatcherDefinition {
         DispatcherDefinition {
             name: name.into(),
             msg_type: msg_type.into(),
+            concurrency: 1,
+            ordered: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_linger: DEFAULT_BATCH_LINGER,
         }
     }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    pub fn batch(mut self, size: usize, linger: Duration) -> Self {
+        self.batch_size = size.max(1);
+        self.batch_linger = linger;
+        self
+    }
 }
 
 #[cfg_attr(feature = "mocks", automock)]
@@ -29,5 +65,50 @@ pub trait Dispatcher: Send + Sync {
     fn register(self, definition: &DispatcherDefinition, handler: Arc<dyn ConsumerHandler>)
         -> Self;
 
-    async fn consume_blocking(&self) -> Result<(), MessagingError>;
+    fn register_batch(
+        self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn BatchConsumerHandler>,
+    ) -> Self;
+
+    /// Registers the handler receiving the payload decoded with the codec.
+    fn register_typed<T, C>(
+        self,
+        definition: &DispatcherDefinition,
+        codec: C,
+        handler: Arc<dyn TypedConsumerHandler<T>>,
+    ) -> Self
+    where
+        Self: Sized,
+        T: Send + Sync + 'static,
+        C: Codec<T> + 'static,
+    {
+        self.register(definition, TypedHandler::with_codec(codec, handler))
+    }
+
+    fn register_with_middlewares(
+        self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn ConsumerHandler>,
+        middlewares: &Middlewares,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        self.register(definition, middlewares.wrap(handler))
+    }
+
+    async fn consume_blocking(&self) -> Result<(), MessagingError> {
+        self.consume_until(CancellationToken::new()).await
+    }
+
+    /// Consumes until the shutdown token is cancelled, then stops receiving new deliveries
+    /// and waits the in-flight handlers to finish before closing the consumers.
+    async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError>;
+
+    /// Whether the consumers are subscribed and receiving, the DispatcherGroup reports the
+    /// dispatcher running only from then on.
+    fn is_consuming(&self) -> bool {
+        true
+    }
 }
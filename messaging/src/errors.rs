@@ -23,9 +23,36 @@ pub enum MessagingError {
     #[error("error to handle message")]
     HandlerError,
 
+    #[error("handler timed out")]
+    TimeoutError,
+
     #[error("failure to consume message `{0}`")]
     ConsumerError(String),
 
     #[error("failure to publish message")]
     PublisherError,
+
+    #[error("unsupported message version")]
+    UnsupportedVersionError,
+
+    #[error("failure to compress or decompress the payload")]
+    CompressionError,
+
+    #[error("invalid message signature")]
+    InvalidSignatureError,
+
+    #[error("delayed delivery is not supported by the publisher")]
+    SchedulingError,
+
+    #[error("failure to read or write the capture file")]
+    CaptureError,
+
+    #[error("the circuit is open")]
+    CircuitOpenError,
+
+    #[error("the broker did not confirm the message")]
+    PublishNackedError,
+
+    #[error("the broker returned the unroutable message `{0}`")]
+    UnroutableError(String),
 }
@@ -1,17 +1,50 @@
-use crate::errors::MessagingError;
+use crate::{
+    codec::{Codec, JsonCodec},
+    errors::MessagingError,
+    headers::HeaderValue,
+};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use opentelemetry::Context;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
 
 #[cfg(feature = "mocks")]
 use mockall::*;
 
-#[derive(Clone, Default)]
+/// Header carrying the broker message id, filled by the dispatchers when the broker provides it.
+pub const MESSAGE_ID_HEADER_KEY: &str = "message-id";
+
+/// Broker properties of the received message, normalized by the dispatchers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageMetadata {
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    pub content_type: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Times the message was delivered to the consumer, 1 for the first delivery.
+    pub delivery_count: u32,
+    pub redelivered: bool,
+    /// Kafka partition and offset of the message.
+    pub partition: Option<i32>,
+    pub offset: Option<i64>,
+    /// MQTT quality of service and flags of the message.
+    pub qos: Option<i32>,
+    pub retain: bool,
+    pub duplicate: bool,
+    /// The headers keeping the type they were received with.
+    pub headers: HashMap<String, HeaderValue>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ConsumerMessage {
     pub from: String,
     pub msg_type: String,
     pub data: Box<[u8]>,
     pub headers: Option<HashMap<String, String>>,
+    pub metadata: MessageMetadata,
 }
 
 impl ConsumerMessage {
@@ -29,6 +62,118 @@ impl ConsumerMessage {
             msg_type: msg_type.into(),
             data: data.into(),
             headers,
+            metadata: MessageMetadata::default(),
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn decode<T, C>(&self, codec: &C) -> Result<T, MessagingError>
+    where
+        C: Codec<T>,
+    {
+        codec.decode(&self.data)
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.metadata.message_id.as_deref().or_else(|| {
+            self.headers
+                .as_ref()
+                .and_then(|headers| headers.get(MESSAGE_ID_HEADER_KEY))
+                .map(|id| id.as_str())
+        })
+    }
+
+    /// The header with the type it was received with.
+    pub fn header(&self, key: &str) -> Option<&HeaderValue> {
+        self.metadata.headers.get(key)
+    }
+
+    pub fn header_str(&self, key: &str) -> Option<&str> {
+        self.header(key)
+            .and_then(HeaderValue::as_str)
+            .or_else(|| self.string_header(key))
+    }
+
+    pub fn header_bool(&self, key: &str) -> Option<bool> {
+        self.typed_header(key, HeaderValue::as_bool)
+    }
+
+    pub fn header_i64(&self, key: &str) -> Option<i64> {
+        self.typed_header(key, HeaderValue::as_i64)
+    }
+
+    pub fn header_u64(&self, key: &str) -> Option<u64> {
+        self.typed_header(key, HeaderValue::as_u64)
+    }
+
+    pub fn header_f64(&self, key: &str) -> Option<f64> {
+        self.typed_header(key, HeaderValue::as_f64)
+    }
+
+    pub fn header_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.header(key).and_then(HeaderValue::as_bytes)
+    }
+
+    pub fn header_timestamp(&self, key: &str) -> Option<DateTime<Utc>> {
+        self.typed_header(key, HeaderValue::as_timestamp)
+    }
+
+    fn string_header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .as_ref()
+            .and_then(|headers| headers.get(key))
+            .map(|value| value.as_str())
+    }
+
+    //the typed headers come first, the string headers cover the messages built by hand
+    fn typed_header<T, F>(&self, key: &str, get: F) -> Option<T>
+    where
+        F: Fn(&HeaderValue) -> Option<T>,
+    {
+        match self.header(key) {
+            Some(value) => get(value),
+            None => self
+                .string_header(key)
+                .and_then(|value| get(&HeaderValue::from(value))),
+        }
+    }
+}
+
+/// Tells the dispatcher what to do with the message after the handler execution.
+///
+/// When the handler returns an error the dispatcher keeps the broker default behavior.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Disposition {
+    #[default]
+    Ack,
+    Retry {
+        after: Option<Duration>,
+    },
+    Reject,
+    DeadLetter {
+        reason: String,
+    },
+}
+
+impl Disposition {
+    pub fn retry() -> Disposition {
+        Disposition::Retry { after: None }
+    }
+
+    pub fn retry_after(after: Duration) -> Disposition {
+        Disposition::Retry { after: Some(after) }
+    }
+
+    pub fn dead_letter<T>(reason: T) -> Disposition
+    where
+        T: Into<String>,
+    {
+        Disposition::DeadLetter {
+            reason: reason.into(),
         }
     }
 }
@@ -36,5 +181,165 @@ impl ConsumerMessage {
 #[cfg_attr(feature = "mocks", automock)]
 #[async_trait]
 pub trait ConsumerHandler: Send + Sync {
-    async fn exec(&self, ctx: &Context, msg: &ConsumerMessage) -> Result<(), MessagingError>;
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError>;
+}
+
+#[async_trait]
+pub trait TypedConsumerHandler<T>: Send + Sync
+where
+    T: Send + Sync,
+{
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+        payload: T,
+    ) -> Result<Disposition, MessagingError>;
+}
+
+/// Adapts a TypedConsumerHandler into a ConsumerHandler, decoding the message
+/// payload with the configured codec before calling the handler.
+pub struct TypedHandler<T, C = JsonCodec> {
+    codec: C,
+    handler: Arc<dyn TypedConsumerHandler<T>>,
+    payload: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedHandler<T, JsonCodec>
+where
+    T: Send + Sync,
+{
+    pub fn new(handler: Arc<dyn TypedConsumerHandler<T>>) -> Arc<Self> {
+        Arc::new(TypedHandler {
+            codec: JsonCodec,
+            handler,
+            payload: PhantomData,
+        })
+    }
+}
+
+impl<T, C> TypedHandler<T, C>
+where
+    T: Send + Sync,
+    C: Codec<T>,
+{
+    pub fn with_codec(codec: C, handler: Arc<dyn TypedConsumerHandler<T>>) -> Arc<Self> {
+        Arc::new(TypedHandler {
+            codec,
+            handler,
+            payload: PhantomData,
+        })
+    }
+}
+
+#[async_trait]
+impl<T, C> ConsumerHandler for TypedHandler<T, C>
+where
+    T: Send + Sync,
+    C: Codec<T>,
+{
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let payload = self.codec.decode(&msg.data)?;
+
+        self.handler.exec(ctx, msg, payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        id: u32,
+    }
+
+    #[derive(Default)]
+    struct PayloadHandler {
+        received: Mutex<Vec<Payload>>,
+    }
+
+    #[async_trait]
+    impl TypedConsumerHandler<Payload> for PayloadHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+            payload: Payload,
+        ) -> Result<Disposition, MessagingError> {
+            self.received.lock().unwrap().push(payload);
+            Ok(Disposition::Ack)
+        }
+    }
+
+    #[test]
+    fn should_read_the_message_id_from_headers() {
+        let headers = HashMap::from([(MESSAGE_ID_HEADER_KEY.to_owned(), "id".to_owned())]);
+
+        let msg = ConsumerMessage::new("queue", "type", b"{}", Some(headers));
+
+        assert_eq!(msg.message_id(), Some("id"));
+        assert_eq!(ConsumerMessage::default().message_id(), None);
+
+        let msg = msg.with_metadata(MessageMetadata {
+            message_id: Some("broker-id".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(msg.message_id(), Some("broker-id"));
+    }
+
+    #[test]
+    fn should_read_the_typed_headers() {
+        let headers = HashMap::from([("retries".to_owned(), "3".to_owned())]);
+        let msg = ConsumerMessage::new("queue", "type", b"{}", Some(headers)).with_metadata(
+            MessageMetadata {
+                headers: HashMap::from([
+                    ("sampled".to_owned(), HeaderValue::Bool(true)),
+                    ("size".to_owned(), HeaderValue::LongLongUint(u64::MAX)),
+                ]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(msg.header_bool("sampled"), Some(true));
+        assert_eq!(msg.header_u64("size"), Some(u64::MAX));
+        assert_eq!(msg.header_i64("size"), None);
+        assert_eq!(msg.header_i64("retries"), Some(3));
+        assert_eq!(msg.header_str("retries"), Some("3"));
+        assert_eq!(msg.header_bytes("missing"), None);
+    }
+
+    #[tokio::test]
+    async fn should_decode_payload_before_calling_the_handler() {
+        let typed = Arc::new(PayloadHandler::default());
+        let handler = TypedHandler::new(typed.clone());
+
+        let msg = ConsumerMessage::new("queue", "type", br#"{"id":10}"#, None);
+        let res = handler.exec(&Context::new(), &msg).await;
+
+        assert_eq!(res.unwrap(), Disposition::Ack);
+        assert_eq!(*typed.received.lock().unwrap(), vec![Payload { id: 10 }]);
+    }
+
+    #[tokio::test]
+    async fn should_return_deserializing_error_when_payload_is_invalid() {
+        let typed = Arc::new(PayloadHandler::default());
+        let handler = TypedHandler::new(typed.clone());
+
+        let msg = ConsumerMessage::new("queue", "type", b"invalid", None);
+        let res = handler.exec(&Context::new(), &msg).await;
+
+        assert_eq!(res.unwrap_err(), MessagingError::DeserializingError);
+        assert!(typed.received.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,327 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+/// Header carrying the types of the headers sent as strings, a json object from the header
+/// key to its type, used by the brokers with no typed headers (Kafka, MQTT) and for the
+/// types with no AMQP equivalent. Headers with no type are strings.
+pub const HEADER_TYPES_KEY: &str = "header-types";
+
+/// Header value shared by the published and consumed messages.
+///
+/// The variant names follow the AMQP field table types, each broker carries the values
+/// natively when it can, and otherwise as strings typed by the header-types header.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HeaderValue {
+    ShortString(String),
+    LongString(String),
+    Int(i8),
+    ShortInt(i16),
+    LongInt(i32),
+    LongLongInt(i64),
+    Uint(u8),
+    ShortUint(u16),
+    LongUint(u32),
+    LongLongUint(u64),
+    Bool(bool),
+    Float(f32),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Timestamp(DateTime<Utc>),
+    Array(Vec<HeaderValue>),
+}
+
+impl HeaderValue {
+    /// Name of the type in the header-types header, None for the strings.
+    pub fn type_name(&self) -> Option<&'static str> {
+        match self {
+            HeaderValue::ShortString(_) | HeaderValue::LongString(_) => None,
+            HeaderValue::Int(_) => Some("i8"),
+            HeaderValue::ShortInt(_) => Some("i16"),
+            HeaderValue::LongInt(_) => Some("i32"),
+            HeaderValue::LongLongInt(_) => Some("i64"),
+            HeaderValue::Uint(_) => Some("u8"),
+            HeaderValue::ShortUint(_) => Some("u16"),
+            HeaderValue::LongUint(_) => Some("u32"),
+            HeaderValue::LongLongUint(_) => Some("u64"),
+            HeaderValue::Bool(_) => Some("bool"),
+            HeaderValue::Float(_) => Some("f32"),
+            HeaderValue::Double(_) => Some("f64"),
+            HeaderValue::Bytes(_) => Some("bytes"),
+            HeaderValue::Timestamp(_) => Some("timestamp"),
+            HeaderValue::Array(_) => Some("array"),
+        }
+    }
+
+    /// Parses the string encoding of the type, the inverse of the String conversion.
+    pub fn parse(type_name: &str, value: &str) -> Option<HeaderValue> {
+        let parsed = match type_name {
+            "string" => Some(HeaderValue::LongString(value.to_owned())),
+            "i8" => value.parse().ok().map(HeaderValue::Int),
+            "i16" => value.parse().ok().map(HeaderValue::ShortInt),
+            "i32" => value.parse().ok().map(HeaderValue::LongInt),
+            "i64" => value.parse().ok().map(HeaderValue::LongLongInt),
+            "u8" => value.parse().ok().map(HeaderValue::Uint),
+            "u16" => value.parse().ok().map(HeaderValue::ShortUint),
+            "u32" => value.parse().ok().map(HeaderValue::LongUint),
+            "u64" => value.parse().ok().map(HeaderValue::LongLongUint),
+            "bool" => value.parse().ok().map(HeaderValue::Bool),
+            "f32" => value.parse().ok().map(HeaderValue::Float),
+            "f64" => value.parse().ok().map(HeaderValue::Double),
+            "bytes" => STANDARD.decode(value).ok().map(HeaderValue::Bytes),
+            "timestamp" => DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|timestamp| HeaderValue::Timestamp(timestamp.with_timezone(&Utc))),
+            "array" => serde_json::from_str(value).ok().map(HeaderValue::Array),
+            _ => None,
+        };
+
+        if parsed.is_none() {
+            warn!(
+                type_name = type_name,
+                value = value,
+                "invalid typed header value"
+            );
+        }
+        parsed
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            HeaderValue::ShortString(v) | HeaderValue::LongString(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            HeaderValue::Bool(v) => Some(*v),
+            _ => self.as_str().and_then(|v| v.parse().ok()),
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            HeaderValue::Int(v) => Some(*v as i64),
+            HeaderValue::ShortInt(v) => Some(*v as i64),
+            HeaderValue::LongInt(v) => Some(*v as i64),
+            HeaderValue::LongLongInt(v) => Some(*v),
+            HeaderValue::Uint(v) => Some(*v as i64),
+            HeaderValue::ShortUint(v) => Some(*v as i64),
+            HeaderValue::LongUint(v) => Some(*v as i64),
+            HeaderValue::LongLongUint(v) => i64::try_from(*v).ok(),
+            _ => self.as_str().and_then(|v| v.parse().ok()),
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            HeaderValue::LongLongUint(v) => Some(*v),
+            HeaderValue::ShortString(_) | HeaderValue::LongString(_) => {
+                self.as_str().and_then(|v| v.parse().ok())
+            }
+            _ => self.as_i64().and_then(|v| u64::try_from(v).ok()),
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            HeaderValue::Float(v) => Some(*v as f64),
+            HeaderValue::Double(v) => Some(*v),
+            HeaderValue::ShortString(_) | HeaderValue::LongString(_) => {
+                self.as_str().and_then(|v| v.parse().ok())
+            }
+            _ => self.as_i64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            HeaderValue::Bytes(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_timestamp(&self) -> Option<DateTime<Utc>> {
+        match self {
+            HeaderValue::Timestamp(v) => Some(*v),
+            _ => self
+                .as_str()
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                .map(|v| v.with_timezone(&Utc)),
+        }
+    }
+}
+
+impl From<HeaderValue> for String {
+    fn from(val: HeaderValue) -> Self {
+        match val {
+            HeaderValue::ShortString(v) => v,
+            HeaderValue::LongString(v) => v,
+            HeaderValue::Int(v) => v.to_string(),
+            HeaderValue::ShortInt(v) => v.to_string(),
+            HeaderValue::LongInt(v) => v.to_string(),
+            HeaderValue::LongLongInt(v) => v.to_string(),
+            HeaderValue::Uint(v) => v.to_string(),
+            HeaderValue::ShortUint(v) => v.to_string(),
+            HeaderValue::LongUint(v) => v.to_string(),
+            HeaderValue::LongLongUint(v) => v.to_string(),
+            HeaderValue::Bool(v) => v.to_string(),
+            HeaderValue::Float(v) => v.to_string(),
+            HeaderValue::Double(v) => v.to_string(),
+            HeaderValue::Bytes(v) => STANDARD.encode(v),
+            HeaderValue::Timestamp(v) => v.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            HeaderValue::Array(v) => serde_json::to_string(&v).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&str> for HeaderValue {
+    fn from(val: &str) -> Self {
+        HeaderValue::LongString(val.to_owned())
+    }
+}
+
+impl From<String> for HeaderValue {
+    fn from(val: String) -> Self {
+        HeaderValue::LongString(val)
+    }
+}
+
+impl From<bool> for HeaderValue {
+    fn from(val: bool) -> Self {
+        HeaderValue::Bool(val)
+    }
+}
+
+impl From<i64> for HeaderValue {
+    fn from(val: i64) -> Self {
+        HeaderValue::LongLongInt(val)
+    }
+}
+
+impl From<u64> for HeaderValue {
+    fn from(val: u64) -> Self {
+        HeaderValue::LongLongUint(val)
+    }
+}
+
+impl From<f64> for HeaderValue {
+    fn from(val: f64) -> Self {
+        HeaderValue::Double(val)
+    }
+}
+
+impl From<DateTime<Utc>> for HeaderValue {
+    fn from(val: DateTime<Utc>) -> Self {
+        HeaderValue::Timestamp(val)
+    }
+}
+
+/// Encodes the headers as strings for the brokers with string headers, adding the
+/// header-types header when there are typed values.
+pub fn to_strings(headers: &HashMap<String, HeaderValue>) -> Vec<(String, String)> {
+    let mut types = BTreeMap::new();
+    let mut encoded = Vec::with_capacity(headers.len() + 1);
+
+    for (key, value) in headers {
+        if let Some(type_name) = value.type_name() {
+            types.insert(key.as_str(), type_name);
+        }
+        encoded.push((key.clone(), value.clone().into()));
+    }
+
+    if !types.is_empty() {
+        let types = serde_json::to_string(&types).unwrap_or_default();
+        encoded.push((HEADER_TYPES_KEY.to_owned(), types));
+    }
+
+    encoded
+}
+
+/// Restores the type of the string headers listed in the header-types header, removing it.
+pub fn apply_types(headers: &mut HashMap<String, HeaderValue>) {
+    let Some(types) = headers.remove(HEADER_TYPES_KEY) else {
+        return;
+    };
+
+    let types = match types
+        .as_str()
+        .map(serde_json::from_str::<HashMap<String, String>>)
+    {
+        Some(Ok(types)) => types,
+        _ => {
+            warn!("invalid header-types header");
+            return;
+        }
+    };
+
+    for (key, type_name) in types {
+        let Some(value) = headers.get_mut(&key) else {
+            continue;
+        };
+
+        let parsed = value
+            .as_str()
+            .and_then(|value| HeaderValue::parse(&type_name, value));
+        if let Some(parsed) = parsed {
+            *value = parsed;
+        }
+    }
+}
+
+/// Decodes the string headers typed by the header-types header, removing it from the
+/// strings. The headers with no type are kept as strings.
+pub fn from_strings(strings: &mut HashMap<String, String>) -> HashMap<String, HeaderValue> {
+    let mut typed = strings
+        .iter()
+        .map(|(key, value)| (key.clone(), HeaderValue::LongString(value.clone())))
+        .collect();
+
+    apply_types(&mut typed);
+    strings.remove(HEADER_TYPES_KEY);
+
+    typed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_the_typed_headers_through_strings() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T10:00:00.250Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = HashMap::from([
+            ("tenant".to_owned(), HeaderValue::from("acme")),
+            ("retries".to_owned(), HeaderValue::ShortInt(-3)),
+            ("size".to_owned(), HeaderValue::LongLongUint(u64::MAX)),
+            ("sampled".to_owned(), HeaderValue::Bool(true)),
+            ("ratio".to_owned(), HeaderValue::Double(0.25)),
+            ("nonce".to_owned(), HeaderValue::Bytes(vec![0, 255])),
+            ("sent_at".to_owned(), HeaderValue::Timestamp(timestamp)),
+            (
+                "tags".to_owned(),
+                HeaderValue::Array(vec![HeaderValue::from("a"), HeaderValue::LongInt(1)]),
+            ),
+        ]);
+
+        let mut strings: HashMap<String, String> = to_strings(&headers).into_iter().collect();
+        let typed = from_strings(&mut strings);
+
+        assert_eq!(typed, headers);
+        assert_eq!(strings["size"], u64::MAX.to_string());
+        assert!(!strings.contains_key(HEADER_TYPES_KEY));
+    }
+
+    #[test]
+    fn should_read_the_string_headers_as_typed_values() {
+        assert_eq!(HeaderValue::from("42").as_i64(), Some(42));
+        assert_eq!(HeaderValue::from("true").as_bool(), Some(true));
+        assert_eq!(HeaderValue::LongUint(7).as_f64(), Some(7.0));
+        assert_eq!(HeaderValue::LongLongInt(-1).as_u64(), None);
+    }
+}
@@ -1,4 +1,25 @@
+#[cfg(feature = "asyncapi")]
+pub mod asyncapi;
+pub mod batch;
+pub mod capture;
+pub mod cloudevents;
+pub mod codec;
+pub mod compression;
 pub mod dispatcher;
 pub mod errors;
 pub mod handler;
+pub mod headers;
+#[cfg(feature = "memory")]
+pub mod memory;
+pub mod metrics;
+pub mod middlewares;
 pub mod publisher;
+pub mod request_reply;
+pub mod resilience;
+pub mod routing;
+pub mod shutdown;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod supervisor;
+pub mod versioning;
+pub mod workers;
@@ -0,0 +1,630 @@
+use crate::{
+    batch::{BatchConsumerHandler, SingleMessageBatch},
+    dispatcher::{Dispatcher, DispatcherDefinition},
+    errors::MessagingError,
+    handler::{
+        ConsumerHandler, ConsumerMessage, Disposition, MessageMetadata, MESSAGE_ID_HEADER_KEY,
+    },
+    metrics::MessagingMetrics,
+    publisher::{PublishMessage, Publisher},
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
+    shutdown::CancellationToken,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use opentelemetry::Context;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+struct Registration {
+    definition: DispatcherDefinition,
+    handler: Arc<dyn ConsumerHandler>,
+}
+
+struct Delivery {
+    ctx: Context,
+    msg: PublishMessage,
+    handler: usize,
+    attempt: u32,
+    published_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Delivery>,
+    in_flight: usize,
+    published: Vec<PublishMessage>,
+    dead_letters: Vec<PublishMessage>,
+}
+
+struct Inner {
+    retries: u32,
+    metrics: MessagingMetrics,
+    state: Mutex<State>,
+    registrations: Mutex<Vec<Arc<Registration>>>,
+    delivered: Notify,
+    drained: Notify,
+}
+
+/// Broker kept in memory that implements both Publisher and Dispatcher,
+/// allowing the messaging flows to be exercised in tests without a real broker.
+///
+/// Messages are routed to every registration whose DispatcherDefinition name matches
+/// the PublishMessage destination (supporting the `+`, `*` and `#` wildcards) and whose
+/// msg_type is empty or equal to the message msg_type. Retries and scheduled messages are
+/// delivered right away, ignoring the Disposition::Retry delay and the deliver_at time.
+#[derive(Clone)]
+pub struct InMemoryBroker {
+    inner: Arc<Inner>,
+}
+
+impl Default for InMemoryBroker {
+    fn default() -> Self {
+        InMemoryBroker::new()
+    }
+}
+
+impl InMemoryBroker {
+    pub fn new() -> InMemoryBroker {
+        InMemoryBroker::with_retries(0)
+    }
+
+    pub fn with_retries(retries: u32) -> InMemoryBroker {
+        InMemoryBroker {
+            inner: Arc::new(Inner {
+                retries,
+                metrics: MessagingMetrics::new("memory"),
+                state: Mutex::new(State::default()),
+                registrations: Mutex::new(vec![]),
+                delivered: Notify::new(),
+                drained: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn published(&self) -> Vec<PublishMessage> {
+        self.inner.state.lock().unwrap().published.clone()
+    }
+
+    pub fn published_to(&self, to: &str) -> Vec<PublishMessage> {
+        self.published()
+            .into_iter()
+            .filter(|msg| msg.to == to)
+            .collect()
+    }
+
+    pub fn dead_letters(&self) -> Vec<PublishMessage> {
+        self.inner.state.lock().unwrap().dead_letters.clone()
+    }
+
+    pub fn pending(&self) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.queue.len() + state.in_flight
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.queue.clear();
+        state.published.clear();
+        state.dead_letters.clear();
+
+        if state.in_flight == 0 {
+            self.inner.drained.notify_waiters();
+        }
+    }
+
+    /// Processes every pending delivery in the current task, including the messages
+    /// published by the handlers while draining.
+    pub async fn drain(&self) {
+        while let Some(delivery) = self.next_delivery() {
+            self.deliver(delivery).await;
+        }
+    }
+
+    /// Waits until the deliveries were processed by a task running consume_blocking.
+    pub async fn wait_until_drained(&self, timeout: Duration) -> Result<(), MessagingError> {
+        let wait = async {
+            loop {
+                let drained = self.inner.drained.notified();
+                if self.pending() == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Err(_) => {
+                error!("timeout waiting the in memory broker to be drained");
+                Err(MessagingError::InternalError)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for InMemoryBroker {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        self.inner.metrics.publish(msg, self.route(ctx, msg)).await
+    }
+}
+
+impl InMemoryBroker {
+    async fn route(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        let registrations = self.inner.registrations.lock().unwrap().clone();
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.published.push(msg.clone());
+
+        let mut routed = false;
+        for (idx, registration) in registrations.iter().enumerate() {
+            if !matches(&registration.definition, msg) {
+                continue;
+            }
+
+            routed = true;
+            state.queue.push_back(Delivery {
+                ctx: ctx.clone(),
+                msg: msg.clone(),
+                handler: idx,
+                attempt: 0,
+                published_at: Utc::now(),
+            });
+        }
+        drop(state);
+
+        if !routed {
+            warn!(
+                to = msg.to,
+                msg_type = msg.msg_type,
+                "there is no handler registered for this message"
+            );
+            return Ok(());
+        }
+
+        self.inner.delivered.notify_one();
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Dispatcher for InMemoryBroker {
+    fn register(
+        self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn ConsumerHandler>,
+    ) -> Self {
+        self.inner
+            .registrations
+            .lock()
+            .unwrap()
+            .push(Arc::new(Registration {
+                definition: definition.clone(),
+                handler,
+            }));
+
+        self
+    }
+
+    /// The in memory broker delivers the messages to the batch handlers one by one.
+    fn register_batch(
+        self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn BatchConsumerHandler>,
+    ) -> Self {
+        self.register(definition, SingleMessageBatch::new(handler))
+    }
+
+    async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+        loop {
+            let delivered = self.inner.delivered.notified();
+
+            if shutdown.is_cancelled() {
+                debug!("in memory broker consumer stopped");
+                return Ok(());
+            }
+
+            match self.next_delivery() {
+                Some(delivery) => self.deliver(delivery).await,
+                _ => tokio::select! {
+                    _ = delivered => {},
+                    _ = shutdown.cancelled() => {},
+                },
+            }
+        }
+    }
+}
+
+impl InMemoryBroker {
+    fn next_delivery(&self) -> Option<Delivery> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        let delivery = state.queue.pop_front();
+        if delivery.is_some() {
+            state.in_flight += 1;
+        }
+
+        delivery
+    }
+
+    async fn deliver(&self, delivery: Delivery) {
+        //released even when the handler panics, the waiters would never be notified otherwise
+        let _in_flight = InFlight(&self.inner);
+
+        let registration = self.inner.registrations.lock().unwrap()[delivery.handler].clone();
+
+        let headers = delivery.msg.headers.clone().unwrap_or_default();
+        let header = |key: &str| headers.get(key).map(|value| value.clone().into());
+        let metadata = MessageMetadata {
+            message_id: header(MESSAGE_ID_HEADER_KEY),
+            correlation_id: header(CORRELATION_ID_HEADER_KEY),
+            reply_to: header(REPLY_TO_HEADER_KEY),
+            content_type: delivery.msg.content_type.clone(),
+            timestamp: Some(delivery.published_at),
+            delivery_count: delivery.attempt + 1,
+            redelivered: delivery.attempt > 0,
+            headers: headers.clone(),
+            ..Default::default()
+        };
+
+        let msg = ConsumerMessage::new(
+            delivery.msg.to.as_str(),
+            delivery.msg.msg_type.as_str(),
+            &delivery.msg.data,
+            delivery.msg.headers.clone().map(|headers| {
+                headers
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect::<HashMap<String, String>>()
+            }),
+        )
+        .with_metadata(metadata);
+
+        self.inner.metrics.received(&msg.from, &msg.msg_type);
+        let result = self
+            .inner
+            .metrics
+            .exec(registration.handler.as_ref(), &delivery.ctx, &msg)
+            .await;
+
+        let mut state = self.inner.state.lock().unwrap();
+
+        let disposition = match result {
+            Ok(disposition) => disposition,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    name = registration.definition.name,
+                    msg_type = msg.msg_type,
+                    "failure to process message"
+                );
+                Disposition::retry()
+            }
+        };
+
+        match disposition {
+            Disposition::Ack => debug!(
+                name = registration.definition.name,
+                msg_type = msg.msg_type,
+                "message processed successfully"
+            ),
+            Disposition::Reject => warn!(
+                name = registration.definition.name,
+                msg_type = msg.msg_type,
+                "message rejected, removing it"
+            ),
+            Disposition::Retry { .. } if delivery.attempt < self.inner.retries => {
+                debug!(
+                    name = registration.definition.name,
+                    msg_type = msg.msg_type,
+                    "requeuing message"
+                );
+                self.inner.metrics.retried(&msg);
+                state.queue.push_back(Delivery {
+                    attempt: delivery.attempt + 1,
+                    ..delivery
+                });
+            }
+            Disposition::Retry { .. } => {
+                error!(
+                    name = registration.definition.name,
+                    msg_type = msg.msg_type,
+                    "too many attempts, sending to dead letters"
+                );
+                self.inner.metrics.dead_lettered(&msg);
+                state.dead_letters.push(delivery.msg);
+            }
+            Disposition::DeadLetter { reason } => {
+                error!(
+                    name = registration.definition.name,
+                    msg_type = msg.msg_type,
+                    reason = reason,
+                    "sending message to dead letters"
+                );
+                self.inner.metrics.dead_lettered(&msg);
+                state.dead_letters.push(delivery.msg);
+            }
+        }
+    }
+}
+
+struct InFlight<'a>(&'a Inner);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .0
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.in_flight -= 1;
+
+        if state.queue.is_empty() && state.in_flight == 0 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+fn matches(definition: &DispatcherDefinition, msg: &PublishMessage) -> bool {
+    if !definition.msg_type.is_empty() && definition.msg_type != msg.msg_type {
+        return false;
+    }
+
+    definition.name.is_empty() || topic_matches(&definition.name, &msg.to)
+}
+
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let separators = ['/', '.'];
+    let filter_levels: Vec<&str> = filter.split(separators).collect();
+    let topic_levels: Vec<&str> = topic.split(separators).collect();
+
+    levels_match(&filter_levels, &topic_levels)
+}
+
+//a trailing # matches the remaining levels, in the middle it matches zero or more levels
+fn levels_match(filter: &[&str], topic: &[&str]) -> bool {
+    match (filter.split_first(), topic.split_first()) {
+        (Some((&"#", [])), _) => true,
+        (Some((&"#", rest)), _) => (0..=topic.len()).any(|skip| levels_match(rest, &topic[skip..])),
+        (Some((&"+", rest)), Some((_, topic_rest)))
+        | (Some((&"*", rest)), Some((_, topic_rest))) => levels_match(rest, topic_rest),
+        (Some((f, rest)), Some((t, topic_rest))) if f == t => levels_match(rest, topic_rest),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingHandler {
+        calls: AtomicUsize,
+        fail: bool,
+        disposition: Disposition,
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for CountingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.fail {
+                return Err(MessagingError::HandlerError);
+            }
+
+            Ok(self.disposition.clone())
+        }
+    }
+
+    fn message(to: &str, msg_type: &str) -> PublishMessage {
+        PublishMessage::new("from", to, "key", msg_type, b"{}", None)
+    }
+
+    #[test]
+    fn should_match_wildcard_topics() {
+        assert!(topic_matches("some/topic", "some/topic"));
+        assert!(topic_matches("some/+/sub", "some/topic/sub"));
+        assert!(topic_matches("some/#", "some/topic/sub"));
+        assert!(topic_matches("orders.*", "orders.created"));
+        assert!(!topic_matches("some/+", "some/topic/sub"));
+        assert!(!topic_matches("other/#", "some/topic"));
+    }
+
+    #[test]
+    fn should_match_the_hash_in_the_middle_of_the_topic() {
+        assert!(topic_matches("a.#.c", "a.c"));
+        assert!(topic_matches("a.#.c", "a.x.y.c"));
+        assert!(!topic_matches("a.#.c", "a.x.d"));
+        assert!(!topic_matches("a.#.c", "a.x.c.d"));
+    }
+
+    #[test]
+    fn should_match_zero_or_more_levels_with_the_hash_in_the_middle() {
+        assert!(levels_match(&["a", "#", "c"], &["a", "c"]));
+        assert!(levels_match(&["a", "#", "c"], &["a", "b", "c"]));
+        assert!(levels_match(&["a", "#", "c"], &["a", "b", "b", "c"]));
+        assert!(!levels_match(&["a", "#", "c"], &["a", "b"]));
+        assert!(!levels_match(&["a", "#", "c"], &["b", "c"]));
+        assert!(topic_matches("a/#/c", "a/b/c"));
+        assert!(!topic_matches("a/#/c", "a/b/c/d"));
+    }
+
+    #[tokio::test]
+    async fn should_route_published_messages_to_the_registered_handler() {
+        let handler = Arc::new(CountingHandler::default());
+
+        let broker = InMemoryBroker::new().register(
+            &DispatcherDefinition::new("orders/#", "order-created"),
+            handler.clone(),
+        );
+
+        broker
+            .publish(&Context::new(), &message("orders/eu", "order-created"))
+            .await
+            .unwrap();
+        broker
+            .publish(&Context::new(), &message("orders/eu", "order-deleted"))
+            .await
+            .unwrap();
+        broker.drain().await;
+
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(broker.published().len(), 2);
+        assert_eq!(broker.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_retry_and_dead_letter_failed_messages() {
+        let handler = Arc::new(CountingHandler {
+            fail: true,
+            ..Default::default()
+        });
+
+        let broker = InMemoryBroker::with_retries(2)
+            .register(&DispatcherDefinition::new("orders", ""), handler.clone());
+
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+        broker.drain().await;
+
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(broker.dead_letters().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_drop_rejected_messages() {
+        let handler = Arc::new(CountingHandler {
+            disposition: Disposition::Reject,
+            ..Default::default()
+        });
+
+        let broker = InMemoryBroker::with_retries(2)
+            .register(&DispatcherDefinition::new("orders", ""), handler.clone());
+
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+        broker.drain().await;
+
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 1);
+        assert!(broker.dead_letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_wait_until_consumer_drains_the_queue() {
+        let handler = Arc::new(CountingHandler::default());
+
+        let broker = InMemoryBroker::new()
+            .register(&DispatcherDefinition::new("orders", ""), handler.clone());
+
+        let consumer = broker.clone();
+        tokio::spawn(async move { consumer.consume_blocking().await });
+
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+
+        let res = broker.wait_until_drained(Duration::from_secs(1)).await;
+
+        assert!(res.is_ok());
+        assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+    }
+
+    struct PanickingHandler;
+
+    #[async_trait]
+    impl ConsumerHandler for PanickingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            panic!("handler failure")
+        }
+    }
+
+    #[tokio::test]
+    async fn should_release_the_deliveries_of_the_panicking_handlers() {
+        let broker = InMemoryBroker::new().register(
+            &DispatcherDefinition::new("orders", ""),
+            Arc::new(PanickingHandler),
+        );
+
+        let consumer = broker.clone();
+        tokio::spawn(async move { consumer.consume_blocking().await });
+
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+
+        let res = broker.wait_until_drained(Duration::from_secs(1)).await;
+
+        assert!(res.is_ok());
+        assert_eq!(broker.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_notify_the_drained_waiters_when_cleared() {
+        let broker = InMemoryBroker::new().register(
+            &DispatcherDefinition::new("orders", ""),
+            Arc::new(CountingHandler::default()),
+        );
+
+        broker
+            .publish(&Context::new(), &message("orders", "order-created"))
+            .await
+            .unwrap();
+
+        let waiter = tokio::spawn({
+            let broker = broker.clone();
+            async move { broker.wait_until_drained(Duration::from_secs(1)).await }
+        });
+        tokio::task::yield_now().await;
+        broker.clear();
+
+        assert!(waiter.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_stop_consuming_when_shutdown_is_requested() {
+        let broker = InMemoryBroker::new().register(
+            &DispatcherDefinition::new("orders", ""),
+            Arc::new(CountingHandler::default()),
+        );
+
+        let shutdown = CancellationToken::new();
+        let consumer = tokio::spawn({
+            let broker = broker.clone();
+            let shutdown = shutdown.clone();
+            async move { broker.consume_until(shutdown).await }
+        });
+
+        shutdown.cancel();
+        let res = tokio::time::timeout(Duration::from_secs(1), consumer).await;
+
+        assert!(res.is_ok());
+    }
+}
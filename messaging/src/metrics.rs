@@ -0,0 +1,260 @@
+use crate::{
+    batch::{self, BatchConsumerHandler},
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    publisher::PublishMessage,
+};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, Unit, UpDownCounter},
+    Context, KeyValue,
+};
+use std::{future::Future, time::Instant};
+
+// Follows the messaging semantic conventions:
+// https://opentelemetry.io/docs/specs/semconv/messaging/messaging-metrics/
+pub const MESSAGING_RECEIVE_MESSAGES: &str = "messaging.receive.messages";
+pub const MESSAGING_PROCESS_MESSAGES: &str = "messaging.process.messages";
+pub const MESSAGING_PROCESS_DURATION: &str = "messaging.process.duration";
+pub const MESSAGING_PROCESS_ACTIVE: &str = "messaging.process.active";
+pub const MESSAGING_PROCESS_RETRIES: &str = "messaging.process.retries";
+pub const MESSAGING_PROCESS_DEAD_LETTERS: &str = "messaging.process.dead_letters";
+pub const MESSAGING_PUBLISH_MESSAGES: &str = "messaging.publish.messages";
+pub const MESSAGING_PUBLISH_DURATION: &str = "messaging.publish.duration";
+pub const MESSAGING_PUBLISH_CIRCUIT_STATE: &str = "messaging.publish.circuit_state";
+
+const MESSAGING_SYSTEM: &str = "messaging.system";
+const MESSAGING_DESTINATION_NAME: &str = "messaging.destination.name";
+const MESSAGING_MESSAGE_TYPE: &str = "messaging.message.type";
+const ERROR_TYPE: &str = "error.type";
+
+/// OTel instruments shared by the dispatchers and publishers of a messaging system.
+///
+/// The instruments are created from the global meter provider, so `metrics::provider::init`
+/// must be called before creating the dispatchers and publishers.
+#[derive(Clone)]
+pub struct MessagingMetrics {
+    system: &'static str,
+    received: Counter<u64>,
+    processed: Counter<u64>,
+    process_duration: Histogram<f64>,
+    active: UpDownCounter<i64>,
+    retries: Counter<u64>,
+    dead_letters: Counter<u64>,
+    published: Counter<u64>,
+    publish_duration: Histogram<f64>,
+}
+
+impl MessagingMetrics {
+    pub fn new(system: &'static str) -> MessagingMetrics {
+        let meter = global::meter("messaging");
+
+        MessagingMetrics {
+            system,
+            received: meter
+                .u64_counter(MESSAGING_RECEIVE_MESSAGES)
+                .with_description("Messages received from the broker, including the ones with no handler")
+                .init(),
+            processed: meter
+                .u64_counter(MESSAGING_PROCESS_MESSAGES)
+                .with_description("Messages processed by the handlers, the failures have the error.type attribute")
+                .init(),
+            process_duration: meter
+                .f64_histogram(MESSAGING_PROCESS_DURATION)
+                .with_description("Duration of the message handler execution")
+                .with_unit(Unit::new("s"))
+                .init(),
+            active: meter
+                .i64_up_down_counter(MESSAGING_PROCESS_ACTIVE)
+                .with_description("Messages being processed by the handlers")
+                .init(),
+            retries: meter
+                .u64_counter(MESSAGING_PROCESS_RETRIES)
+                .with_description("Messages scheduled to be processed again")
+                .init(),
+            dead_letters: meter
+                .u64_counter(MESSAGING_PROCESS_DEAD_LETTERS)
+                .with_description("Messages dead lettered")
+                .init(),
+            published: meter
+                .u64_counter(MESSAGING_PUBLISH_MESSAGES)
+                .with_description("Messages published, the failures have the error.type attribute")
+                .init(),
+            publish_duration: meter
+                .f64_histogram(MESSAGING_PUBLISH_DURATION)
+                .with_description("Duration of the publish operation")
+                .with_unit(Unit::new("s"))
+                .init(),
+        }
+    }
+
+    /// Records a message received from the broker, called by the dispatchers before looking
+    /// up its handler.
+    pub fn received(&self, destination: &str, msg_type: &str) {
+        self.received
+            .add(1, &self.attributes(destination, msg_type));
+    }
+
+    /// Executes the handler recording the processed and in-flight messages.
+    pub async fn exec(
+        &self,
+        handler: &dyn ConsumerHandler,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let attributes = self.attributes(&msg.from, &msg.msg_type);
+
+        self.active.add(1, &attributes);
+
+        let started = Instant::now();
+        let result = handler.exec(ctx, msg).await;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        self.active.add(-1, &attributes);
+        self.processed(attributes, elapsed, &result);
+
+        result
+    }
+
+    /// Same as `exec` for batch handlers, every message of the batch records the batch duration.
+    pub async fn exec_batch(
+        &self,
+        handler: &dyn BatchConsumerHandler,
+        ctx: &Context,
+        msgs: &[ConsumerMessage],
+    ) -> Vec<Result<Disposition, MessagingError>> {
+        let attributes: Vec<Vec<KeyValue>> = msgs
+            .iter()
+            .map(|msg| self.attributes(&msg.from, &msg.msg_type))
+            .collect();
+
+        for attributes in &attributes {
+            self.active.add(1, attributes);
+        }
+
+        let started = Instant::now();
+        let results = batch::exec(handler, ctx, msgs).await;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        for (attributes, result) in attributes.into_iter().zip(&results) {
+            self.active.add(-1, &attributes);
+            self.processed(attributes, elapsed, result);
+        }
+
+        results
+    }
+
+    pub fn retried(&self, msg: &ConsumerMessage) {
+        self.retries
+            .add(1, &self.attributes(&msg.from, &msg.msg_type));
+    }
+
+    pub fn dead_lettered(&self, msg: &ConsumerMessage) {
+        self.dead_letters
+            .add(1, &self.attributes(&msg.from, &msg.msg_type));
+    }
+
+    /// Awaits the publish operation recording its latency and result.
+    pub async fn publish<F>(&self, msg: &PublishMessage, fut: F) -> Result<(), MessagingError>
+    where
+        F: Future<Output = Result<(), MessagingError>>,
+    {
+        let started = Instant::now();
+        let result = fut.await;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        let mut attributes = self.attributes(&msg.to, &msg.msg_type);
+        if let Err(err) = &result {
+            attributes.push(KeyValue::new(ERROR_TYPE, error_type(err)));
+        }
+
+        self.publish_duration.record(elapsed, &attributes);
+        self.published.add(1, &attributes);
+
+        result
+    }
+
+    fn processed(
+        &self,
+        mut attributes: Vec<KeyValue>,
+        elapsed: f64,
+        result: &Result<Disposition, MessagingError>,
+    ) {
+        if let Err(err) = result {
+            attributes.push(KeyValue::new(ERROR_TYPE, error_type(err)));
+        }
+
+        self.process_duration.record(elapsed, &attributes);
+        self.processed.add(1, &attributes);
+    }
+
+    fn attributes(&self, destination: &str, msg_type: &str) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new(MESSAGING_SYSTEM, self.system),
+            KeyValue::new(MESSAGING_DESTINATION_NAME, destination.to_owned()),
+            KeyValue::new(MESSAGING_MESSAGE_TYPE, msg_type.to_owned()),
+        ]
+    }
+}
+
+//the variant name, keeping the error.type cardinality bounded
+fn error_type(err: &MessagingError) -> &'static str {
+    match err {
+        MessagingError::InternalError => "InternalError",
+        MessagingError::UnregisteredHandler => "UnregisteredHandler",
+        MessagingError::ConnectionError => "ConnectionError",
+        MessagingError::CreatingConsumerError => "CreatingConsumerError",
+        MessagingError::SerializingError => "SerializingError",
+        MessagingError::DeserializingError => "DeserializingError",
+        MessagingError::HandlerError => "HandlerError",
+        MessagingError::TimeoutError => "TimeoutError",
+        MessagingError::ConsumerError(_) => "ConsumerError",
+        MessagingError::PublisherError => "PublisherError",
+        MessagingError::UnsupportedVersionError => "UnsupportedVersionError",
+        MessagingError::CompressionError => "CompressionError",
+        MessagingError::InvalidSignatureError => "InvalidSignatureError",
+        MessagingError::SchedulingError => "SchedulingError",
+        MessagingError::CaptureError => "CaptureError",
+        MessagingError::CircuitOpenError => "CircuitOpenError",
+        MessagingError::PublishNackedError => "PublishNackedError",
+        MessagingError::UnroutableError(_) => "UnroutableError",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    struct FailingHandler;
+
+    #[async_trait]
+    impl ConsumerHandler for FailingHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            Err(MessagingError::HandlerError)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_the_handler_result() {
+        let handler = FailingHandler;
+
+        let res = MessagingMetrics::new("memory")
+            .exec(&handler, &Context::new(), &ConsumerMessage::default())
+            .await;
+
+        assert_eq!(res.unwrap_err(), MessagingError::HandlerError);
+    }
+
+    #[test]
+    fn should_use_the_variant_name_as_error_type() {
+        let err = MessagingError::UnroutableError("orders".to_owned());
+
+        assert_eq!(error_type(&err), "UnroutableError");
+        assert_eq!(error_type(&MessagingError::TimeoutError), "TimeoutError");
+    }
+}
@@ -0,0 +1,76 @@
+use super::ConsumerMiddleware;
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+};
+use async_trait::async_trait;
+use opentelemetry::{trace::TraceContextExt, Context};
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, error};
+
+#[derive(Debug, Clone, Default)]
+pub struct LoggingMiddleware;
+
+impl LoggingMiddleware {
+    pub fn new() -> LoggingMiddleware {
+        LoggingMiddleware
+    }
+}
+
+impl ConsumerMiddleware for LoggingMiddleware {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(LoggingHandler { inner: handler })
+    }
+}
+
+struct LoggingHandler {
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for LoggingHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let span = ctx.span();
+        let span_ctx = span.span_context();
+        let trace_id = span_ctx.trace_id().to_string();
+        let span_id = span_ctx.span_id().to_string();
+
+        debug!(
+            trace.id = trace_id,
+            span.id = span_id,
+            from = msg.from,
+            msg_type = msg.msg_type,
+            "message received"
+        );
+
+        let started = Instant::now();
+        let result = self.inner.exec(ctx, msg).await;
+        let elapsed = started.elapsed().as_millis() as u64;
+
+        match &result {
+            Ok(_) => debug!(
+                trace.id = trace_id,
+                span.id = span_id,
+                from = msg.from,
+                msg_type = msg.msg_type,
+                elapsed_ms = elapsed,
+                "message processed successfully"
+            ),
+            Err(err) => error!(
+                error = err.to_string(),
+                trace.id = trace_id,
+                span.id = span_id,
+                from = msg.from,
+                msg_type = msg.msg_type,
+                elapsed_ms = elapsed,
+                "failure to process message"
+            ),
+        };
+
+        result
+    }
+}
@@ -0,0 +1,51 @@
+use super::ConsumerMiddleware;
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    metrics::MessagingMetrics,
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::sync::Arc;
+
+/// Records the messaging.process.* metrics of the handler with the same instruments used by
+/// the dispatchers, which already record them for every handler they execute. It is meant
+/// for the handlers executed out of a dispatcher, wrapping a dispatcher handler counts its
+/// messages twice.
+#[derive(Clone)]
+pub struct MetricsMiddleware {
+    metrics: MessagingMetrics,
+}
+
+impl MetricsMiddleware {
+    pub fn new(system: &'static str) -> MetricsMiddleware {
+        MetricsMiddleware {
+            metrics: MessagingMetrics::new(system),
+        }
+    }
+}
+
+impl ConsumerMiddleware for MetricsMiddleware {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(MetricsHandler {
+            metrics: self.metrics.clone(),
+            inner: handler,
+        })
+    }
+}
+
+struct MetricsHandler {
+    metrics: MessagingMetrics,
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for MetricsHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        self.metrics.exec(self.inner.as_ref(), ctx, msg).await
+    }
+}
@@ -0,0 +1,167 @@
+mod logging;
+mod metrics;
+mod panic;
+mod timeout;
+
+pub use logging::LoggingMiddleware;
+pub use metrics::MetricsMiddleware;
+pub use panic::PanicMiddleware;
+pub use timeout::TimeoutMiddleware;
+
+use crate::handler::ConsumerHandler;
+use std::sync::Arc;
+
+/// Wraps a ConsumerHandler into another ConsumerHandler, similar to the tower Layer.
+pub trait ConsumerMiddleware: Send + Sync {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler>;
+}
+
+/// Ordered chain of middlewares, the first middleware added is the outermost one.
+#[derive(Clone, Default)]
+pub struct Middlewares {
+    layers: Vec<Arc<dyn ConsumerMiddleware>>,
+}
+
+impl Middlewares {
+    pub fn new() -> Middlewares {
+        Middlewares::default()
+    }
+
+    pub fn layer<M>(mut self, middleware: M) -> Self
+    where
+        M: ConsumerMiddleware + 'static,
+    {
+        self.layers.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        self.layers
+            .iter()
+            .rev()
+            .fold(handler, |handler, layer| layer.wrap(handler))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        errors::MessagingError,
+        handler::{ConsumerMessage, Disposition},
+    };
+    use async_trait::async_trait;
+    use opentelemetry::Context;
+    use std::{sync::Mutex, time::Duration};
+
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    struct Recorded {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        inner: Arc<dyn ConsumerHandler>,
+    }
+
+    impl ConsumerMiddleware for Recorder {
+        fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+            Arc::new(Recorded {
+                name: self.name,
+                calls: self.calls.clone(),
+                inner: handler,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ConsumerHandler for Recorded {
+        async fn exec(
+            &self,
+            ctx: &Context,
+            msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            self.calls.lock().unwrap().push(self.name);
+            self.inner.exec(ctx, msg).await
+        }
+    }
+
+    struct SleepyHandler(Duration);
+
+    #[async_trait]
+    impl ConsumerHandler for SleepyHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            tokio::time::sleep(self.0).await;
+            Ok(Disposition::Ack)
+        }
+    }
+
+    struct PanicHandler;
+
+    #[async_trait]
+    impl ConsumerHandler for PanicHandler {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            panic!("handler panic")
+        }
+    }
+
+    #[tokio::test]
+    async fn should_execute_middlewares_in_the_registration_order() {
+        let calls = Arc::new(Mutex::new(vec![]));
+
+        let handler = Middlewares::new()
+            .layer(Recorder {
+                name: "first",
+                calls: calls.clone(),
+            })
+            .layer(Recorder {
+                name: "second",
+                calls: calls.clone(),
+            })
+            .wrap(Arc::new(SleepyHandler(Duration::ZERO)));
+
+        let res = handler
+            .exec(&Context::new(), &ConsumerMessage::default())
+            .await;
+
+        assert!(res.is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn should_return_timeout_error_when_handler_takes_too_long() {
+        let handler = Middlewares::new()
+            .layer(TimeoutMiddleware::new(Duration::from_millis(10)))
+            .wrap(Arc::new(SleepyHandler(Duration::from_secs(1))));
+
+        let res = handler
+            .exec(&Context::new(), &ConsumerMessage::default())
+            .await;
+
+        assert_eq!(res.unwrap_err(), MessagingError::TimeoutError);
+    }
+
+    #[tokio::test]
+    async fn should_convert_handler_panic_into_error() {
+        let handler = Middlewares::new()
+            .layer(LoggingMiddleware::new())
+            .layer(MetricsMiddleware::new("memory"))
+            .layer(PanicMiddleware::new())
+            .wrap(Arc::new(PanicHandler));
+
+        let res = handler
+            .exec(&Context::new(), &ConsumerMessage::default())
+            .await;
+
+        assert_eq!(res.unwrap_err(), MessagingError::HandlerError);
+    }
+}
@@ -0,0 +1,66 @@
+use super::ConsumerMiddleware;
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+};
+use async_trait::async_trait;
+use futures_util::FutureExt;
+use opentelemetry::Context;
+use std::{any::Any, panic::AssertUnwindSafe, sync::Arc};
+use tracing::error;
+
+#[derive(Debug, Clone, Default)]
+pub struct PanicMiddleware;
+
+impl PanicMiddleware {
+    pub fn new() -> PanicMiddleware {
+        PanicMiddleware
+    }
+}
+
+impl ConsumerMiddleware for PanicMiddleware {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(PanicHandler { inner: handler })
+    }
+}
+
+struct PanicHandler {
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for PanicHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        match AssertUnwindSafe(self.inner.exec(ctx, msg))
+            .catch_unwind()
+            .await
+        {
+            Err(panic) => {
+                error!(
+                    panic = panic_message(&panic),
+                    from = msg.from,
+                    msg_type = msg.msg_type,
+                    "handler panicked"
+                );
+                Err(MessagingError::HandlerError)
+            }
+            Ok(result) => result,
+        }
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        return msg.to_string();
+    }
+
+    if let Some(msg) = panic.downcast_ref::<String>() {
+        return msg.to_owned();
+    }
+
+    "unknown panic".to_owned()
+}
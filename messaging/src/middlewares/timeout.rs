@@ -0,0 +1,56 @@
+use super::ConsumerMiddleware;
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::{sync::Arc, time::Duration};
+use tracing::error;
+
+#[derive(Debug, Clone)]
+pub struct TimeoutMiddleware {
+    timeout: Duration,
+}
+
+impl TimeoutMiddleware {
+    pub fn new(timeout: Duration) -> TimeoutMiddleware {
+        TimeoutMiddleware { timeout }
+    }
+}
+
+impl ConsumerMiddleware for TimeoutMiddleware {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(TimeoutHandler {
+            timeout: self.timeout,
+            inner: handler,
+        })
+    }
+}
+
+struct TimeoutHandler {
+    timeout: Duration,
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for TimeoutHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        match tokio::time::timeout(self.timeout, self.inner.exec(ctx, msg)).await {
+            Err(_) => {
+                error!(
+                    from = msg.from,
+                    msg_type = msg.msg_type,
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "handler timed out"
+                );
+                Err(MessagingError::TimeoutError)
+            }
+            Ok(result) => result,
+        }
+    }
+}
@@ -1,37 +1,17 @@
-use crate::errors::MessagingError;
+use crate::{codec::Codec, compression::Compression, errors::MessagingError};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use opentelemetry::Context;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
+use tracing::error;
 
 #[cfg(feature = "mocks")]
 use mockall::*;
 
-#[derive(Clone)]
-pub enum HeaderValues {
-    ShortString(String),
-    LongString(String),
-    Int(i8),
-    LongInt(i32),
-    LongLongInt(i64),
-    Uint(u8),
-    LongUint(u32),
-    LongLongUint(u64),
-}
+pub use crate::headers::HeaderValue;
 
-impl From<HeaderValues> for String {
-    fn from(val: HeaderValues) -> Self {
-        match val {
-            HeaderValues::ShortString(v) => v,
-            HeaderValues::LongString(v) => v,
-            HeaderValues::Int(v) => v.to_string(),
-            HeaderValues::LongInt(v) => v.to_string(),
-            HeaderValues::LongLongInt(v) => v.to_string(),
-            HeaderValues::Uint(v) => v.to_string(),
-            HeaderValues::LongUint(v) => v.to_string(),
-            HeaderValues::LongLongUint(v) => v.to_string(),
-        }
-    }
-}
+#[deprecated(note = "use HeaderValue")]
+pub type HeaderValues = HeaderValue;
 
 #[derive(Clone)]
 pub struct PublishMessage {
@@ -40,7 +20,12 @@ pub struct PublishMessage {
     pub key: String,
     pub msg_type: String,
     pub data: Box<[u8]>,
-    pub headers: Option<HashMap<String, HeaderValues>>,
+    pub content_type: Option<String>,
+    pub headers: Option<HashMap<String, HeaderValue>>,
+    /// Overrides the publisher compression for this message.
+    pub compression: Option<Compression>,
+    /// Delivers the message at the given time instead of right away.
+    pub deliver_at: Option<DateTime<Utc>>,
 }
 
 impl PublishMessage {
@@ -50,7 +35,7 @@ impl PublishMessage {
         key: T,
         msg_type: T,
         data: &[u8],
-        headers: Option<HashMap<String, HeaderValues>>,
+        headers: Option<HashMap<String, HeaderValue>>,
     ) -> Self
     where
         T: Into<String>,
@@ -61,13 +46,165 @@ impl PublishMessage {
             key: key.into(),
             msg_type: msg_type.into(),
             data: data.into(),
+            content_type: None,
             headers,
+            compression: None,
+            deliver_at: None,
         }
     }
+
+    pub fn encode<T, C, S>(
+        codec: &C,
+        from: S,
+        to: S,
+        key: S,
+        msg_type: S,
+        payload: &T,
+        headers: Option<HashMap<String, HeaderValue>>,
+    ) -> Result<Self, MessagingError>
+    where
+        C: Codec<T>,
+        S: Into<String>,
+    {
+        let data = codec.encode(payload)?;
+
+        Ok(PublishMessage::new(from, to, key, msg_type, &data, headers)
+            .with_content_type(codec.content_type()))
+    }
+
+    pub fn with_content_type<T>(mut self, content_type: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_deliver_at(mut self, at: DateTime<Utc>) -> Self {
+        self.deliver_at = Some(at);
+        self
+    }
+
+    /// Time remaining until the scheduled delivery, None when the message must be delivered
+    /// right away.
+    pub fn delay(&self) -> Option<Duration> {
+        self.deliver_at
+            .and_then(|at| (at - Utc::now()).to_std().ok())
+            .filter(|delay| !delay.is_zero())
+    }
 }
 
 #[cfg_attr(feature = "mocks", automock)]
 #[async_trait]
 pub trait Publisher: Send + Sync {
     async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError>;
+
+    /// Publishes the message to be delivered at the given time, the publishers with no
+    /// delayed delivery support return MessagingError::SchedulingError.
+    async fn publish_at(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        at: DateTime<Utc>,
+    ) -> Result<(), MessagingError> {
+        self.publish(ctx, &msg.clone().with_deliver_at(at)).await
+    }
+
+    async fn publish_after(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        after: Duration,
+    ) -> Result<(), MessagingError> {
+        let after = match chrono::Duration::from_std(after) {
+            Err(err) => {
+                error!(error = err.to_string(), "invalid delivery delay");
+                Err(MessagingError::SchedulingError)
+            }
+            Ok(after) => Ok(after),
+        }?;
+
+        let at = match Utc::now().checked_add_signed(after) {
+            None => {
+                error!("the delivery delay exceeds the supported dates");
+                Err(MessagingError::SchedulingError)
+            }
+            Some(at) => Ok(at),
+        }?;
+
+        self.publish_at(ctx, msg, at).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{JsonCodec, JSON_CONTENT_TYPE};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct Payload {
+        id: u32,
+    }
+
+    struct NoopPublisher;
+
+    #[async_trait]
+    impl Publisher for NoopPublisher {
+        async fn publish(
+            &self,
+            _ctx: &Context,
+            _msg: &PublishMessage,
+        ) -> Result<(), MessagingError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_fail_to_publish_after_a_delay_beyond_the_supported_dates() {
+        let msg = PublishMessage::new("", "to", "", "type", b"{}", None);
+
+        //a million years, valid for chrono::Duration but not for DateTime
+        let res = NoopPublisher
+            .publish_after(&Context::new(), &msg, Duration::from_secs(1 << 45))
+            .await;
+
+        assert_eq!(res, Err(MessagingError::SchedulingError));
+    }
+
+    #[test]
+    fn should_return_the_delay_of_the_scheduled_messages() {
+        let msg = PublishMessage::new("", "to", "", "type", b"{}", None);
+        assert_eq!(msg.delay(), None);
+
+        let past = msg
+            .clone()
+            .with_deliver_at(Utc::now() - chrono::Duration::seconds(1));
+        assert_eq!(past.delay(), None);
+
+        let future = msg.with_deliver_at(Utc::now() + chrono::Duration::seconds(60));
+        assert!(future.delay().unwrap() > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn should_encode_payload_and_set_content_type() {
+        let msg = PublishMessage::encode(
+            &JsonCodec,
+            "from",
+            "to",
+            "key",
+            "type",
+            &Payload { id: 1 },
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(&*msg.data, br#"{"id":1}"#);
+        assert_eq!(msg.content_type, Some(JSON_CONTENT_TYPE.to_owned()));
+    }
 }
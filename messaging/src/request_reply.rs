@@ -0,0 +1,362 @@
+use crate::{
+    codec::Codec,
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+#[cfg(feature = "mocks")]
+use mockall::*;
+
+/// Header carrying the id used to match the reply with the request.
+pub const CORRELATION_ID_HEADER_KEY: &str = "correlation-id";
+
+/// Header carrying the destination where the reply must be published.
+pub const REPLY_TO_HEADER_KEY: &str = "reply-to";
+
+#[cfg_attr(feature = "mocks", automock)]
+#[async_trait]
+pub trait RequestReplyClient: Send + Sync {
+    /// Publishes the request and waits the reply until the timeout.
+    async fn request(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        timeout: Duration,
+    ) -> Result<ConsumerMessage, MessagingError>;
+}
+
+/// Requests waiting for a reply, shared by the broker clients and the task consuming the replies.
+#[derive(Default)]
+pub struct PendingReplies {
+    waiting: Mutex<HashMap<String, oneshot::Sender<ConsumerMessage>>>,
+}
+
+impl PendingReplies {
+    pub fn new() -> Arc<PendingReplies> {
+        Arc::new(PendingReplies::default())
+    }
+
+    /// Publishes the request with a new correlation id and the given reply destination,
+    /// waiting the matching reply until the timeout.
+    pub async fn request(
+        &self,
+        publisher: &dyn Publisher,
+        ctx: &Context,
+        msg: &PublishMessage,
+        reply_to: &str,
+        timeout: Duration,
+    ) -> Result<ConsumerMessage, MessagingError> {
+        let correlation_id = Uuid::new_v4().to_string();
+
+        let mut request = msg.clone();
+        let headers = request.headers.get_or_insert_with(HashMap::new);
+        headers.insert(
+            CORRELATION_ID_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString(correlation_id.clone()),
+        );
+        headers.insert(
+            REPLY_TO_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString(reply_to.to_owned()),
+        );
+
+        let (sender, receiver) = oneshot::channel();
+        let _waiting = Waiting::new(self, &correlation_id, sender);
+
+        publisher.publish(ctx, &request).await?;
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Err(_) => {
+                error!(
+                    correlation_id = correlation_id,
+                    to = msg.to,
+                    "timeout waiting the reply"
+                );
+                Err(MessagingError::TimeoutError)
+            }
+            Ok(Err(_)) => Err(MessagingError::InternalError),
+            Ok(Ok(reply)) => Ok(reply),
+        }
+    }
+
+    /// Delivers the reply to the waiting request, returning false when there is no request
+    /// waiting for it.
+    pub fn complete(&self, reply: ConsumerMessage) -> bool {
+        let Some(correlation_id) = correlation_id(&reply) else {
+            warn!(from = reply.from, "ignoring reply with no correlation id");
+            return false;
+        };
+
+        let Some(sender) = self.waiting.lock().unwrap().remove(correlation_id) else {
+            debug!(
+                correlation_id = correlation_id,
+                "ignoring reply, the request is not waiting anymore"
+            );
+            return false;
+        };
+
+        sender.send(reply).is_ok()
+    }
+}
+
+/// Request registered as waiting for its reply until dropped, including when the request
+/// future is cancelled.
+struct Waiting<'a> {
+    pending: &'a PendingReplies,
+    correlation_id: &'a str,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(
+        pending: &'a PendingReplies,
+        correlation_id: &'a str,
+        sender: oneshot::Sender<ConsumerMessage>,
+    ) -> Self {
+        pending
+            .waiting
+            .lock()
+            .unwrap()
+            .insert(correlation_id.to_owned(), sender);
+
+        Waiting {
+            pending,
+            correlation_id,
+        }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .waiting
+            .lock()
+            .unwrap()
+            .remove(self.correlation_id);
+    }
+}
+
+/// Reply produced by a RequestHandler.
+#[derive(Clone, Default)]
+pub struct Reply {
+    pub msg_type: String,
+    pub data: Box<[u8]>,
+    pub content_type: Option<String>,
+    pub headers: Option<HashMap<String, HeaderValue>>,
+}
+
+impl Reply {
+    pub fn new<T>(msg_type: T, data: &[u8]) -> Self
+    where
+        T: Into<String>,
+    {
+        Reply {
+            msg_type: msg_type.into(),
+            data: data.into(),
+            ..Reply::default()
+        }
+    }
+
+    pub fn encode<T, C, S>(codec: &C, msg_type: S, payload: &T) -> Result<Self, MessagingError>
+    where
+        C: Codec<T>,
+        S: Into<String>,
+    {
+        let data = codec.encode(payload)?;
+
+        let mut reply = Reply::new(msg_type, &data);
+        reply.content_type = Some(codec.content_type().to_owned());
+
+        Ok(reply)
+    }
+}
+
+#[cfg_attr(feature = "mocks", automock)]
+#[async_trait]
+pub trait RequestHandler: Send + Sync {
+    async fn exec(&self, ctx: &Context, msg: &ConsumerMessage) -> Result<Reply, MessagingError>;
+}
+
+/// ConsumerHandler answering the requests with the RequestHandler reply, published to the
+/// request reply destination with the request correlation id.
+///
+/// Messages with no reply destination or correlation id are rejected.
+pub struct Responder {
+    publisher: Arc<dyn Publisher>,
+    handler: Arc<dyn RequestHandler>,
+}
+
+impl Responder {
+    pub fn new(publisher: Arc<dyn Publisher>, handler: Arc<dyn RequestHandler>) -> Arc<Self> {
+        Arc::new(Responder { publisher, handler })
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for Responder {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let headers = msg.headers.as_ref();
+
+        let (Some(correlation_id), Some(reply_to)) = (
+            correlation_id(msg),
+            headers.and_then(|headers| headers.get(REPLY_TO_HEADER_KEY)),
+        ) else {
+            warn!(
+                from = msg.from,
+                msg_type = msg.msg_type,
+                "rejecting request with no reply destination or correlation id"
+            );
+            return Ok(Disposition::Reject);
+        };
+
+        let reply = self.handler.exec(ctx, msg).await?;
+
+        let mut reply_headers = reply.headers.unwrap_or_default();
+        reply_headers.insert(
+            CORRELATION_ID_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString(correlation_id.to_owned()),
+        );
+
+        let mut publish = PublishMessage::new(
+            msg.from.as_str(),
+            reply_to.as_str(),
+            reply.msg_type.as_str(),
+            reply.msg_type.as_str(),
+            &reply.data,
+            Some(reply_headers),
+        );
+        publish.content_type = reply.content_type;
+
+        self.publisher.publish(ctx, &publish).await?;
+
+        Ok(Disposition::Ack)
+    }
+}
+
+fn correlation_id(msg: &ConsumerMessage) -> Option<&str> {
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get(CORRELATION_ID_HEADER_KEY))
+        .map(|id| id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ReplyingPublisher {
+        pending: Arc<PendingReplies>,
+    }
+
+    #[async_trait]
+    impl Publisher for ReplyingPublisher {
+        async fn publish(
+            &self,
+            _ctx: &Context,
+            msg: &PublishMessage,
+        ) -> Result<(), MessagingError> {
+            let headers = msg
+                .headers
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect();
+
+            let reply = ConsumerMessage::new("replies", "pong", &msg.data, Some(headers));
+            tokio::spawn({
+                let pending = self.pending.clone();
+                async move { pending.complete(reply) }
+            });
+
+            Ok(())
+        }
+    }
+
+    struct SilentPublisher;
+
+    #[async_trait]
+    impl Publisher for SilentPublisher {
+        async fn publish(
+            &self,
+            _ctx: &Context,
+            _msg: &PublishMessage,
+        ) -> Result<(), MessagingError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_the_reply_with_the_same_correlation_id() {
+        let pending = PendingReplies::new();
+        let publisher = ReplyingPublisher {
+            pending: pending.clone(),
+        };
+
+        let msg = PublishMessage::new("", "requests", "", "ping", b"data", None);
+        let reply = pending
+            .request(
+                &publisher,
+                &Context::new(),
+                &msg,
+                "replies",
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(reply.msg_type, "pong");
+        assert_eq!(&*reply.data, b"data");
+    }
+
+    #[tokio::test]
+    async fn should_return_timeout_error_when_there_is_no_reply() {
+        let pending = PendingReplies::new();
+
+        let msg = PublishMessage::new("", "requests", "", "ping", b"data", None);
+        let res = pending
+            .request(
+                &SilentPublisher,
+                &Context::new(),
+                &msg,
+                "replies",
+                Duration::from_millis(10),
+            )
+            .await;
+
+        assert_eq!(res.unwrap_err(), MessagingError::TimeoutError);
+        assert!(pending.waiting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_stop_waiting_the_reply_when_the_request_is_cancelled() {
+        let pending = PendingReplies::new();
+
+        let ctx = Context::new();
+        let msg = PublishMessage::new("", "requests", "", "ping", b"data", None);
+        let request = pending.request(
+            &SilentPublisher,
+            &ctx,
+            &msg,
+            "replies",
+            Duration::from_secs(60),
+        );
+        let res = tokio::time::timeout(Duration::from_millis(10), request).await;
+
+        assert!(res.is_err());
+        assert!(pending.waiting.lock().unwrap().is_empty());
+    }
+}
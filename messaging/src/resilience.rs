@@ -0,0 +1,629 @@
+use crate::{
+    errors::MessagingError,
+    metrics::MESSAGING_PUBLISH_CIRCUIT_STATE,
+    publisher::{PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use opentelemetry::{global, metrics::ObservableGauge, Context, KeyValue};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// Exponential backoff between the publish attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Single attempt, no retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::default().max_attempts(1)
+    }
+
+    /// Attempts including the first one.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Backoff after the failed attempt, starting at 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        if self.initial_backoff.is_zero() {
+            return Duration::ZERO;
+        }
+
+        //the factor overflows the Duration after enough attempts, capped by the max backoff
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+
+        Duration::try_from_secs_f64(backoff)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn value(&self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    open_timeout: Duration,
+    probing: bool,
+}
+
+impl Breaker {
+    fn elapsed(&self) -> bool {
+        self.opened_at
+            .is_some_and(|opened_at| opened_at.elapsed() >= self.open_timeout)
+    }
+
+    //the open circuit is reported half-open once the timeout is over, even with no calls
+    fn current(&self) -> CircuitState {
+        match self.state {
+            CircuitState::Open if self.elapsed() => CircuitState::HalfOpen,
+            state => state,
+        }
+    }
+}
+
+/// Circuit breaker opening after consecutive failures, failing fast while open.
+///
+/// After the open timeout the circuit is half-open and a single call is let through,
+/// closing the circuit when it succeeds and opening it again when it fails. The state is
+/// exported as the messaging.publish.circuit_state gauge: 0 closed, 1 half-open and 2 open.
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    breaker: Arc<Mutex<Breaker>>,
+    _gauge: ObservableGauge<i64>,
+}
+
+impl CircuitBreaker {
+    pub fn new<T>(name: T) -> CircuitBreaker
+    where
+        T: Into<String>,
+    {
+        let name = name.into();
+        let breaker = Arc::new(Mutex::new(Breaker {
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: None,
+            open_timeout: Duration::from_secs(30),
+            probing: false,
+        }));
+
+        let observed = breaker.clone();
+        let attributes = [KeyValue::new("name", name.clone())];
+        let gauge = global::meter("messaging")
+            .i64_observable_gauge(MESSAGING_PUBLISH_CIRCUIT_STATE)
+            .with_description(
+                "State of the publisher circuit breaker, 0 closed, 1 half-open and 2 open",
+            )
+            .with_callback(move |observer| {
+                observer.observe(observed.lock().unwrap().current().value(), &attributes)
+            })
+            .init();
+
+        CircuitBreaker {
+            name,
+            failure_threshold: 5,
+            breaker,
+            _gauge: gauge,
+        }
+    }
+
+    /// Consecutive failures opening the circuit.
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// Time the circuit stays open before letting a call through.
+    pub fn open_timeout(self, timeout: Duration) -> Self {
+        self.breaker.lock().unwrap().open_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.breaker.lock().unwrap().current()
+    }
+
+    /// Whether the call can be made, moving the open circuit to half-open after the timeout.
+    pub fn allow(&self) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                if !breaker.elapsed() {
+                    return false;
+                }
+
+                debug!(name = self.name, "circuit half-open");
+                breaker.state = CircuitState::HalfOpen;
+                breaker.probing = true;
+                true
+            }
+            CircuitState::HalfOpen if breaker.probing => false,
+            CircuitState::HalfOpen => {
+                breaker.probing = true;
+                true
+            }
+        }
+    }
+
+    pub fn success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        if breaker.state != CircuitState::Closed {
+            debug!(name = self.name, "circuit closed");
+        }
+
+        breaker.state = CircuitState::Closed;
+        breaker.failures = 0;
+        breaker.opened_at = None;
+        breaker.probing = false;
+    }
+
+    /// Releases the half-open call ending with no outcome, letting another call through.
+    pub fn release(&self) {
+        self.breaker.lock().unwrap().probing = false;
+    }
+
+    pub fn failure(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        breaker.failures += 1;
+        breaker.probing = false;
+
+        let open = match breaker.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => breaker.failures >= self.failure_threshold,
+            CircuitState::Open => false,
+        };
+
+        if open {
+            warn!(
+                name = self.name,
+                failures = breaker.failures,
+                "circuit open"
+            );
+            breaker.state = CircuitState::Open;
+            breaker.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Publisher retrying the failed publishes with backoff behind a circuit breaker, so the
+/// broker blips are absorbed instead of failing the callers.
+///
+/// With the fallback buffer the messages that could not be published are kept in memory
+/// and published again after the next successful publish or when flush is called, after
+/// the message that succeeded, so the publishing order is not kept. The buffer is lost when
+/// the process stops, the OutboxPublisher must be used when the messages cannot be lost.
+pub struct ResilientPublisher {
+    inner: Arc<dyn Publisher>,
+    retry: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
+    buffer: Option<Mutex<VecDeque<(Context, PublishMessage)>>>,
+    buffer_capacity: usize,
+}
+
+impl ResilientPublisher {
+    pub fn new(publisher: Arc<dyn Publisher>) -> ResilientPublisher {
+        ResilientPublisher {
+            inner: publisher,
+            retry: RetryPolicy::default(),
+            breaker: CircuitBreaker::new("publisher").build(),
+            buffer: None,
+            buffer_capacity: 0,
+        }
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    pub fn circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    pub fn fallback_buffer(mut self, capacity: usize) -> Self {
+        self.buffer = Some(Mutex::new(VecDeque::with_capacity(capacity)));
+        self.buffer_capacity = capacity;
+        self
+    }
+
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    pub fn breaker(&self) -> Arc<CircuitBreaker> {
+        self.breaker.clone()
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer
+            .as_ref()
+            .map_or(0, |buffer| buffer.lock().unwrap().len())
+    }
+
+    /// Publishes the buffered messages in order, stopping at the first failure, returns the
+    /// number of published messages.
+    pub async fn flush(&self) -> usize {
+        let Some(buffer) = &self.buffer else {
+            return 0;
+        };
+
+        let mut flushed = 0;
+        loop {
+            let Some((ctx, msg)) = buffer.lock().unwrap().pop_front() else {
+                break;
+            };
+
+            if let Err(err) = self.inner.publish(&ctx, &msg).await {
+                error!(
+                    error = err.to_string(),
+                    to = msg.to,
+                    "failure to publish buffered message"
+                );
+                buffer.lock().unwrap().push_front((ctx, msg));
+                self.breaker.failure();
+                break;
+            }
+            flushed += 1;
+        }
+
+        if flushed > 0 {
+            debug!(flushed = flushed, "buffered messages published");
+        }
+
+        flushed
+    }
+
+    async fn attempt(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.inner.publish(ctx, msg).await {
+                Ok(()) => return Ok(()),
+                Err(err) if !retryable(&err) => return Err(err),
+                Err(err) => err,
+            };
+
+            if attempt >= self.retry.max_attempts {
+                return Err(err);
+            }
+
+            let backoff = self.retry.backoff(attempt);
+            warn!(
+                error = err.to_string(),
+                to = msg.to,
+                attempt = attempt,
+                backoff_ms = backoff.as_millis() as u64,
+                "failure to publish, retrying"
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    fn fallback(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        err: MessagingError,
+    ) -> Result<(), MessagingError> {
+        let Some(buffer) = &self.buffer else {
+            return Err(err);
+        };
+
+        let mut buffer = buffer.lock().unwrap();
+        if buffer.len() >= self.buffer_capacity {
+            error!(
+                error = err.to_string(),
+                to = msg.to,
+                "the fallback buffer is full, dropping the message"
+            );
+            return Err(err);
+        }
+
+        warn!(
+            error = err.to_string(),
+            to = msg.to,
+            "message kept in the fallback buffer"
+        );
+        buffer.push_back((ctx.clone(), msg.clone()));
+        Ok(())
+    }
+}
+
+/// Only the broker failures are retried, the messages failing to be encoded would fail again.
+fn retryable(err: &MessagingError) -> bool {
+    matches!(
+        err,
+        MessagingError::PublisherError
+            | MessagingError::PublishNackedError
+            | MessagingError::ConnectionError
+            | MessagingError::TimeoutError
+    )
+}
+
+struct Probe<'a> {
+    breaker: &'a CircuitBreaker,
+    done: bool,
+}
+
+impl<'a> Probe<'a> {
+    fn new(breaker: &'a CircuitBreaker) -> Self {
+        Probe {
+            breaker,
+            done: false,
+        }
+    }
+
+    fn outcome<T>(mut self, outcome: T) -> T {
+        self.done = true;
+        outcome
+    }
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breaker.release();
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for ResilientPublisher {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        if !self.breaker.allow() {
+            return self.fallback(ctx, msg, MessagingError::CircuitOpenError);
+        }
+
+        //releases the half-open probe when the caller drops the publish before the outcome
+        let probe = Probe::new(&self.breaker);
+
+        match probe.outcome(self.attempt(ctx, msg).await) {
+            Ok(()) => {
+                self.breaker.success();
+                if self.buffered() > 0 {
+                    self.flush().await;
+                }
+                Ok(())
+            }
+            Err(err) if retryable(&err) => {
+                self.breaker.failure();
+                self.fallback(ctx, msg, err)
+            }
+            Err(err) => {
+                self.breaker.release();
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Default)]
+    struct FlakyPublisher {
+        failures: AtomicU32,
+        published: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Publisher for FlakyPublisher {
+        async fn publish(
+            &self,
+            _ctx: &Context,
+            msg: &PublishMessage,
+        ) -> Result<(), MessagingError> {
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(MessagingError::PublisherError);
+            }
+
+            self.published.lock().unwrap().push(msg.msg_type.clone());
+            Ok(())
+        }
+    }
+
+    fn message(msg_type: &str) -> PublishMessage {
+        PublishMessage::new("", "exchange", "", msg_type, b"{}", None)
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy::new()
+            .max_attempts(3)
+            .initial_backoff(Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn should_retry_until_the_message_is_published() {
+        let inner = Arc::new(FlakyPublisher::default());
+        inner.failures.store(2, Ordering::SeqCst);
+
+        let publisher = ResilientPublisher::new(inner.clone())
+            .retry(retry())
+            .build();
+
+        let res = publisher.publish(&Context::new(), &message("first")).await;
+
+        assert!(res.is_ok());
+        assert_eq!(*inner.published.lock().unwrap(), vec!["first"]);
+        assert_eq!(publisher.breaker().state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn should_open_the_circuit_and_buffer_the_messages() {
+        let inner = Arc::new(FlakyPublisher::default());
+        inner.failures.store(3, Ordering::SeqCst);
+
+        let breaker = CircuitBreaker::new("test")
+            .failure_threshold(1)
+            .open_timeout(Duration::from_millis(20))
+            .build();
+        let publisher = ResilientPublisher::new(inner.clone())
+            .retry(retry())
+            .circuit_breaker(breaker.clone())
+            .fallback_buffer(10)
+            .build();
+        let ctx = Context::new();
+
+        let res = publisher.publish(&ctx, &message("first")).await;
+        assert!(res.is_ok());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(publisher.buffered(), 1);
+
+        //the open timeout is over, the half-open probe closes the circuit and flushes the buffer
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let res = publisher.publish(&ctx, &message("second")).await;
+        assert!(res.is_ok());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(*inner.published.lock().unwrap(), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn should_cap_the_backoff() {
+        let policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(300));
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn should_not_overflow_the_backoff_of_the_long_retries() {
+        let policy = RetryPolicy::new()
+            .max_attempts(u32::MAX)
+            .initial_backoff(Duration::from_secs(1))
+            .max_backoff(Duration::from_secs(60));
+
+        assert_eq!(policy.backoff(66), Duration::from_secs(60));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn should_release_the_probe_of_the_dropped_publish() {
+        struct PendingPublisher;
+
+        #[async_trait]
+        impl Publisher for PendingPublisher {
+            async fn publish(
+                &self,
+                _ctx: &Context,
+                _msg: &PublishMessage,
+            ) -> Result<(), MessagingError> {
+                std::future::pending().await
+            }
+        }
+
+        let breaker = CircuitBreaker::new("test")
+            .failure_threshold(1)
+            .open_timeout(Duration::ZERO)
+            .build();
+        breaker.failure();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        let publisher = ResilientPublisher::new(Arc::new(PendingPublisher))
+            .circuit_breaker(breaker.clone())
+            .build();
+
+        let (ctx, msg) = (Context::new(), message("first"));
+        let res =
+            tokio::time::timeout(Duration::from_millis(10), publisher.publish(&ctx, &msg)).await;
+        assert!(res.is_err());
+
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn should_report_half_open_after_the_open_timeout() {
+        let breaker = CircuitBreaker::new("test")
+            .failure_threshold(1)
+            .open_timeout(Duration::from_millis(5))
+            .build();
+
+        breaker.failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    versioning::VERSION_HEADER_KEY,
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+type Predicate = Arc<dyn Fn(&ConsumerMessage) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+enum Condition {
+    HeaderEquals { key: String, value: String },
+    HeaderExists(String),
+    HeaderAtLeast { key: String, value: i64 },
+    Custom(Predicate),
+}
+
+impl Condition {
+    fn matches(&self, msg: &ConsumerMessage) -> bool {
+        let header = |key: &str| msg.headers.as_ref().and_then(|headers| headers.get(key));
+
+        match self {
+            Condition::HeaderEquals { key, value } => header(key) == Some(value),
+            Condition::HeaderExists(key) => header(key).is_some(),
+            Condition::HeaderAtLeast { key, value } => header(key)
+                .and_then(|header| header.parse::<i64>().ok())
+                .is_some_and(|header| header >= *value),
+            Condition::Custom(predicate) => predicate(msg),
+        }
+    }
+}
+
+/// Handler selected when all the conditions match the message.
+#[derive(Clone)]
+pub struct Route {
+    name: String,
+    priority: i32,
+    conditions: Vec<Condition>,
+    handler: Arc<dyn ConsumerHandler>,
+}
+
+impl Route {
+    pub fn new<T>(name: T, handler: Arc<dyn ConsumerHandler>) -> Self
+    where
+        T: Into<String>,
+    {
+        Route {
+            name: name.into(),
+            priority: 0,
+            conditions: vec![],
+            handler,
+        }
+    }
+
+    /// Routes with the higher priority are evaluated first, routes with the same priority
+    /// are evaluated in the registration order.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn header_eq<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.conditions.push(Condition::HeaderEquals {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    pub fn header_exists<K>(mut self, key: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.conditions.push(Condition::HeaderExists(key.into()));
+        self
+    }
+
+    /// Matches numeric headers greater than or equal to the value.
+    pub fn header_gte<K>(mut self, key: K, value: i64) -> Self
+    where
+        K: Into<String>,
+    {
+        self.conditions.push(Condition::HeaderAtLeast {
+            key: key.into(),
+            value,
+        });
+        self
+    }
+
+    /// Matches the messages with the payload version greater than or equal to the version,
+    /// messages with no version are the version 1.
+    pub fn version_gte(self, version: u32) -> Self {
+        self.when(move |msg| {
+            let current = msg
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(VERSION_HEADER_KEY))
+                .map_or(Some(1), |header| header.parse::<u32>().ok());
+
+            current.is_some_and(|current| current >= version)
+        })
+    }
+
+    pub fn when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&ConsumerMessage) -> bool + Send + Sync + 'static,
+    {
+        self.conditions.push(Condition::Custom(Arc::new(predicate)));
+        self
+    }
+
+    fn matches(&self, msg: &ConsumerMessage) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(msg))
+    }
+}
+
+/// ConsumerHandler dispatching the message to the first matching route, allowing many
+/// handlers to be registered for the same msg_type.
+///
+/// Messages matching no route are handled by the fallback handler, and with no fallback
+/// the unmatched disposition is returned, acking them by default.
+#[derive(Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Arc<dyn ConsumerHandler>>,
+    unmatched: Disposition,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router {
+            routes: vec![],
+            fallback: None,
+            unmatched: Disposition::Ack,
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        //stable sort, keeping the registration order of the same priority
+        self.routes.sort_by_key(|route| std::cmp::Reverse(route.priority));
+        self
+    }
+
+    pub fn fallback(mut self, handler: Arc<dyn ConsumerHandler>) -> Self {
+        self.fallback = Some(handler);
+        self
+    }
+
+    pub fn unmatched(mut self, disposition: Disposition) -> Self {
+        self.unmatched = disposition;
+        self
+    }
+
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+}
+
+#[async_trait]
+impl ConsumerHandler for Router {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        if let Some(route) = self.routes.iter().find(|route| route.matches(msg)) {
+            debug!(
+                route = route.name,
+                msg_type = msg.msg_type,
+                "message routed"
+            );
+            return route.handler.exec(ctx, msg).await;
+        }
+
+        if let Some(fallback) = &self.fallback {
+            return fallback.exec(ctx, msg).await;
+        }
+
+        warn!(
+            from = msg.from,
+            msg_type = msg.msg_type,
+            disposition = format!("{:?}", self.unmatched),
+            "there is no route matching the message"
+        );
+
+        Ok(self.unmatched.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Answer(Disposition);
+
+    #[async_trait]
+    impl ConsumerHandler for Answer {
+        async fn exec(
+            &self,
+            _ctx: &Context,
+            _msg: &ConsumerMessage,
+        ) -> Result<Disposition, MessagingError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn message(headers: &[(&str, &str)]) -> ConsumerMessage {
+        let headers = headers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        ConsumerMessage::new("queue", "order.created", b"{}", Some(headers))
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route(
+                Route::new("acme", Arc::new(Answer(Disposition::Reject)))
+                    .header_eq("tenant", "acme"),
+            )
+            .route(
+                Route::new("v2", Arc::new(Answer(Disposition::retry())))
+                    .version_gte(2)
+                    .priority(10),
+            )
+            .unmatched(Disposition::dead_letter("unmatched"))
+    }
+
+    #[tokio::test]
+    async fn should_route_to_the_highest_priority_matching_route() {
+        let ctx = Context::new();
+
+        let res = router()
+            .exec(
+                &ctx,
+                &message(&[("tenant", "acme"), (VERSION_HEADER_KEY, "2")]),
+            )
+            .await;
+        assert_eq!(res.unwrap(), Disposition::retry());
+
+        let res = router().exec(&ctx, &message(&[("tenant", "acme")])).await;
+        assert_eq!(res.unwrap(), Disposition::Reject);
+    }
+
+    #[tokio::test]
+    async fn should_apply_the_unmatched_policy_when_there_is_no_fallback() {
+        let ctx = Context::new();
+        let msg = message(&[("tenant", "other")]);
+
+        let res = router().exec(&ctx, &msg).await;
+        assert_eq!(res.unwrap(), Disposition::dead_letter("unmatched"));
+
+        let res = router()
+            .fallback(Arc::new(Answer(Disposition::Ack)))
+            .exec(&ctx, &msg)
+            .await;
+        assert_eq!(res.unwrap(), Disposition::Ack);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Time given to the in-flight handlers to finish after the shutdown was requested.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns a token cancelled when the process receives SIGTERM or SIGINT.
+pub fn signal() -> CancellationToken {
+    let token = CancellationToken::new();
+
+    tokio::spawn({
+        let token = token.clone();
+
+        async move {
+            wait_signal().await;
+            warn!("shutdown signal received");
+            token.cancel();
+        }
+    });
+
+    token
+}
+
+#[cfg(unix)]
+async fn wait_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Err(err) => {
+            error!(error = err.to_string(), "failure to listen SIGTERM");
+            return wait_ctrl_c().await;
+        }
+        Ok(s) => s,
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = wait_ctrl_c() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() {
+    wait_ctrl_c().await
+}
+
+async fn wait_ctrl_c() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!(error = err.to_string(), "failure to listen SIGINT");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Time limit of the drains of a consumer, starting when the shutdown is first seen, so the
+/// successive drains share the same shutdown timeout.
+#[derive(Clone)]
+pub struct Deadline {
+    shutdown: CancellationToken,
+    timeout: Duration,
+    at: Arc<OnceLock<Instant>>,
+}
+
+impl Deadline {
+    pub fn new(shutdown: &CancellationToken, timeout: Duration) -> Deadline {
+        Deadline {
+            shutdown: shutdown.clone(),
+            timeout,
+            at: Arc::default(),
+        }
+    }
+
+    /// Completes when the timeout elapsed after the shutdown was requested.
+    pub async fn elapsed(&self) {
+        self.shutdown.cancelled().await;
+
+        let at = *self.at.get_or_init(|| Instant::now() + self.timeout);
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+/// Awaits the in-flight future, giving up when the deadline elapses after the shutdown was requested.
+pub async fn drain<F>(deadline: &Deadline, fut: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    tokio::select! {
+        output = fut => Some(output),
+        _ = deadline.elapsed() => {
+            warn!("shutdown timeout elapsed, abandoning the in-flight message");
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_finish_in_flight_future_before_the_timeout() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        let deadline = Deadline::new(&shutdown, Duration::from_millis(100));
+
+        let res = drain(&deadline, async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            1
+        })
+        .await;
+
+        assert_eq!(res, Some(1));
+    }
+
+    #[tokio::test]
+    async fn should_abandon_in_flight_future_after_the_timeout() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        let deadline = Deadline::new(&shutdown, Duration::from_millis(10));
+
+        let res = drain(&deadline, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            1
+        })
+        .await;
+
+        assert_eq!(res, None);
+    }
+
+    #[tokio::test]
+    async fn should_share_the_deadline_between_the_drains() {
+        let shutdown = CancellationToken::new();
+        let deadline = Deadline::new(&shutdown, Duration::from_millis(100));
+        shutdown.cancel();
+
+        let started = Instant::now();
+        let first = drain(&deadline, tokio::time::sleep(Duration::from_millis(60))).await;
+        let second = drain(&deadline, tokio::time::sleep(Duration::from_millis(60))).await;
+
+        assert_eq!(first, Some(()));
+        assert_eq!(second, None);
+        assert!(started.elapsed() < Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,554 @@
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    middlewares::ConsumerMiddleware,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use hmac::{Hmac, Mac};
+use opentelemetry::Context;
+use secrets_manager::SecretClient;
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, warn};
+
+/// Header carrying the base64 signature of the payload and the signed headers.
+pub const SIGNATURE_HEADER_KEY: &str = "signature";
+
+/// Header carrying the id of the key used to sign the message, resolved by the SecretClient.
+pub const SIGNATURE_KEY_ID_HEADER_KEY: &str = "signature-key-id";
+
+/// Header carrying the algorithm of the signature, informative only, the verifiers use the
+/// algorithm configured for the key id.
+pub const SIGNATURE_ALGORITHM_HEADER_KEY: &str = "signature-algorithm";
+
+pub const HMAC_SHA256_ALGORITHM: &str = "hmac-sha256";
+pub const ED25519_ALGORITHM: &str = "ed25519";
+
+/// The key material is stored base64 encoded in the secrets, for Ed25519 the publishers
+/// use the 32 bytes secret key and the consumers the 32 bytes public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    HmacSha256,
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::HmacSha256 => HMAC_SHA256_ALGORITHM,
+            SignatureAlgorithm::Ed25519 => ED25519_ALGORITHM,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SignatureAlgorithm> {
+        match name {
+            HMAC_SHA256_ALGORITHM => Some(SignatureAlgorithm::HmacSha256),
+            ED25519_ALGORITHM => Some(SignatureAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+
+    fn sign(&self, key: &[u8], content: &[u8]) -> Result<Vec<u8>, MessagingError> {
+        match self {
+            SignatureAlgorithm::HmacSha256 => {
+                let mut mac = hmac(key)?;
+                mac.update(content);
+                Ok(mac.finalize().into_bytes().to_vec())
+            }
+            SignatureAlgorithm::Ed25519 => {
+                let key = SigningKey::from_bytes(&key32(key)?);
+                Ok(key.sign(content).to_vec())
+            }
+        }
+    }
+
+    fn verify(&self, key: &[u8], content: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SignatureAlgorithm::HmacSha256 => hmac(key).is_ok_and(|mut mac| {
+                mac.update(content);
+                mac.verify_slice(signature).is_ok()
+            }),
+            SignatureAlgorithm::Ed25519 => {
+                let Ok(key) = key32(key).and_then(|key| match VerifyingKey::from_bytes(&key) {
+                    Err(err) => {
+                        error!(error = err.to_string(), "invalid ed25519 public key");
+                        Err(MessagingError::InvalidSignatureError)
+                    }
+                    Ok(key) => Ok(key),
+                }) else {
+                    return false;
+                };
+
+                ed25519_dalek::Signature::from_slice(signature)
+                    .is_ok_and(|signature| key.verify(content, &signature).is_ok())
+            }
+        }
+    }
+}
+
+fn hmac(key: &[u8]) -> Result<Hmac<Sha256>, MessagingError> {
+    match Hmac::<Sha256>::new_from_slice(key) {
+        Err(err) => {
+            error!(error = err.to_string(), "invalid hmac key");
+            Err(MessagingError::InvalidSignatureError)
+        }
+        Ok(mac) => Ok(mac),
+    }
+}
+
+fn key32(key: &[u8]) -> Result<[u8; 32], MessagingError> {
+    match key.try_into() {
+        Err(_) => {
+            error!(size = key.len(), "the ed25519 keys must have 32 bytes");
+            Err(MessagingError::InvalidSignatureError)
+        }
+        Ok(key) => Ok(key),
+    }
+}
+
+/// Resolves the key material of the key id, the secret keys follow the `!key` convention
+/// used by the configs.
+fn resolve(secrets: &dyn SecretClient, key_id: &str) -> Result<Vec<u8>, MessagingError> {
+    let secret = match secrets.get_by_key(&format!("!{}", key_id)) {
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                key_id = key_id,
+                "failure to resolve the signing key"
+            );
+            Err(MessagingError::InvalidSignatureError)
+        }
+        Ok(secret) => Ok(secret),
+    }?;
+
+    match STANDARD.decode(secret) {
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                key_id = key_id,
+                "the signing key is not base64 encoded"
+            );
+            Err(MessagingError::InvalidSignatureError)
+        }
+        Ok(key) => Ok(key),
+    }
+}
+
+/// The signed content is the destination and the msg_type, then each signed header in the
+/// configured order, followed by the payload, so a signed payload can not be replayed to
+/// other destinations or as another type.
+fn content<F>(
+    destination: &str,
+    msg_type: &str,
+    headers: &[String],
+    value: F,
+    data: &[u8],
+) -> Vec<u8>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut content = Vec::with_capacity(data.len());
+    for (key, value) in [("destination", destination), ("msg_type", msg_type)] {
+        content.extend_from_slice(key.as_bytes());
+        content.push(b'=');
+        content.extend_from_slice(value.as_bytes());
+        content.push(b'\n');
+    }
+    for key in headers {
+        content.extend_from_slice(key.as_bytes());
+        content.push(b'=');
+        content.extend_from_slice(value(key).unwrap_or_default().as_bytes());
+        content.push(b'\n');
+    }
+    content.extend_from_slice(data);
+
+    content
+}
+
+/// Signs the payload and the selected headers with the key id, rotating the keys is
+/// made by changing the key id, the consumers resolve the key of each message.
+#[derive(Clone)]
+pub struct Signer {
+    algorithm: SignatureAlgorithm,
+    key_id: String,
+    headers: Vec<String>,
+    secrets: Arc<dyn SecretClient>,
+}
+
+impl Signer {
+    pub fn new<T>(algorithm: SignatureAlgorithm, key_id: T, secrets: Arc<dyn SecretClient>) -> Self
+    where
+        T: Into<String>,
+    {
+        Signer {
+            algorithm,
+            key_id: key_id.into(),
+            headers: vec![],
+            secrets,
+        }
+    }
+
+    /// Headers covered by the signature, the verifier must be configured with the same headers.
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    pub fn sign(&self, msg: &PublishMessage) -> Result<PublishMessage, MessagingError> {
+        let key = resolve(self.secrets.as_ref(), &self.key_id)?;
+
+        let value = |key: &str| {
+            msg.headers
+                .as_ref()
+                .and_then(|headers| headers.get(key))
+                .map(|value| value.clone().into())
+        };
+        let content = content(&msg.to, &msg.msg_type, &self.headers, value, &msg.data);
+        let signature = self.algorithm.sign(&key, &content)?;
+
+        let mut signed = msg.clone();
+        let headers = signed.headers.get_or_insert_with(HashMap::new);
+        headers.insert(
+            SIGNATURE_HEADER_KEY.to_owned(),
+            HeaderValue::LongString(STANDARD.encode(signature)),
+        );
+        headers.insert(
+            SIGNATURE_KEY_ID_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString(self.key_id.clone()),
+        );
+        headers.insert(
+            SIGNATURE_ALGORITHM_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString(self.algorithm.name().to_owned()),
+        );
+
+        Ok(signed)
+    }
+}
+
+/// Publisher signing every message with the Signer.
+pub struct SigningPublisher {
+    signer: Signer,
+    inner: Arc<dyn Publisher>,
+}
+
+impl SigningPublisher {
+    pub fn new(signer: Signer, publisher: Arc<dyn Publisher>) -> Arc<Self> {
+        Arc::new(SigningPublisher {
+            signer,
+            inner: publisher,
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for SigningPublisher {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        self.inner.publish(ctx, &self.signer.sign(msg)?).await
+    }
+}
+
+/// Verifies the message signatures, used as ConsumerMiddleware it rejects the unsigned
+/// and tampered messages before calling the handler.
+///
+/// The algorithm is never taken from the message, each key id is verified with the algorithm
+/// configured for it, the verifier algorithm by default. Otherwise a public Ed25519 key could
+/// be used as the secret of an HMAC signature.
+#[derive(Clone)]
+pub struct Verifier {
+    algorithm: SignatureAlgorithm,
+    keys: HashMap<String, SignatureAlgorithm>,
+    key_prefix: String,
+    destination: Option<String>,
+    headers: Vec<String>,
+    secrets: Arc<dyn SecretClient>,
+}
+
+impl Verifier {
+    pub fn new(algorithm: SignatureAlgorithm, secrets: Arc<dyn SecretClient>) -> Self {
+        Verifier {
+            algorithm,
+            keys: HashMap::new(),
+            key_prefix: String::new(),
+            destination: None,
+            headers: vec![],
+            secrets,
+        }
+    }
+
+    /// Verifies the key id with the given algorithm instead of the verifier one.
+    pub fn key<T>(mut self, key_id: T, algorithm: SignatureAlgorithm) -> Self
+    where
+        T: Into<String>,
+    {
+        self.keys.insert(key_id.into(), algorithm);
+        self
+    }
+
+    /// Only the key ids starting with the prefix are resolved, avoiding the messages
+    /// to reference unrelated secrets.
+    pub fn key_prefix<T>(mut self, prefix: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.key_prefix = prefix.into();
+        self
+    }
+
+    /// Destination the messages were published to, defaults to where they are consumed
+    /// from. Must be set when they differ, e.g. the RabbitMQ exchange of the consumed queue.
+    pub fn destination<T>(mut self, destination: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.destination = Some(destination.into());
+        self
+    }
+
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    pub fn verify(&self, msg: &ConsumerMessage) -> Result<(), MessagingError> {
+        let header = |key: &str| msg.headers.as_ref().and_then(|headers| headers.get(key));
+
+        let (Some(signature), Some(key_id)) = (
+            header(SIGNATURE_HEADER_KEY),
+            header(SIGNATURE_KEY_ID_HEADER_KEY),
+        ) else {
+            warn!(from = msg.from, msg_type = msg.msg_type, "unsigned message");
+            return Err(MessagingError::InvalidSignatureError);
+        };
+
+        if !key_id.starts_with(&self.key_prefix) {
+            warn!(from = msg.from, key_id = key_id, "unexpected signing key");
+            return Err(MessagingError::InvalidSignatureError);
+        }
+
+        let algorithm = self.keys.get(key_id).unwrap_or(&self.algorithm);
+
+        let Ok(signature) = STANDARD.decode(signature) else {
+            warn!(from = msg.from, "the signature is not base64 encoded");
+            return Err(MessagingError::InvalidSignatureError);
+        };
+
+        let key = resolve(self.secrets.as_ref(), key_id)?;
+        let destination = self.destination.as_deref().unwrap_or(&msg.from);
+        let content = content(
+            destination,
+            &msg.msg_type,
+            &self.headers,
+            |key| header(key).cloned(),
+            &msg.data,
+        );
+
+        if !algorithm.verify(&key, &content, &signature) {
+            warn!(
+                from = msg.from,
+                msg_type = msg.msg_type,
+                key_id = key_id,
+                algorithm = algorithm.name(),
+                "invalid message signature"
+            );
+            return Err(MessagingError::InvalidSignatureError);
+        }
+
+        Ok(())
+    }
+}
+
+impl ConsumerMiddleware for Verifier {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(VerifyingHandler {
+            verifier: self.clone(),
+            inner: handler,
+        })
+    }
+}
+
+struct VerifyingHandler {
+    verifier: Verifier,
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for VerifyingHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        if self.verifier.verify(msg).is_err() {
+            return Ok(Disposition::Reject);
+        }
+
+        self.inner.exec(ctx, msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrets_manager::errors::SecretsManagerError;
+
+    struct Secrets(HashMap<String, String>);
+
+    impl SecretClient for Secrets {
+        fn get_by_key(&self, key: &str) -> Result<String, SecretsManagerError> {
+            self.0
+                .get(key.strip_prefix('!').unwrap_or_default())
+                .cloned()
+                .ok_or(SecretsManagerError::SecretNotFound)
+        }
+    }
+
+    fn received(msg: &PublishMessage) -> ConsumerMessage {
+        let headers = msg
+            .headers
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect();
+
+        ConsumerMessage::new("devices", "reading", &msg.data, Some(headers))
+    }
+
+    fn message() -> PublishMessage {
+        let headers = HashMap::from([(
+            "device".to_owned(),
+            HeaderValue::ShortString("sensor-1".to_owned()),
+        )]);
+
+        PublishMessage::new(
+            "",
+            "devices",
+            "",
+            "reading",
+            b"{\"value\":1}",
+            Some(headers),
+        )
+    }
+
+    #[test]
+    fn should_verify_the_signed_messages() {
+        let seed = [7u8; 32];
+        let public = SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+
+        let signer_secrets = Arc::new(Secrets(HashMap::from([
+            ("hmac-1".to_owned(), STANDARD.encode(b"secret")),
+            ("ed-1".to_owned(), STANDARD.encode(seed)),
+        ])));
+        let verifier_secrets = Arc::new(Secrets(HashMap::from([
+            ("hmac-1".to_owned(), STANDARD.encode(b"secret")),
+            ("ed-1".to_owned(), STANDARD.encode(public)),
+        ])));
+
+        let verifier = Verifier::new(SignatureAlgorithm::HmacSha256, verifier_secrets)
+            .key("ed-1", SignatureAlgorithm::Ed25519)
+            .headers(&["device"]);
+
+        for (algorithm, key_id) in [
+            (SignatureAlgorithm::HmacSha256, "hmac-1"),
+            (SignatureAlgorithm::Ed25519, "ed-1"),
+        ] {
+            let signer =
+                Signer::new(algorithm, key_id, signer_secrets.clone()).headers(&["device"]);
+
+            let msg = received(&signer.sign(&message()).unwrap());
+            assert!(verifier.verify(&msg).is_ok());
+        }
+    }
+
+    #[test]
+    fn should_reject_the_tampered_and_unsigned_messages() {
+        let secrets = Arc::new(Secrets(HashMap::from([(
+            "hmac-1".to_owned(),
+            STANDARD.encode(b"secret"),
+        )])));
+
+        let signer = Signer::new(SignatureAlgorithm::HmacSha256, "hmac-1", secrets.clone())
+            .headers(&["device"]);
+        let verifier = Verifier::new(SignatureAlgorithm::HmacSha256, secrets).headers(&["device"]);
+
+        let mut tampered = received(&signer.sign(&message()).unwrap());
+        tampered
+            .headers
+            .as_mut()
+            .unwrap()
+            .insert("device".to_owned(), "sensor-2".to_owned());
+
+        assert_eq!(
+            verifier.verify(&tampered),
+            Err(MessagingError::InvalidSignatureError)
+        );
+        assert_eq!(
+            verifier.verify(&received(&message())),
+            Err(MessagingError::InvalidSignatureError)
+        );
+    }
+
+    #[test]
+    fn should_reject_the_hmac_signed_with_the_ed25519_public_key() {
+        let seed = [7u8; 32];
+        let public = SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+
+        //the attacker knows the public key, stored as the key material of the key id
+        let secrets = Arc::new(Secrets(HashMap::from([(
+            "ed-1".to_owned(),
+            STANDARD.encode(public),
+        )])));
+        let forger = Signer::new(SignatureAlgorithm::HmacSha256, "ed-1", secrets.clone());
+        let forged = received(&forger.sign(&message()).unwrap());
+
+        let verifier = Verifier::new(SignatureAlgorithm::Ed25519, secrets.clone());
+        assert_eq!(
+            verifier.verify(&forged),
+            Err(MessagingError::InvalidSignatureError)
+        );
+
+        let verifier = Verifier::new(SignatureAlgorithm::HmacSha256, secrets)
+            .key("ed-1", SignatureAlgorithm::Ed25519);
+        assert_eq!(
+            verifier.verify(&forged),
+            Err(MessagingError::InvalidSignatureError)
+        );
+    }
+
+    #[test]
+    fn should_reject_the_messages_replayed_as_another_type_or_destination() {
+        let secrets = Arc::new(Secrets(HashMap::from([(
+            "hmac-1".to_owned(),
+            STANDARD.encode(b"secret"),
+        )])));
+
+        let signer = Signer::new(SignatureAlgorithm::HmacSha256, "hmac-1", secrets.clone());
+        let verifier = Verifier::new(SignatureAlgorithm::HmacSha256, secrets);
+
+        let mut replayed = received(&signer.sign(&message()).unwrap());
+        replayed.msg_type = "calibration".to_owned();
+        assert_eq!(
+            verifier.verify(&replayed),
+            Err(MessagingError::InvalidSignatureError)
+        );
+
+        let mut replayed = received(&signer.sign(&message()).unwrap());
+        replayed.from = "actuators".to_owned();
+        assert_eq!(
+            verifier.verify(&replayed),
+            Err(MessagingError::InvalidSignatureError)
+        );
+
+        //consumed from a queue bound to the devices exchange
+        let mut queued = received(&signer.sign(&message()).unwrap());
+        queued.from = "devices-queue".to_owned();
+        assert!(verifier
+            .clone()
+            .destination("devices")
+            .verify(&queued)
+            .is_ok());
+    }
+}
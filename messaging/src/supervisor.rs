@@ -0,0 +1,355 @@
+use crate::{
+    dispatcher::Dispatcher, errors::MessagingError, resilience::RetryPolicy,
+    shutdown::CancellationToken,
+};
+use futures_util::future::{join_all, BoxFuture};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Dispatchers consuming longer than this are considered recovered, resetting their restarts.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+type Consume =
+    Arc<dyn Fn(CancellationToken) -> BoxFuture<'static, Result<(), MessagingError>> + Send + Sync>;
+
+type Consuming = Arc<dyn Fn() -> bool + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatcherStatus {
+    Starting,
+    Running,
+    Restarting,
+    Stopped,
+    Failed,
+}
+
+struct Member {
+    name: String,
+    consume: Consume,
+    consuming: Consuming,
+}
+
+/// Runs many dispatchers together, possibly of different brokers, restarting the ones
+/// failing with backoff.
+///
+/// When a dispatcher fails more times in a row than the restart policy allows, or when
+/// the shutdown token is cancelled, all the dispatchers are shut down together.
+pub struct DispatcherGroup {
+    members: Vec<Member>,
+    restart: RetryPolicy,
+    statuses: Arc<Mutex<BTreeMap<String, DispatcherStatus>>>,
+}
+
+impl Default for DispatcherGroup {
+    fn default() -> Self {
+        DispatcherGroup {
+            members: vec![],
+            restart: RetryPolicy::new()
+                .max_attempts(u32::MAX)
+                .initial_backoff(Duration::from_secs(1))
+                .max_backoff(Duration::from_secs(60)),
+            statuses: Arc::default(),
+        }
+    }
+}
+
+impl DispatcherGroup {
+    pub fn new() -> DispatcherGroup {
+        DispatcherGroup::default()
+    }
+
+    pub fn dispatcher<T, D>(mut self, name: T, dispatcher: D) -> Self
+    where
+        T: Into<String>,
+        D: Dispatcher + 'static,
+    {
+        let dispatcher = Arc::new(dispatcher);
+        let name = name.into();
+
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(name.clone(), DispatcherStatus::Starting);
+        self.members.push(Member {
+            name,
+            consume: Arc::new({
+                let dispatcher = dispatcher.clone();
+                move |shutdown| {
+                    let dispatcher = dispatcher.clone();
+                    Box::pin(async move { dispatcher.consume_until(shutdown).await })
+                }
+            }),
+            consuming: Arc::new(move || dispatcher.is_consuming()),
+        });
+        self
+    }
+
+    /// Backoff between the restarts, and the failures in a row giving up the group.
+    pub fn restart(mut self, policy: RetryPolicy) -> Self {
+        self.restart = policy;
+        self
+    }
+
+    pub fn build(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// The started dispatchers are reported starting until they are consuming.
+    pub fn statuses(&self) -> BTreeMap<String, DispatcherStatus> {
+        let mut statuses = self.statuses.lock().unwrap().clone();
+
+        for member in &self.members {
+            let status = statuses.get_mut(&member.name);
+            if let Some(status @ DispatcherStatus::Running) = status {
+                if !(member.consuming)() {
+                    *status = DispatcherStatus::Starting;
+                }
+            }
+        }
+
+        statuses
+    }
+
+    /// Whether all the dispatchers are consuming.
+    pub fn is_running(&self) -> bool {
+        self.statuses()
+            .values()
+            .all(|status| *status == DispatcherStatus::Running)
+    }
+
+    /// Starts all the dispatchers and waits until they are shut down.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+        let group = shutdown.child_token();
+
+        let results = join_all(
+            self.members
+                .iter()
+                .map(|member| self.supervise(member, group.clone())),
+        )
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    async fn supervise(
+        &self,
+        member: &Member,
+        group: CancellationToken,
+    ) -> Result<(), MessagingError> {
+        let mut failures = 0;
+
+        loop {
+            self.set_status(&member.name, DispatcherStatus::Running);
+            info!(name = member.name, "dispatcher started");
+
+            let started = Instant::now();
+            let result = (member.consume)(group.clone()).await;
+
+            if group.is_cancelled() {
+                self.set_status(&member.name, DispatcherStatus::Stopped);
+                info!(name = member.name, "dispatcher stopped");
+                return Ok(());
+            }
+
+            if started.elapsed() >= STABLE_AFTER {
+                failures = 0;
+            }
+            failures += 1;
+
+            let reason = match result {
+                Err(err) => err.to_string(),
+                Ok(()) => "dispatcher stopped consuming".to_owned(),
+            };
+
+            if failures >= self.restart.attempts() {
+                error!(
+                    error = reason,
+                    name = member.name,
+                    failures = failures,
+                    "dispatcher failed too many times, shutting down the group"
+                );
+                self.set_status(&member.name, DispatcherStatus::Failed);
+                group.cancel();
+                return Err(MessagingError::ConsumerError(member.name.clone()));
+            }
+
+            let backoff = self.restart.backoff(failures);
+            warn!(
+                error = reason,
+                name = member.name,
+                backoff_ms = backoff.as_millis() as u64,
+                "dispatcher failed, restarting"
+            );
+            self.set_status(&member.name, DispatcherStatus::Restarting);
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = group.cancelled() => {
+                    self.set_status(&member.name, DispatcherStatus::Stopped);
+                    return Ok(());
+                },
+            }
+        }
+    }
+
+    fn set_status(&self, name: &str, status: DispatcherStatus) {
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::BatchConsumerHandler, dispatcher::DispatcherDefinition, handler::ConsumerHandler,
+    };
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    struct FlakyDispatcher {
+        failures: AtomicU32,
+        calls: Arc<AtomicU32>,
+        consuming: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Dispatcher for FlakyDispatcher {
+        fn register(self, _: &DispatcherDefinition, _: Arc<dyn ConsumerHandler>) -> Self {
+            self
+        }
+
+        fn register_batch(
+            self,
+            _: &DispatcherDefinition,
+            _: Arc<dyn BatchConsumerHandler>,
+        ) -> Self {
+            self
+        }
+
+        async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(MessagingError::ConnectionError);
+            }
+
+            shutdown.cancelled().await;
+            Ok(())
+        }
+
+        fn is_consuming(&self) -> bool {
+            self.consuming.load(Ordering::SeqCst)
+        }
+    }
+
+    fn dispatcher(failures: u32) -> (FlakyDispatcher, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let dispatcher = FlakyDispatcher {
+            failures: AtomicU32::new(failures),
+            calls: calls.clone(),
+            consuming: Arc::new(AtomicBool::new(true)),
+        };
+        (dispatcher, calls)
+    }
+
+    fn restart(attempts: u32) -> RetryPolicy {
+        RetryPolicy::new()
+            .max_attempts(attempts)
+            .initial_backoff(Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn should_restart_the_failed_dispatchers() {
+        let (rabbitmq, rabbitmq_calls) = dispatcher(2);
+        let (mqtt, mqtt_calls) = dispatcher(0);
+
+        let group = DispatcherGroup::new()
+            .dispatcher("rabbitmq", rabbitmq)
+            .dispatcher("mqtt", mqtt)
+            .restart(restart(5))
+            .build();
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let group = group.clone();
+            let shutdown = shutdown.clone();
+            async move { group.run(shutdown).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(group.is_running());
+        assert_eq!(rabbitmq_calls.load(Ordering::SeqCst), 3);
+        assert_eq!(mqtt_calls.load(Ordering::SeqCst), 1);
+
+        shutdown.cancel();
+        assert!(running.await.unwrap().is_ok());
+        assert!(group
+            .statuses()
+            .values()
+            .all(|status| *status == DispatcherStatus::Stopped));
+    }
+
+    #[tokio::test]
+    async fn should_shut_down_the_group_when_a_dispatcher_gives_up() {
+        let (rabbitmq, _) = dispatcher(u32::MAX);
+        let (mqtt, _) = dispatcher(0);
+
+        let group = DispatcherGroup::new()
+            .dispatcher("rabbitmq", rabbitmq)
+            .dispatcher("mqtt", mqtt)
+            .restart(restart(2));
+
+        let res = group.run(CancellationToken::new()).await;
+
+        assert_eq!(
+            res.unwrap_err(),
+            MessagingError::ConsumerError("rabbitmq".to_owned())
+        );
+        assert_eq!(group.statuses()["rabbitmq"], DispatcherStatus::Failed);
+        assert_eq!(group.statuses()["mqtt"], DispatcherStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn should_report_running_once_the_dispatcher_is_consuming() {
+        let (rabbitmq, _) = dispatcher(0);
+        let consuming = rabbitmq.consuming.clone();
+        consuming.store(false, Ordering::SeqCst);
+
+        let group = DispatcherGroup::new()
+            .dispatcher("rabbitmq", rabbitmq)
+            .build();
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let group = group.clone();
+            let shutdown = shutdown.clone();
+            async move { group.run(shutdown).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!group.is_running());
+        assert_eq!(group.statuses()["rabbitmq"], DispatcherStatus::Starting);
+
+        consuming.store(true, Ordering::SeqCst);
+        assert!(group.is_running());
+
+        shutdown.cancel();
+        assert!(running.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn should_not_overflow_the_restart_backoff() {
+        let group = DispatcherGroup::new();
+
+        assert_eq!(group.restart.backoff(u32::MAX), Duration::from_secs(60));
+    }
+}
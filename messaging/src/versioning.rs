@@ -0,0 +1,291 @@
+use crate::{
+    errors::MessagingError,
+    handler::{ConsumerHandler, ConsumerMessage, Disposition},
+    middlewares::ConsumerMiddleware,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use async_trait::async_trait;
+use opentelemetry::Context;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, error};
+
+/// Header carrying the payload version of the message, messages with no version are the version 1.
+pub const VERSION_HEADER_KEY: &str = "msg-version";
+
+/// Transforms the payload of a version into the payload of the next version.
+pub type Upcaster = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, MessagingError> + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct MessageVersions {
+    current: u32,
+    upcasters: HashMap<u32, Upcaster>,
+}
+
+/// Registry of the payload versions of each msg_type.
+///
+/// Used as ConsumerMiddleware it upgrades the older payloads to the current version before
+/// calling the handler, and used by the VersionedPublisher it stamps the current version
+/// in the published messages, so consumers and producers can be upgraded independently.
+#[derive(Clone, Default)]
+pub struct VersionRegistry {
+    types: HashMap<String, MessageVersions>,
+}
+
+impl VersionRegistry {
+    pub fn new() -> VersionRegistry {
+        VersionRegistry::default()
+    }
+
+    /// Declares the current version of the msg_type.
+    pub fn version<T>(mut self, msg_type: T, current: u32) -> Self
+    where
+        T: Into<String>,
+    {
+        self.types.entry(msg_type.into()).or_default().current = current;
+        self
+    }
+
+    /// Registers the upcaster transforming the payload from the given version into the next one.
+    pub fn upcaster<T, F>(mut self, msg_type: T, from: u32, upcaster: F) -> Self
+    where
+        T: Into<String>,
+        F: Fn(&[u8]) -> Result<Vec<u8>, MessagingError> + Send + Sync + 'static,
+    {
+        let versions = self.types.entry(msg_type.into()).or_default();
+        versions.current = versions.current.max(from + 1);
+        versions.upcasters.insert(from, Arc::new(upcaster));
+        self
+    }
+
+    /// Same as upcaster for json payloads.
+    pub fn json_upcaster<T, F>(self, msg_type: T, from: u32, upcaster: F) -> Self
+    where
+        T: Into<String>,
+        F: Fn(Value) -> Result<Value, MessagingError> + Send + Sync + 'static,
+    {
+        self.upcaster(msg_type, from, move |data| {
+            let payload = match serde_json::from_slice(data) {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        "failure to deserialize json payload"
+                    );
+                    Err(MessagingError::DeserializingError)
+                }
+                Ok(payload) => Ok(payload),
+            }?;
+
+            match serde_json::to_vec(&upcaster(payload)?) {
+                Err(err) => {
+                    error!(error = err.to_string(), "failure to serialize json payload");
+                    Err(MessagingError::SerializingError)
+                }
+                Ok(data) => Ok(data),
+            }
+        })
+    }
+
+    pub fn current(&self, msg_type: &str) -> Option<u32> {
+        self.types.get(msg_type).map(|versions| versions.current)
+    }
+
+    /// Upgrades the message payload to the current version of its msg_type, the messages
+    /// of unregistered msg_types are returned as they are.
+    pub fn upcast(&self, msg: &ConsumerMessage) -> Result<ConsumerMessage, MessagingError> {
+        let Some(versions) = self.types.get(&msg.msg_type) else {
+            return Ok(msg.clone());
+        };
+
+        let version = match msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(VERSION_HEADER_KEY))
+        {
+            Some(version) => match version.parse::<u32>() {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        msg_type = msg.msg_type,
+                        "invalid message version"
+                    );
+                    Err(MessagingError::UnsupportedVersionError)
+                }
+                Ok(version) => Ok(version),
+            }?,
+            _ => 1,
+        };
+
+        if version > versions.current {
+            error!(
+                msg_type = msg.msg_type,
+                version = version,
+                current = versions.current,
+                "message version is newer than the current version"
+            );
+            return Err(MessagingError::UnsupportedVersionError);
+        }
+
+        let mut upcasted = msg.clone();
+        for from in version..versions.current {
+            let Some(upcaster) = versions.upcasters.get(&from) else {
+                error!(
+                    msg_type = msg.msg_type,
+                    from = from,
+                    "there is no upcaster registered for this version"
+                );
+                return Err(MessagingError::UnsupportedVersionError);
+            };
+
+            upcasted.data = upcaster(&upcasted.data)?.into();
+        }
+
+        if version < versions.current {
+            debug!(
+                msg_type = msg.msg_type,
+                from = version,
+                to = versions.current,
+                "message upcasted"
+            );
+        }
+
+        upcasted
+            .headers
+            .get_or_insert_with(HashMap::new)
+            .insert(VERSION_HEADER_KEY.to_owned(), versions.current.to_string());
+
+        Ok(upcasted)
+    }
+
+    /// Stamps the current version of the msg_type, keeping the version already set.
+    pub fn stamp(&self, msg: &PublishMessage) -> PublishMessage {
+        let mut stamped = msg.clone();
+
+        if let Some(current) = self.current(&msg.msg_type) {
+            stamped
+                .headers
+                .get_or_insert_with(HashMap::new)
+                .entry(VERSION_HEADER_KEY.to_owned())
+                .or_insert_with(|| HeaderValue::ShortString(current.to_string()));
+        }
+
+        stamped
+    }
+}
+
+impl ConsumerMiddleware for VersionRegistry {
+    fn wrap(&self, handler: Arc<dyn ConsumerHandler>) -> Arc<dyn ConsumerHandler> {
+        Arc::new(UpcastingHandler {
+            registry: self.clone(),
+            inner: handler,
+        })
+    }
+}
+
+struct UpcastingHandler {
+    registry: VersionRegistry,
+    inner: Arc<dyn ConsumerHandler>,
+}
+
+#[async_trait]
+impl ConsumerHandler for UpcastingHandler {
+    async fn exec(
+        &self,
+        ctx: &Context,
+        msg: &ConsumerMessage,
+    ) -> Result<Disposition, MessagingError> {
+        let msg = match self.registry.upcast(msg) {
+            //retrying would fail again, the message is kept in the dlq until the consumer is upgraded
+            Err(MessagingError::UnsupportedVersionError) => {
+                return Ok(Disposition::dead_letter("unsupported message version"));
+            }
+            Err(err) => return Err(err),
+            Ok(msg) => msg,
+        };
+
+        self.inner.exec(ctx, &msg).await
+    }
+}
+
+/// Publisher stamping the current version registered for the msg_type.
+pub struct VersionedPublisher {
+    registry: VersionRegistry,
+    inner: Arc<dyn Publisher>,
+}
+
+impl VersionedPublisher {
+    pub fn new(registry: VersionRegistry, publisher: Arc<dyn Publisher>) -> Arc<Self> {
+        Arc::new(VersionedPublisher {
+            registry,
+            inner: publisher,
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for VersionedPublisher {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        self.inner.publish(ctx, &self.registry.stamp(msg)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry() -> VersionRegistry {
+        VersionRegistry::new()
+            .json_upcaster("user.created", 1, |mut payload| {
+                payload["name"] = payload["first_name"].take();
+                Ok(payload)
+            })
+            .json_upcaster("user.created", 2, |mut payload| {
+                payload["active"] = json!(true);
+                Ok(payload)
+            })
+    }
+
+    fn message(version: Option<&str>) -> ConsumerMessage {
+        let headers = version
+            .map(|version| HashMap::from([(VERSION_HEADER_KEY.to_owned(), version.to_owned())]));
+
+        ConsumerMessage::new(
+            "queue",
+            "user.created",
+            br#"{"first_name":"john"}"#,
+            headers,
+        )
+    }
+
+    #[test]
+    fn should_upcast_the_payload_to_the_current_version() {
+        let msg = registry().upcast(&message(None)).unwrap();
+
+        let payload: Value = serde_json::from_slice(&msg.data).unwrap();
+        assert_eq!(
+            payload,
+            json!({"first_name": null, "name": "john", "active": true})
+        );
+        assert_eq!(msg.headers.unwrap()[VERSION_HEADER_KEY], "3");
+    }
+
+    #[test]
+    fn should_reject_versions_newer_than_the_current_version() {
+        let res = registry().upcast(&message(Some("4")));
+
+        assert_eq!(res.unwrap_err(), MessagingError::UnsupportedVersionError);
+    }
+
+    #[test]
+    fn should_stamp_the_current_version() {
+        let msg = PublishMessage::new("", "exchange", "", "user.created", b"{}", None);
+
+        let stamped = registry().stamp(&msg);
+
+        assert_eq!(
+            stamped.headers.unwrap()[VERSION_HEADER_KEY],
+            HeaderValue::ShortString("3".to_owned())
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use futures_util::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+};
+
+struct Slot {
+    pool: String,
+    key: Option<String>,
+}
+
+/// Bounded set of in-flight handler executions polled by the dispatchers consume loop.
+///
+/// Each pool, usually one per DispatcherDefinition, runs up to its concurrency executions at
+/// the same time. When a key is given the executions sharing the same key run sequentially.
+/// Dispatchers must call `reserve` before receiving the next delivery of a pool, applying
+/// backpressure to the broker while the pool is full.
+#[derive(Default)]
+pub struct Workers<'a> {
+    running: FuturesUnordered<BoxFuture<'a, Slot>>,
+    in_flight: HashMap<String, usize>,
+    keys: HashSet<(String, String)>,
+}
+
+impl<'a> Workers<'a> {
+    pub fn new() -> Workers<'a> {
+        Workers::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Waits until the pool has a free slot and no execution with the same key is running.
+    pub async fn reserve(&mut self, pool: &str, concurrency: usize, key: Option<&str>) {
+        while !self.available(pool, concurrency, key) {
+            if self.next().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    pub fn spawn<F>(&mut self, pool: &str, key: Option<&str>, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'a,
+    {
+        *self.in_flight.entry(pool.to_owned()).or_default() += 1;
+
+        let slot = Slot {
+            pool: pool.to_owned(),
+            key: key.map(|key| key.to_owned()),
+        };
+
+        if let Some(key) = &slot.key {
+            self.keys.insert((slot.pool.clone(), key.clone()));
+        }
+
+        self.running.push(fut.map(move |_| slot).boxed());
+    }
+
+    /// Waits the next execution to finish, returning None when there is nothing running.
+    pub async fn next(&mut self) -> Option<()> {
+        let slot = self.running.next().await?;
+
+        if let Some(count) = self.in_flight.get_mut(&slot.pool) {
+            *count -= 1;
+        }
+
+        if let Some(key) = slot.key {
+            self.keys.remove(&(slot.pool, key));
+        }
+
+        Some(())
+    }
+
+    pub async fn wait_all(&mut self) {
+        while self.next().await.is_some() {}
+    }
+
+    fn available(&self, pool: &str, concurrency: usize, key: Option<&str>) -> bool {
+        let in_flight = self.in_flight.get(pool).copied().unwrap_or_default();
+        if in_flight >= concurrency.max(1) {
+            return false;
+        }
+
+        match key {
+            Some(key) => !self.keys.contains(&(pool.to_owned(), key.to_owned())),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn should_limit_the_in_flight_executions() {
+        let mut workers = Workers::new();
+
+        workers.spawn("pool", None, tokio::time::sleep(Duration::from_millis(10)));
+        workers.spawn("pool", None, tokio::time::sleep(Duration::from_millis(10)));
+        assert_eq!(workers.len(), 2);
+
+        workers.reserve("pool", 2, None).await;
+        assert_eq!(workers.len(), 1);
+
+        workers.reserve("other", 2, None).await;
+        assert_eq!(workers.len(), 1);
+
+        workers.wait_all().await;
+        assert!(workers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_run_executions_with_the_same_key_sequentially() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut workers = Workers::new();
+
+        for (key, value, delay) in [("a", 1, 20), ("a", 2, 0), ("b", 3, 10)] {
+            workers.reserve("pool", 10, Some(key)).await;
+
+            let order = order.clone();
+            workers.spawn("pool", Some(key), async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                order.lock().unwrap().push(value);
+            });
+        }
+        workers.wait_all().await;
+
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 3]);
+    }
+}
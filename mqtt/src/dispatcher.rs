@@ -1,18 +1,60 @@
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use messaging::{
+    batch::{self, Batch, BatchConsumerHandler, SingleMessageBatch},
+    cloudevents::{self, CE_HEADER_PREFIX},
+    compression::{self, CONTENT_ENCODING_HEADER_KEY},
     dispatcher::{Dispatcher, DispatcherDefinition},
     errors::MessagingError,
-    handler::{ConsumerHandler, ConsumerMessage},
+    handler::{
+        ConsumerHandler, ConsumerMessage, Disposition, MessageMetadata, MESSAGE_ID_HEADER_KEY,
+    },
+    headers::{self, HEADER_TYPES_KEY},
+    metrics::MessagingMetrics,
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
+    shutdown::{drain, CancellationToken, Deadline, DEFAULT_SHUTDOWN_TIMEOUT},
+    versioning::VERSION_HEADER_KEY,
+    workers::Workers,
 };
 use opentelemetry::{
     global::{self, BoxedTracer},
     trace::{SpanKind, Status, TraceContextExt},
     Context,
 };
-use paho_mqtt::{AsyncClient, AsyncReceiver, Message, TopicFilter};
-use std::{borrow::Cow, sync::Arc};
+use paho_mqtt::{
+    AsyncClient, AsyncReceiver, Message, MessageBuilder, Properties, PropertyCode, TopicFilter,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+const CE_SPEC_VERSION_PROPERTY: &str = "specversion";
+
+/// User property carrying how many times the message was republished to be retried.
+pub const RETRY_COUNT_HEADER_KEY: &str = "retry-count";
+
+/// Prefix of the topics where the dispatchers republish the messages to retry them, followed
+/// by the client id and the original topic.
+pub const RETRY_TOPIC_PREFIX: &str = "retry";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+//properties kept when republishing the message to retry it, with the user properties
+const RETRIED_PROPERTIES: [PropertyCode; 5] = [
+    PropertyCode::PayloadFormatIndicator,
+    PropertyCode::MessageExpiryInterval,
+    PropertyCode::ContentType,
+    PropertyCode::ResponseTopic,
+    PropertyCode::CorrelationData,
+];
 
 pub struct MQTTDispatcher {
     conn: Arc<AsyncClient>,
@@ -20,18 +62,66 @@ pub struct MQTTDispatcher {
     tracer: BoxedTracer,
     topics: Vec<String>,
     handlers: Vec<Arc<dyn ConsumerHandler>>,
+    batch_handlers: HashMap<usize, Arc<dyn BatchConsumerHandler>>,
+    definitions: Vec<DispatcherDefinition>,
+    shutdown_timeout: Duration,
+    unmatched: Disposition,
+    max_attempts: u32,
+    retry_prefix: String,
+    metrics: MessagingMetrics,
+    consuming: AtomicBool,
 }
 
 impl MQTTDispatcher {
     pub fn new(conn: Arc<AsyncClient>, stream: AsyncReceiver<Option<Message>>) -> Self {
+        //the retries are only received by this client, not by every subscriber of the topic
+        let client_id = match conn.client_id() {
+            client_id if client_id.is_empty() => Uuid::new_v4().to_string(),
+            client_id => client_id,
+        };
+
         Self {
             conn,
             stream,
             tracer: global::tracer("mqtt-consumer"),
             topics: vec![],
             handlers: vec![],
+            batch_handlers: HashMap::new(),
+            definitions: vec![],
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            unmatched: Disposition::Ack,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_prefix: format!("{}/{}", RETRY_TOPIC_PREFIX, client_id),
+            metrics: MessagingMetrics::new("mqtt"),
+            consuming: AtomicBool::new(false),
         }
     }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Disposition of the messages received in a topic with no handler registered, defaults
+    /// to ack, skipping them.
+    pub fn unmatched(mut self, disposition: Disposition) -> Self {
+        self.unmatched = disposition;
+        self
+    }
+
+    /// Deliveries of a retried message, including the first one, before it is dead lettered,
+    /// defaults to 5.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Topic prefix where the messages are republished to be retried, defaults to
+    /// retry/{client id}. It must only be subscribed by this dispatcher.
+    pub fn retry_prefix(mut self, prefix: &str) -> Self {
+        self.retry_prefix = prefix.to_owned();
+        self
+    }
 }
 
 #[async_trait]
@@ -47,32 +137,255 @@ impl Dispatcher for MQTTDispatcher {
         }
 
         self.topics.push(definition.name.clone());
+        self.definitions.push(definition.clone());
         self.handlers.push(handler);
 
         self
     }
 
-    async fn consume_blocking(&self) -> Result<(), MessagingError> {
-        for topic in self.topics.clone() {
-            self.conn.subscribe(topic, 2);
+    fn register_batch(
+        self,
+        definition: &DispatcherDefinition,
+        handler: Arc<dyn BatchConsumerHandler>,
+    ) -> Self {
+        let registered = self.handlers.len();
+        let mut dispatcher = self.register(definition, SingleMessageBatch::new(handler.clone()));
+
+        if dispatcher.handlers.len() > registered {
+            dispatcher.batch_handlers.insert(registered, handler);
         }
 
+        dispatcher
+    }
+
+    async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+        for topic in self.subscriptions() {
+            if let Err(err) = self.conn.subscribe(topic, 2).await {
+                error!(error = err.to_string(), "failure to subscribe the topic");
+                return Err(MessagingError::CreatingConsumerError);
+            }
+        }
+        self.consuming.store(true, Ordering::SeqCst);
+
         let mut cloned_stream = self.stream.clone();
+        let mut workers = Workers::new();
+        let deadline = Deadline::new(&shutdown, self.shutdown_timeout);
+        let mut batches: HashMap<usize, Batch<Message>> = self
+            .batch_handlers
+            .keys()
+            .map(|idx| {
+                let definition = &self.definitions[*idx];
+                (
+                    *idx,
+                    Batch::new(definition.batch_size, definition.batch_linger),
+                )
+            })
+            .collect();
+
+        'consume: loop {
+            let next_deadline = batches.values().filter_map(|batch| batch.deadline()).min();
+
+            let delivery = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                Some(_) = workers.next() => continue,
+                _ = batch::linger(next_deadline) => {
+                    for (idx, batch) in batches.iter_mut() {
+                        if !batch.is_expired() {
+                            continue;
+                        }
+
+                        if !self
+                            .flush(&mut workers, &shutdown, &deadline, *idx, batch.take())
+                            .await
+                        {
+                            break 'consume;
+                        }
+                    }
+                    continue;
+                },
+                delivery = cloned_stream.next() => delivery,
+            };
 
-        while let Some(delivery) = cloned_stream.next().await {
-            if let Some(msg) = delivery {
-                if let Err(err) = self.consume(&Context::new(), &msg).await {
-                    error!(error = err.to_string(), "failure to consume msg");
+            let Some(delivery) = delivery else {
+                break;
+            };
+
+            let Some(msg) = delivery else {
+                continue;
+            };
+
+            //the msg_type comes with the cloud events attributes, parsed with the message
+            self.metrics.received(msg.topic(), "");
+
+            let ctx = Context::new();
+            let Ok(handler_idx) = self.get_handler_index(&ctx, msg.topic()) else {
+                let consumer_msg = self.consumer_message(&msg);
+                let disposed = self
+                    .dispose(&ctx, &msg, &consumer_msg, self.unmatched.clone(), &shutdown)
+                    .await;
+                if let Err(err) = disposed {
+                    error!(
+                        error = err.to_string(),
+                        "failure to dispose the unmatched msg"
+                    );
+                }
+                continue;
+            };
+
+            if let Some(batch) = batches.get_mut(&handler_idx) {
+                batch.push(msg);
+                if !batch.is_full() {
+                    continue;
                 }
+
+                if !self
+                    .flush(
+                        &mut workers,
+                        &shutdown,
+                        &deadline,
+                        handler_idx,
+                        batch.take(),
+                    )
+                    .await
+                {
+                    break;
+                }
+                continue;
+            }
+
+            let definition = &self.definitions[handler_idx];
+            let key = definition
+                .ordered
+                .then(|| self.original_topic(msg.topic()).to_owned());
+
+            let reserved = drain(
+                &deadline,
+                workers.reserve(&definition.name, definition.concurrency, key.as_deref()),
+            )
+            .await;
+            if reserved.is_none() {
+                break;
             }
+
+            let shutdown = &shutdown;
+            workers.spawn(&definition.name, key.as_deref(), async move {
+                if let Err(err) = self.consume(&Context::new(), &msg, shutdown).await {
+                    error!(error = err.to_string(), "failure to consume msg");
+                }
+            });
+        }
+
+        self.consuming.store(false, Ordering::SeqCst);
+        drain(&deadline, workers.wait_all()).await;
+
+        //the connection is shared with the publishers, its owner disconnects it
+        if let Err(err) = self.conn.unsubscribe_many(&self.subscriptions()).await {
+            error!(error = err.to_string(), "failure to unsubscribe the topics");
         }
 
+        debug!("mqtt consumer stopped");
+
         Ok(())
     }
+
+    fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::SeqCst)
+    }
 }
 
 impl MQTTDispatcher {
-    async fn consume(&self, ctx: &Context, msg: &Message) -> Result<(), MessagingError> {
+    /// Hands the batch to the workers, returns false when the shutdown timeout elapsed
+    /// waiting for a free slot.
+    async fn flush<'a>(
+        &'a self,
+        workers: &mut Workers<'a>,
+        shutdown: &'a CancellationToken,
+        deadline: &Deadline,
+        handler_idx: usize,
+        msgs: Vec<Message>,
+    ) -> bool {
+        let definition = &self.definitions[handler_idx];
+
+        let reserved = drain(
+            deadline,
+            workers.reserve(&definition.name, definition.concurrency, None),
+        )
+        .await;
+        if reserved.is_none() {
+            return false;
+        }
+
+        workers.spawn(&definition.name, None, async move {
+            if let Err(err) = self.consume_batch(handler_idx, &msgs, shutdown).await {
+                error!(error = err.to_string(), "failure to consume batch");
+            }
+        });
+
+        true
+    }
+
+    async fn consume_batch(
+        &self,
+        handler_idx: usize,
+        msgs: &[Message],
+        shutdown: &CancellationToken,
+    ) -> Result<(), MessagingError> {
+        let handler = &self.batch_handlers[&handler_idx];
+        let definition = &self.definitions[handler_idx];
+
+        let ctx = traces::span_ctx(&self.tracer, SpanKind::Consumer, &definition.name);
+
+        debug!(
+            trace.id = traces::trace_id(&ctx),
+            span.id = traces::span_id(&ctx),
+            "batch of {} messages received in the topic {:?}",
+            msgs.len(),
+            definition.name
+        );
+
+        let consumer_msgs: Vec<ConsumerMessage> =
+            msgs.iter().map(|msg| self.consumer_message(msg)).collect();
+
+        let ctx = &ctx;
+        let results = compression::exec_batch(&consumer_msgs, |msgs| async move {
+            self.metrics.exec_batch(handler.as_ref(), ctx, &msgs).await
+        })
+        .await;
+
+        let mut failure = Ok(());
+        for ((msg, consumer_msg), result) in msgs.iter().zip(&consumer_msgs).zip(results) {
+            let disposed = match result {
+                Ok(disposition) => {
+                    self.dispose(ctx, msg, consumer_msg, disposition, shutdown)
+                        .await
+                }
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        trace.id = traces::trace_id(&ctx),
+                        span.id = traces::span_id(&ctx),
+                        topic = msg.topic(),
+                        "failed to handle the event"
+                    );
+                    Err(err)
+                }
+            };
+
+            if disposed.is_err() {
+                failure = disposed;
+            }
+        }
+
+        failure
+    }
+
+    async fn consume(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        shutdown: &CancellationToken,
+    ) -> Result<(), MessagingError> {
         let handler_idx = self.get_handler_index(ctx, msg.topic())?;
 
         let ctx = traces::span_ctx(&self.tracer, SpanKind::Consumer, msg.topic());
@@ -87,16 +400,26 @@ impl MQTTDispatcher {
 
         let handler = self.handlers.get(handler_idx).unwrap();
 
-        let msg = ConsumerMessage::new(msg.topic(), "", msg.payload(), None);
+        let consumer_msg = self.consumer_message(msg);
+        if let Some(disposition) = compression::undecompressed(&consumer_msg) {
+            return self
+                .dispose(&ctx, msg, &consumer_msg, disposition, shutdown)
+                .await;
+        }
 
-        match handler.exec(&ctx, &msg).await {
-            Ok(_) => {
+        match self
+            .metrics
+            .exec(handler.as_ref(), &ctx, &consumer_msg)
+            .await
+        {
+            Ok(disposition) => {
                 debug!(
                     trace.id = traces::trace_id(&ctx),
                     span.id = traces::span_id(&ctx),
                     "event processed successfully"
                 );
-                Ok(())
+                self.dispose(&ctx, msg, &consumer_msg, disposition, shutdown)
+                    .await
             }
             Err(e) => {
                 debug!(
@@ -114,11 +437,123 @@ impl MQTTDispatcher {
         }
     }
 
+    async fn dispose(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        consumer_msg: &ConsumerMessage,
+        disposition: Disposition,
+        shutdown: &CancellationToken,
+    ) -> Result<(), MessagingError> {
+        let attempts = consumer_msg.metadata.delivery_count;
+
+        //the retries past the max attempts are dead lettered, a poison message would loop forever
+        let disposition = match disposition {
+            Disposition::Retry { .. } if attempts >= self.max_attempts => {
+                warn!(
+                    trace.id = traces::trace_id(ctx),
+                    span.id = traces::span_id(ctx),
+                    topic = msg.topic(),
+                    attempts = attempts,
+                    "event retried too many times, dead lettering it"
+                );
+                Disposition::dead_letter("max attempts exceeded")
+            }
+            disposition => disposition,
+        };
+
+        match disposition {
+            Disposition::Ack => Ok(()),
+            Disposition::Reject => {
+                warn!(
+                    trace.id = traces::trace_id(ctx),
+                    span.id = traces::span_id(ctx),
+                    topic = msg.topic(),
+                    "event rejected by the handler"
+                );
+                Ok(())
+            }
+            Disposition::DeadLetter { reason } => {
+                error!(
+                    trace.id = traces::trace_id(ctx),
+                    span.id = traces::span_id(ctx),
+                    topic = msg.topic(),
+                    reason = reason,
+                    "event dead lettered by the handler, there is no dlq for mqtt"
+                );
+                self.metrics.dead_lettered(consumer_msg);
+                Ok(())
+            }
+            Disposition::Retry { after } => {
+                warn!(
+                    trace.id = traces::trace_id(ctx),
+                    span.id = traces::span_id(ctx),
+                    topic = msg.topic(),
+                    attempt = attempts,
+                    "republishing event to retry latter"
+                );
+                self.metrics.retried(consumer_msg);
+
+                //the retries are republished right away when shutting down
+                if let Some(after) = after {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => {},
+                        _ = tokio::time::sleep(after) => {},
+                    }
+                }
+
+                let topic = format!("{}/{}", self.retry_prefix, self.original_topic(msg.topic()));
+                let retry = MessageBuilder::new()
+                    .topic(topic)
+                    .payload(msg.payload())
+                    .qos(msg.qos())
+                    .properties(retried_properties(msg.properties(), attempts))
+                    .finalize();
+
+                match self.conn.publish(retry).await {
+                    Err(err) => {
+                        error!(error = err.to_string(), "failure to republish the event");
+                        Err(MessagingError::PublisherError)
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// The registered topics and the topics receiving their retries.
+    fn subscriptions(&self) -> Vec<String> {
+        self.topics
+            .iter()
+            .cloned()
+            .chain(
+                self.topics
+                    .iter()
+                    .map(|topic| format!("{}/{}", self.retry_prefix, topic)),
+            )
+            .collect()
+    }
+
+    /// The topic the message was first published to, without the retry prefix.
+    fn original_topic<'t>(&self, topic: &'t str) -> &'t str {
+        topic
+            .strip_prefix(self.retry_prefix.as_str())
+            .and_then(|topic| topic.strip_prefix('/'))
+            .unwrap_or(topic)
+    }
+
+    fn consumer_message(&self, msg: &Message) -> ConsumerMessage {
+        let mut consumer_msg = consumer_message(msg);
+        consumer_msg.from = self.original_topic(msg.topic()).to_owned();
+        consumer_msg
+    }
+
     fn get_handler_index(
         &self,
         ctx: &Context,
         received_topic: &str,
     ) -> Result<usize, MessagingError> {
+        let received_topic = self.original_topic(received_topic);
         let mut p = usize::MAX;
 
         for handler_topic_index in 0..self.topics.len() {
@@ -149,6 +584,7 @@ impl MQTTDispatcher {
                 trace.id = traces::trace_id(ctx),
                 span.id = traces::span_id(ctx),
                 topic = received_topic,
+                disposition = format!("{:?}", self.unmatched),
                 "cant find dispatch for this topic"
             );
             return Err(MessagingError::UnregisteredHandler);
@@ -158,10 +594,117 @@ impl MQTTDispatcher {
     }
 }
 
+pub(crate) fn consumer_message(msg: &Message) -> ConsumerMessage {
+    let props = msg.properties();
+    let mut headers = HashMap::new();
+
+    if let Some(reply_to) = props.get_string(PropertyCode::ResponseTopic) {
+        headers.insert(REPLY_TO_HEADER_KEY.to_owned(), reply_to);
+    }
+
+    if let Some(correlation) = props.get_binary(PropertyCode::CorrelationData) {
+        headers.insert(
+            CORRELATION_ID_HEADER_KEY.to_owned(),
+            String::from_utf8_lossy(&correlation).into_owned(),
+        );
+    }
+
+    //the cloud events binding sends the attributes as user properties with no prefix
+    let cloud_event = props.find_user_property(CE_SPEC_VERSION_PROPERTY).is_some();
+    let not_attributes = [
+        MESSAGE_ID_HEADER_KEY,
+        VERSION_HEADER_KEY,
+        CONTENT_ENCODING_HEADER_KEY,
+        HEADER_TYPES_KEY,
+        RETRY_COUNT_HEADER_KEY,
+    ];
+    for (key, value) in props.user_iter() {
+        if cloud_event && !not_attributes.contains(&key.as_str()) {
+            headers.insert(format!("{}{}", CE_HEADER_PREFIX, key), value.clone());
+        }
+        headers.insert(key, value);
+    }
+
+    let content_type = props.get_string(PropertyCode::ContentType);
+
+    let retries = props
+        .find_user_property(RETRY_COUNT_HEADER_KEY)
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or_default();
+
+    //the user properties are strings, the types come with the header-types property
+    let typed = headers::from_strings(&mut headers);
+
+    let metadata = MessageMetadata {
+        message_id: headers.get(MESSAGE_ID_HEADER_KEY).cloned(),
+        correlation_id: headers.get(CORRELATION_ID_HEADER_KEY).cloned(),
+        reply_to: headers.get(REPLY_TO_HEADER_KEY).cloned(),
+        content_type: content_type.clone(),
+        delivery_count: retries.saturating_add(1),
+        qos: Some(msg.qos()),
+        retain: msg.retained(),
+        //paho-mqtt does not expose the dup flag of the received messages
+        duplicate: false,
+        headers: typed,
+        ..Default::default()
+    };
+
+    let msg = ConsumerMessage::new(
+        msg.topic(),
+        "",
+        msg.payload(),
+        (!headers.is_empty()).then_some(headers),
+    )
+    .with_metadata(metadata);
+
+    cloudevents::parse(compression::decompress(msg), content_type.as_deref())
+}
+
+//the properties of the republished message, with the retries done so far
+fn retried_properties(props: &Properties, retries: u32) -> Properties {
+    let mut retried = Properties::new();
+
+    for code in RETRIED_PROPERTIES {
+        for prop in props.iter(code) {
+            if let Err(err) = retried.push(prop) {
+                error!(
+                    error = err.to_string(),
+                    "failure to copy the message property"
+                );
+            }
+        }
+    }
+
+    for (key, value) in props.user_iter() {
+        if key == RETRY_COUNT_HEADER_KEY {
+            continue;
+        }
+        if let Err(err) = retried.push_string_pair(PropertyCode::UserProperty, &key, &value) {
+            error!(
+                error = err.to_string(),
+                "failure to copy the message user property"
+            );
+        }
+    }
+
+    if let Err(err) = retried.push_string_pair(
+        PropertyCode::UserProperty,
+        RETRY_COUNT_HEADER_KEY,
+        &retries.to_string(),
+    ) {
+        error!(
+            error = err.to_string(),
+            "failure to set the retry count property"
+        );
+    }
+
+    retried
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use messaging::handler::MockConsumerHandler;
+    use messaging::{batch::MockBatchConsumerHandler, handler::MockConsumerHandler};
     use paho_mqtt::CreateOptions;
     use std::vec;
 
@@ -178,42 +721,55 @@ mod tests {
         let stream = client.get_stream(2048);
         let dispatch = MQTTDispatcher::new(Arc::new(client), stream)
             .register(
-                &DispatcherDefinition {
-                    name: "some/topic".to_owned(),
-                    msg_type: String::new(),
-                },
+                &DispatcherDefinition::new("some/topic", ""),
                 Arc::new(MockConsumerHandler::new()),
             )
             .register(
-                &DispatcherDefinition {
-                    name: String::new(),
-                    msg_type: String::new(),
-                },
+                &DispatcherDefinition::new("", ""),
                 Arc::new(MockConsumerHandler::new()),
             );
 
         assert!(dispatch.handlers.len() == 1);
     }
 
+    #[test]
+    fn test_declare_batch() {
+        let mut client = AsyncClient::new(CreateOptions::default()).unwrap();
+        let stream = client.get_stream(2048);
+        let dispatch = MQTTDispatcher::new(Arc::new(client), stream)
+            .register(
+                &DispatcherDefinition::new("some/topic", ""),
+                Arc::new(MockConsumerHandler::new()),
+            )
+            .register_batch(
+                &DispatcherDefinition::new("other/topic", "").batch(10, Duration::from_millis(100)),
+                Arc::new(MockBatchConsumerHandler::new()),
+            );
+
+        assert!(dispatch.handlers.len() == 2);
+        assert!(dispatch.batch_handlers.contains_key(&1));
+    }
+
     #[tokio::test]
     async fn test_consume() {
         let mut client = AsyncClient::new(CreateOptions::default()).unwrap();
         let stream = client.get_stream(2048);
 
         let mut handler = MockConsumerHandler::new();
-        handler.expect_exec().return_once(move |_, _| Ok(()));
+        handler
+            .expect_exec()
+            .return_once(move |_, _| Ok(Disposition::Ack));
 
         let dispatch = MQTTDispatcher::new(Arc::new(client), stream).register(
-            &DispatcherDefinition {
-                name: "some/topic/#".to_owned(),
-                msg_type: String::new(),
-            },
+            &DispatcherDefinition::new("some/topic/#", ""),
             Arc::new(handler),
         );
 
         let msg = Message::new("some/topic/sub/1", vec![], 0);
 
-        let res = dispatch.consume(&Context::new(), &msg).await;
+        let res = dispatch
+            .consume(&Context::new(), &msg, &CancellationToken::new())
+            .await;
         assert!(res.is_ok());
     }
 
@@ -223,19 +779,20 @@ mod tests {
         let stream = client.get_stream(2048);
 
         let mut handler = MockConsumerHandler::new();
-        handler.expect_exec().return_once(move |_, _| Ok(()));
+        handler
+            .expect_exec()
+            .return_once(move |_, _| Ok(Disposition::Ack));
 
         let dispatcher = MQTTDispatcher::new(Arc::new(client), stream).register(
-            &DispatcherDefinition {
-                name: "some/+/+/sub".to_owned(),
-                msg_type: String::new(),
-            },
+            &DispatcherDefinition::new("some/+/+/sub", ""),
             Arc::new(handler),
         );
 
         let msg = Message::new("some/topic/with/sub", vec![], 0);
 
-        let res = dispatcher.consume(&Context::new(), &msg).await;
+        let res = dispatcher
+            .consume(&Context::new(), &msg, &CancellationToken::new())
+            .await;
         assert!(res.is_ok());
     }
 
@@ -250,16 +807,15 @@ mod tests {
             .return_once(move |_, _| Err(MessagingError::ConsumerError("err".to_string())));
 
         let dispatcher = MQTTDispatcher::new(Arc::new(client), stream).register(
-            &DispatcherDefinition {
-                name: "/some/topic/#".to_owned(),
-                msg_type: String::new(),
-            },
+            &DispatcherDefinition::new("/some/topic/#", ""),
             Arc::new(handler),
         );
 
         let msg = Message::new("/some/topic/sub", vec![], 0);
 
-        let res = dispatcher.consume(&Context::new(), &msg).await;
+        let res = dispatcher
+            .consume(&Context::new(), &msg, &CancellationToken::new())
+            .await;
         assert!(res.is_err());
     }
 
@@ -269,19 +825,116 @@ mod tests {
         let stream = client.get_stream(2048);
 
         let mut handler = MockConsumerHandler::new();
-        handler.expect_exec().return_once(move |_, _| Ok(()));
+        handler
+            .expect_exec()
+            .return_once(move |_, _| Ok(Disposition::Ack));
 
         let dispatcher = MQTTDispatcher::new(Arc::new(client), stream).register(
-            &DispatcherDefinition {
-                name: "other/topic/#".to_owned(),
-                msg_type: String::new(),
-            },
+            &DispatcherDefinition::new("other/topic/#", ""),
             Arc::new(handler),
         );
 
         let msg = Message::new("some/topic/sub", vec![], 0);
 
-        let res = dispatcher.consume(&Context::new(), &msg).await;
+        let res = dispatcher
+            .consume(&Context::new(), &msg, &CancellationToken::new())
+            .await;
         assert!(res.is_err());
     }
+
+    #[tokio::test]
+    async fn test_consume_with_retry_exceeding_max_attempts() {
+        let mut client = AsyncClient::new(CreateOptions::default()).unwrap();
+        let stream = client.get_stream(2048);
+
+        let mut handler = MockConsumerHandler::new();
+        handler
+            .expect_exec()
+            .withf(|_, msg| msg.metadata.delivery_count == 3)
+            .return_once(move |_, _| Ok(Disposition::Retry { after: None }));
+
+        let dispatcher = MQTTDispatcher::new(Arc::new(client), stream)
+            .max_attempts(3)
+            .register(
+                &DispatcherDefinition::new("some/topic", ""),
+                Arc::new(handler),
+            );
+
+        let mut props = Properties::new();
+        props
+            .push_string_pair(PropertyCode::UserProperty, RETRY_COUNT_HEADER_KEY, "2")
+            .unwrap();
+        let msg = MessageBuilder::new()
+            .topic("some/topic")
+            .properties(props)
+            .finalize();
+
+        //dead lettered instead of republished with the client not connected
+        let res = dispatcher
+            .consume(&Context::new(), &msg, &CancellationToken::new())
+            .await;
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_retry_topics() {
+        let mut client = AsyncClient::new(CreateOptions::default()).unwrap();
+        let stream = client.get_stream(2048);
+        let dispatcher = MQTTDispatcher::new(Arc::new(client), stream)
+            .retry_prefix("retry/consumer-1")
+            .register(
+                &DispatcherDefinition::new("some/+/topic", ""),
+                Arc::new(MockConsumerHandler::new()),
+            );
+
+        assert_eq!(
+            dispatcher.subscriptions(),
+            vec!["some/+/topic", "retry/consumer-1/some/+/topic"]
+        );
+        assert_eq!(
+            dispatcher.original_topic("retry/consumer-1/some/1/topic"),
+            "some/1/topic"
+        );
+        assert_eq!(dispatcher.original_topic("some/1/topic"), "some/1/topic");
+        assert_eq!(
+            dispatcher.get_handler_index(&Context::new(), "retry/consumer-1/some/1/topic"),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_retried_properties() {
+        let mut props = Properties::new();
+        props
+            .push_string(PropertyCode::ContentType, "application/json")
+            .unwrap();
+        props
+            .push_string(PropertyCode::ResponseTopic, "replies")
+            .unwrap();
+        props
+            .push_string_pair(PropertyCode::UserProperty, "key", "value")
+            .unwrap();
+        props
+            .push_string_pair(PropertyCode::UserProperty, RETRY_COUNT_HEADER_KEY, "1")
+            .unwrap();
+
+        let retried = retried_properties(&props, 2);
+
+        assert_eq!(
+            retried.get_string(PropertyCode::ContentType).as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            retried.get_string(PropertyCode::ResponseTopic).as_deref(),
+            Some("replies")
+        );
+        assert_eq!(retried.find_user_property("key").as_deref(), Some("value"));
+        assert_eq!(
+            retried
+                .find_user_property(RETRY_COUNT_HEADER_KEY)
+                .as_deref(),
+            Some("2")
+        );
+        assert_eq!(retried.user_iter().count(), 2);
+    }
 }
@@ -3,3 +3,4 @@ pub mod dispatcher;
 pub mod errors;
 pub mod payload;
 pub mod publisher;
+pub mod request_reply;
@@ -1,50 +1,78 @@
 use async_trait::async_trait;
 use messaging::{
+    cloudevents::CE_HEADER_PREFIX,
+    compression,
     errors::MessagingError,
-    publisher::{HeaderValues, PublishMessage, Publisher},
+    handler::MESSAGE_ID_HEADER_KEY,
+    headers::{HeaderValue, HEADER_TYPES_KEY},
+    metrics::MessagingMetrics,
+    publisher::{PublishMessage, Publisher},
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
 };
 use opentelemetry::{
     trace::{Status, TraceContextExt},
     Context,
 };
-use paho_mqtt::{AsyncClient, Message};
-use std::{borrow::Cow, sync::Arc};
+use paho_mqtt::{AsyncClient, MessageBuilder, Properties, PropertyCode};
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
 use tracing::error;
+use uuid::Uuid;
+
+pub const QOS_HEADER_KEY: &str = "qos";
 
 pub struct MQTTPublisher {
     conn: Arc<AsyncClient>,
+    metrics: MessagingMetrics,
 }
 
 impl MQTTPublisher {
     pub fn new(conn: Arc<AsyncClient>) -> Self {
-        Self { conn }
+        Self {
+            conn,
+            metrics: MessagingMetrics::new("mqtt"),
+        }
     }
 }
 
 #[async_trait]
 impl Publisher for MQTTPublisher {
     async fn publish(&self, ctx: &Context, infos: &PublishMessage) -> Result<(), MessagingError> {
+        self.metrics.publish(infos, self.send(ctx, infos)).await
+    }
+}
+
+impl MQTTPublisher {
+    async fn send(&self, ctx: &Context, infos: &PublishMessage) -> Result<(), MessagingError> {
         let span = ctx.span();
 
+        //there is no delayed delivery in the broker, the OutboxPublisher schedules the messages
+        if infos.delay().is_some() {
+            error!(
+                to = infos.to,
+                "delayed delivery is not supported, use the outbox to schedule the message"
+            );
+            return Err(MessagingError::SchedulingError);
+        }
+
+        let compressed = compression::compress(infos)?;
+        let infos = compressed.as_ref();
+
         let mut qos: i32 = 0;
 
         if let Some(headers) = &infos.headers {
-            if let Some(custom_qos) = headers.get("qos") {
-                if let HeaderValues::Int(custom) = custom_qos {
-                    qos = custom.to_owned() as i32;
-                }
-
-                if let HeaderValues::LongInt(custom) = custom_qos {
-                    qos = custom.to_owned();
-                }
+            if let Some(custom) = headers.get(QOS_HEADER_KEY).and_then(HeaderValue::as_i64) {
+                qos = custom as i32;
             }
         }
 
-        match self
-            .conn
-            .publish(Message::new(infos.to.clone(), infos.data.clone(), qos))
-            .await
-        {
+        let msg = MessageBuilder::new()
+            .topic(infos.to.clone())
+            .payload(infos.data.clone())
+            .qos(qos)
+            .properties(self.properties(infos))
+            .finalize();
+
+        match self.conn.publish(msg).await {
             Err(err) => {
                 error!(error = err.to_string(), "error to publish message");
 
@@ -61,4 +89,144 @@ impl Publisher for MQTTPublisher {
             }
         }
     }
+
+    /// The MQTT 5 properties carrying the headers as user properties, the CloudEvents
+    /// attributes with no prefix, the content type, and the request/reply using the response
+    /// topic and correlation data. The messages with no message-id header get a new one, the
+    /// properties are ignored by the MQTT 3 connections.
+    fn properties(&self, infos: &PublishMessage) -> Properties {
+        let mut props = Properties::new();
+        let mut types = BTreeMap::new();
+        for (key, value) in infos.headers.iter().flatten() {
+            let type_name = value.type_name();
+            let value: String = value.clone().into();
+
+            let pushed = match key.as_str() {
+                REPLY_TO_HEADER_KEY => props.push_string(PropertyCode::ResponseTopic, &value),
+                CORRELATION_ID_HEADER_KEY => {
+                    props.push_binary(PropertyCode::CorrelationData, value.as_bytes())
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = pushed {
+                error!(
+                    error = err.to_string(),
+                    key = key,
+                    "failure to set property"
+                );
+            }
+
+            let name = match key.as_str() {
+                QOS_HEADER_KEY | REPLY_TO_HEADER_KEY | CORRELATION_ID_HEADER_KEY => continue,
+                _ => key.strip_prefix(CE_HEADER_PREFIX).unwrap_or(key),
+            };
+
+            //user properties are strings, the typed values are listed in the header-types property
+            if let Some(type_name) = type_name {
+                types.insert(name, type_name);
+            }
+
+            if let Err(err) = props.push_string_pair(PropertyCode::UserProperty, name, &value) {
+                error!(
+                    error = err.to_string(),
+                    key = key,
+                    "failure to set user property"
+                );
+            }
+        }
+
+        if !types.is_empty() {
+            let types = serde_json::to_string(&types).unwrap_or_default();
+            if let Err(err) =
+                props.push_string_pair(PropertyCode::UserProperty, HEADER_TYPES_KEY, &types)
+            {
+                error!(
+                    error = err.to_string(),
+                    "failure to set header types property"
+                );
+            }
+        }
+
+        let has_message_id = infos
+            .headers
+            .as_ref()
+            .is_some_and(|headers| headers.contains_key(MESSAGE_ID_HEADER_KEY));
+        if !has_message_id {
+            let message_id = Uuid::new_v4().to_string();
+            if let Err(err) = props.push_string_pair(
+                PropertyCode::UserProperty,
+                MESSAGE_ID_HEADER_KEY,
+                &message_id,
+            ) {
+                error!(
+                    error = err.to_string(),
+                    "failure to set message id property"
+                );
+            }
+        }
+
+        if let Some(content_type) = &infos.content_type {
+            if let Err(err) = props.push_string(PropertyCode::ContentType, content_type) {
+                error!(
+                    error = err.to_string(),
+                    "failure to set content type property"
+                );
+            }
+        }
+
+        props
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use paho_mqtt::CreateOptions;
+    use std::collections::HashMap;
+
+    fn publisher() -> MQTTPublisher {
+        MQTTPublisher::new(Arc::new(
+            AsyncClient::new(CreateOptions::default()).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_properties_with_new_message_id() {
+        let msg = PublishMessage::new("", "some/topic", "", "created", b"data", None);
+
+        let props = publisher().properties(&msg);
+
+        let message_id = props.find_user_property(MESSAGE_ID_HEADER_KEY).unwrap();
+        assert!(Uuid::parse_str(&message_id).is_ok());
+    }
+
+    #[test]
+    fn test_properties_with_message_id_header() {
+        let headers = HashMap::from([(
+            MESSAGE_ID_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString("id".to_owned()),
+        )]);
+        let msg = PublishMessage::new("", "some/topic", "", "created", b"data", Some(headers));
+
+        let props = publisher().properties(&msg);
+
+        assert_eq!(
+            props.find_user_property(MESSAGE_ID_HEADER_KEY).as_deref(),
+            Some("id")
+        );
+        assert_eq!(props.user_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_properties_with_content_type_and_no_headers() {
+        let mut msg = PublishMessage::new("", "some/topic", "", "created", b"data", None);
+        msg.content_type = Some("application/cloudevents+json".to_owned());
+
+        let props = publisher().properties(&msg);
+
+        assert_eq!(
+            props.get_string(PropertyCode::ContentType).as_deref(),
+            Some("application/cloudevents+json")
+        );
+    }
 }
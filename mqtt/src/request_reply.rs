@@ -0,0 +1,75 @@
+use crate::{dispatcher::consumer_message, publisher::MQTTPublisher};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use messaging::{
+    errors::MessagingError,
+    handler::ConsumerMessage,
+    publisher::PublishMessage,
+    request_reply::{PendingReplies, RequestReplyClient},
+};
+use opentelemetry::Context;
+use paho_mqtt::{AsyncClient, AsyncReceiver, Message};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error};
+
+/// RequestReplyClient using the MQTT 5 response topic and correlation data.
+///
+/// The client consumes the whole connection stream, so the connection must be exclusive
+/// to the client and created with the MQTT 5 version.
+pub struct MQTTRequestReplyClient {
+    publisher: MQTTPublisher,
+    pending: Arc<PendingReplies>,
+    response_topic: String,
+}
+
+impl MQTTRequestReplyClient {
+    pub async fn new(
+        conn: Arc<AsyncClient>,
+        mut stream: AsyncReceiver<Option<Message>>,
+        response_topic: &str,
+    ) -> Result<Arc<Self>, MessagingError> {
+        if let Err(err) = conn.subscribe(response_topic, 1).await {
+            error!(
+                error = err.to_string(),
+                "failure to subscribe the response topic"
+            );
+            return Err(MessagingError::CreatingConsumerError);
+        }
+
+        let pending = PendingReplies::new();
+
+        tokio::spawn({
+            let pending = pending.clone();
+
+            async move {
+                while let Some(delivery) = stream.next().await {
+                    if let Some(msg) = delivery {
+                        pending.complete(consumer_message(&msg));
+                    }
+                }
+
+                debug!("reply consumer stopped");
+            }
+        });
+
+        Ok(Arc::new(MQTTRequestReplyClient {
+            publisher: MQTTPublisher::new(conn),
+            pending,
+            response_topic: response_topic.to_owned(),
+        }))
+    }
+}
+
+#[async_trait]
+impl RequestReplyClient for MQTTRequestReplyClient {
+    async fn request(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        timeout: Duration,
+    ) -> Result<ConsumerMessage, MessagingError> {
+        self.pending
+            .request(&self.publisher, ctx, msg, &self.response_topic, timeout)
+            .await
+    }
+}
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS outbox_available_at_idx;
+
+ALTER TABLE outbox DROP COLUMN IF EXISTS available_at;
@@ -0,0 +1,3 @@
+ALTER TABLE outbox ADD COLUMN IF NOT EXISTS available_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS outbox_available_at_idx ON outbox (available_at) WHERE published_at IS NULL AND failed_at IS NULL AND available_at IS NOT NULL;
@@ -0,0 +1,22 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OutboxError {
+    #[error("internal error")]
+    InternalError,
+
+    #[error("db connection error")]
+    DbConnectionErr,
+
+    #[error("transaction error")]
+    TransactionErr,
+
+    #[error("select error")]
+    SelectErr,
+
+    #[error("update error")]
+    UpdateErr,
+
+    #[error("delete error")]
+    DeleteErr,
+}
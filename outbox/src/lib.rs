@@ -0,0 +1,6 @@
+pub mod errors;
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod publisher;
+#[cfg(feature = "postgres")]
+pub mod relay;
@@ -0,0 +1,14 @@
+pub const OUTBOX_TABLE: &str = "outbox";
+
+/// Directory containing the outbox migrations, it can be given to the migrator crate.
+pub const MIGRATIONS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations/");
+
+pub const CREATE_OUTBOX_TABLE_UP: &str =
+    include_str!("../migrations/0001_create_outbox_table_up.sql");
+pub const CREATE_OUTBOX_TABLE_DOWN: &str =
+    include_str!("../migrations/0001_create_outbox_table_down.sql");
+
+pub const ADD_OUTBOX_AVAILABLE_AT_UP: &str =
+    include_str!("../migrations/0002_add_outbox_available_at_up.sql");
+pub const ADD_OUTBOX_AVAILABLE_AT_DOWN: &str =
+    include_str!("../migrations/0002_add_outbox_available_at_down.sql");
@@ -0,0 +1,171 @@
+use async_trait::async_trait;
+use deadpool_postgres::{GenericClient, Pool};
+use messaging::{
+    errors::MessagingError,
+    handler::MESSAGE_ID_HEADER_KEY,
+    publisher::{PublishMessage, Publisher},
+};
+use opentelemetry::{global, Context};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+const INSERT_QUERY: &str = "
+    INSERT INTO outbox (message_id, source, destination, routing_key, msg_type, content_type, payload, headers, trace_context, available_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, CURRENT_TIMESTAMP + make_interval(secs => $10::float8))
+";
+
+/// Publisher that stores the messages in the outbox table using a pooled connection.
+///
+/// To write the message together with the caller's own changes use OutboxPublisher::transactional
+/// with the caller's transaction.
+///
+/// The scheduled messages (publish_at and publish_after) are kept in the outbox until their
+/// deliver_at time, being forwarded to the broker by the OutboxRelay.
+pub struct OutboxPublisher {
+    pool: Arc<Pool>,
+}
+
+impl OutboxPublisher {
+    pub fn new(pool: Arc<Pool>) -> Arc<OutboxPublisher> {
+        Arc::new(OutboxPublisher { pool })
+    }
+
+    pub fn transactional<C>(client: &C) -> TransactionalOutboxPublisher<'_, C>
+    where
+        C: GenericClient,
+    {
+        TransactionalOutboxPublisher { client }
+    }
+}
+
+#[async_trait]
+impl Publisher for OutboxPublisher {
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        let conn = match self.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(MessagingError::ConnectionError)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        insert(&conn, ctx, msg).await
+    }
+}
+
+/// Publisher that stores the messages in the outbox table inside the given transaction.
+pub struct TransactionalOutboxPublisher<'t, C> {
+    client: &'t C,
+}
+
+#[async_trait]
+impl<'t, C> Publisher for TransactionalOutboxPublisher<'t, C>
+where
+    C: GenericClient,
+{
+    async fn publish(&self, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError> {
+        insert(self.client, ctx, msg).await
+    }
+}
+
+async fn insert<C>(client: &C, ctx: &Context, msg: &PublishMessage) -> Result<(), MessagingError>
+where
+    C: GenericClient,
+{
+    let headers = match &msg.headers {
+        Some(headers) => match serde_json::to_value(headers) {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to serialize message headers"
+                );
+                Err(MessagingError::SerializingError)
+            }
+            Ok(v) => Ok(Some(v)),
+        },
+        _ => Ok(None),
+    }?;
+
+    let mut trace_context = HashMap::<String, String>::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(ctx, &mut trace_context)
+    });
+    let trace_context = Value::from_iter(trace_context);
+
+    let message_id = message_id(msg);
+    let delay = msg.delay().map(|delay| delay.as_secs_f64());
+
+    match client
+        .execute(
+            INSERT_QUERY,
+            &[
+                &message_id,
+                &msg.from,
+                &msg.to,
+                &msg.key,
+                &msg.msg_type,
+                &msg.content_type,
+                &msg.data.as_ref(),
+                &headers,
+                &trace_context,
+                &delay,
+            ],
+        )
+        .await
+    {
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                "error to insert the message in the outbox"
+            );
+            Err(MessagingError::PublisherError)
+        }
+        _ => {
+            debug!(
+                message_id = message_id,
+                to = msg.to,
+                msg_type = msg.msg_type,
+                "message stored in the outbox"
+            );
+            Ok(())
+        }
+    }
+}
+
+//the message id given by the caller, relayed with the message
+fn message_id(msg: &PublishMessage) -> String {
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get(MESSAGE_ID_HEADER_KEY))
+        .map(|id| id.clone().into())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messaging::publisher::HeaderValue;
+
+    #[test]
+    fn should_keep_the_message_id_of_the_message() {
+        let headers = HashMap::from([(
+            MESSAGE_ID_HEADER_KEY.to_owned(),
+            HeaderValue::ShortString("id".to_owned()),
+        )]);
+        let msg = PublishMessage::new("", "orders", "", "created", b"data", Some(headers));
+
+        assert_eq!(message_id(&msg), "id");
+    }
+
+    #[test]
+    fn should_generate_the_message_id_when_there_is_none() {
+        let msg = PublishMessage::new("", "orders", "", "created", b"data", None);
+
+        assert!(Uuid::parse_str(&message_id(&msg)).is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+use crate::errors::OutboxError;
+use deadpool_postgres::{Pool, Transaction};
+use messaging::{
+    errors::MessagingError,
+    handler::MESSAGE_ID_HEADER_KEY,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+};
+use opentelemetry::{global, Context};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_postgres::Row;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+/// Advisory lock used to guarantee that only one relay forwards the outbox rows at a time,
+/// keeping the per-key ordering across replicas.
+const RELAY_LOCK_KEY: i64 = 0x6f7574626f78;
+
+const SELECT_PENDING_QUERY: &str = "
+    SELECT id, message_id, source, destination, routing_key, msg_type, content_type, payload, headers, trace_context, attempts
+    FROM outbox
+    WHERE published_at IS NULL AND failed_at IS NULL
+        AND (available_at IS NULL OR available_at <= CURRENT_TIMESTAMP)
+    ORDER BY id
+    LIMIT $1
+";
+const MARK_PUBLISHED_QUERY: &str =
+    "UPDATE outbox SET published_at = CURRENT_TIMESTAMP, attempts = attempts + 1 WHERE id = $1";
+const MARK_ATTEMPT_QUERY: &str = "
+    UPDATE outbox SET attempts = attempts + 1, last_error = $2,
+        failed_at = CASE WHEN attempts + 1 >= $3 THEN CURRENT_TIMESTAMP ELSE NULL END
+    WHERE id = $1
+";
+const MARK_FAILED_QUERY: &str =
+    "UPDATE outbox SET failed_at = CURRENT_TIMESTAMP, last_error = $2 WHERE id = $1";
+const CLEANUP_QUERY: &str = "
+    DELETE FROM outbox
+    WHERE published_at IS NOT NULL AND published_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+";
+
+/// Forwards the pending outbox rows to a real Publisher.
+///
+/// Rows are forwarded in insertion order, when a row fails the remaining rows with the same
+/// destination and key are kept for the next poll so the per-key ordering is preserved.
+/// Scheduled rows are only forwarded once their available_at time is reached, not blocking
+/// the rows inserted after them.
+pub struct OutboxRelay {
+    pool: Arc<Pool>,
+    publisher: Arc<dyn Publisher>,
+    batch_size: i64,
+    poll_interval: Duration,
+    max_attempts: i32,
+    retention: Duration,
+    cleanup_interval: Duration,
+}
+
+struct OutboxRow {
+    id: i64,
+    attempts: i32,
+    ctx: Context,
+    msg: PublishMessage,
+}
+
+impl OutboxRelay {
+    pub fn new(pool: Arc<Pool>, publisher: Arc<dyn Publisher>) -> OutboxRelay {
+        OutboxRelay {
+            pool,
+            publisher,
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+            max_attempts: 10,
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+            cleanup_interval: Duration::from_secs(60 * 60),
+        }
+    }
+
+    pub fn batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn cleanup_interval(mut self, cleanup_interval: Duration) -> Self {
+        self.cleanup_interval = cleanup_interval;
+        self
+    }
+
+    /// Relays the pending rows until the shutdown is cancelled, the running pass is finished
+    /// before returning.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<(), OutboxError> {
+        let mut cleaned_at = Instant::now();
+
+        while !shutdown.is_cancelled() {
+            //a relay pass is never interrupted, the rows published by it would be published again
+            let relayed = self.relay().await;
+
+            if cleaned_at.elapsed() >= self.cleanup_interval {
+                if let Err(err) = self.cleanup().await {
+                    error!(error = err.to_string(), "failure to cleanup the outbox");
+                }
+                cleaned_at = Instant::now();
+            }
+
+            match relayed {
+                Ok(count) if count as i64 >= self.batch_size => continue,
+                Ok(_) => {}
+                Err(err) => error!(error = err.to_string(), "failure to relay the outbox"),
+            };
+
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+        }
+
+        debug!("outbox relay stopped");
+        Ok(())
+    }
+
+    /// Forwards one batch of pending rows, returning how many rows were published.
+    pub async fn relay(&self) -> Result<usize, OutboxError> {
+        let mut conn = match self.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(OutboxError::DbConnectionErr)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        let tx = match conn.transaction().await {
+            Err(err) => {
+                error!(error = err.to_string(), "error to begin transaction");
+                Err(OutboxError::TransactionErr)
+            }
+            Ok(tx) => Ok(tx),
+        }?;
+
+        match tx
+            .query_one("SELECT pg_try_advisory_xact_lock($1)", &[&RELAY_LOCK_KEY])
+            .await
+        {
+            Err(err) => {
+                error!(error = err.to_string(), "error to acquire the relay lock");
+                return Err(OutboxError::SelectErr);
+            }
+            Ok(row) if !row.get::<_, bool>(0) => {
+                debug!("outbox relay lock held by another relay");
+                return Ok(0);
+            }
+            _ => {}
+        };
+
+        let rows = match tx.query(SELECT_PENDING_QUERY, &[&self.batch_size]).await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to select pending outbox rows"
+                );
+                Err(OutboxError::SelectErr)
+            }
+            Ok(rows) => Ok(rows),
+        }?;
+
+        let mut published = 0;
+        let mut blocked_keys = HashSet::new();
+
+        for row in rows {
+            //a malformed row would fail every pass, it is marked failed instead
+            let row = match outbox_row(&row) {
+                Err(err) => {
+                    let id = row.get::<_, i64>("id");
+                    error!(error = err, id = id, "malformed outbox message failed");
+                    self.mark_failed(&tx, &id, &err).await?;
+                    continue;
+                }
+                Ok(row) => row,
+            };
+            let ordering_key = (row.msg.to.clone(), row.msg.key.clone());
+
+            if blocked_keys.contains(&ordering_key) {
+                continue;
+            }
+
+            match self.publisher.publish(&row.ctx, &row.msg).await {
+                Ok(_) => {
+                    self.execute(&tx, MARK_PUBLISHED_QUERY, &row.id, None)
+                        .await?;
+                    published += 1;
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        id = row.id,
+                        attempts = row.attempts + 1,
+                        "failure to relay outbox message"
+                    );
+
+                    if row.attempts + 1 >= self.max_attempts {
+                        error!(id = row.id, "too many attempts, outbox message failed");
+                    } else {
+                        blocked_keys.insert(ordering_key);
+                    }
+
+                    self.execute(&tx, MARK_ATTEMPT_QUERY, &row.id, Some(err))
+                        .await?;
+                }
+            }
+        }
+
+        match tx.commit().await {
+            Err(err) => {
+                error!(error = err.to_string(), "error to commit transaction");
+                Err(OutboxError::TransactionErr)
+            }
+            _ => Ok(published),
+        }
+    }
+
+    /// Deletes the published rows older than the retention.
+    pub async fn cleanup(&self) -> Result<u64, OutboxError> {
+        let conn = match self.pool.get().await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to retrieve a db connection from pool"
+                );
+                Err(OutboxError::DbConnectionErr)
+            }
+            Ok(c) => Ok(c),
+        }?;
+
+        match conn
+            .execute(CLEANUP_QUERY, &[&self.retention.as_secs_f64()])
+            .await
+        {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error to cleanup published messages"
+                );
+                Err(OutboxError::DeleteErr)
+            }
+            Ok(deleted) => {
+                debug!(deleted = deleted, "outbox cleaned up");
+                Ok(deleted)
+            }
+        }
+    }
+
+    async fn mark_failed(
+        &self,
+        tx: &Transaction<'_>,
+        id: &i64,
+        err: &str,
+    ) -> Result<(), OutboxError> {
+        match tx.execute(MARK_FAILED_QUERY, &[id, &err]).await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    id = id,
+                    "error to update outbox row"
+                );
+                Err(OutboxError::UpdateErr)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn execute(
+        &self,
+        tx: &Transaction<'_>,
+        query: &str,
+        id: &i64,
+        err: Option<MessagingError>,
+    ) -> Result<(), OutboxError> {
+        let res = match err {
+            Some(err) => {
+                tx.execute(query, &[id, &err.to_string(), &self.max_attempts])
+                    .await
+            }
+            _ => tx.execute(query, &[id]).await,
+        };
+
+        match res {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    id = id,
+                    "error to update outbox row"
+                );
+                Err(OutboxError::UpdateErr)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn outbox_row(row: &Row) -> Result<OutboxRow, String> {
+    let headers = message_headers(
+        row.try_get::<_, Option<Value>>("headers")
+            .map_err(|err| err.to_string())?,
+        row.try_get::<_, String>("message_id")
+            .map_err(|err| err.to_string())?,
+    )
+    .map_err(|err| format!("error to deserialize outbox headers: {}", err))?;
+
+    let trace_context = row
+        .get::<_, Option<Value>>("trace_context")
+        .and_then(|value| serde_json::from_value::<HashMap<String, String>>(value).ok())
+        .unwrap_or_default();
+
+    let ctx = global::get_text_map_propagator(|propagator| propagator.extract(&trace_context));
+
+    let mut msg = PublishMessage::new(
+        row.get::<_, String>("source"),
+        row.get::<_, String>("destination"),
+        row.get::<_, String>("routing_key"),
+        row.get::<_, String>("msg_type"),
+        row.get::<_, &[u8]>("payload"),
+        Some(headers),
+    );
+    msg.content_type = row.get::<_, Option<String>>("content_type");
+
+    Ok(OutboxRow {
+        id: row.get::<_, i64>("id"),
+        attempts: row.get::<_, i32>("attempts"),
+        ctx,
+        msg,
+    })
+}
+
+//the stored headers with the message id generated when the message was stored, so the
+//consumers can dedupe the messages relayed again
+fn message_headers(
+    headers: Option<Value>,
+    message_id: String,
+) -> Result<HashMap<String, HeaderValue>, serde_json::Error> {
+    let mut headers = match headers {
+        Some(value) => serde_json::from_value::<HashMap<String, HeaderValue>>(value)?,
+        _ => HashMap::new(),
+    };
+
+    headers
+        .entry(MESSAGE_ID_HEADER_KEY.to_owned())
+        .or_insert(HeaderValue::ShortString(message_id));
+
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn should_add_the_stored_message_id_to_the_headers() {
+        let headers = message_headers(None, "id".to_owned()).unwrap();
+
+        assert_eq!(
+            headers.get(MESSAGE_ID_HEADER_KEY),
+            Some(&HeaderValue::ShortString("id".to_owned()))
+        );
+    }
+
+    #[test]
+    fn should_keep_the_message_id_of_the_stored_headers() {
+        let stored = serde_json::to_value(HashMap::from([
+            (
+                MESSAGE_ID_HEADER_KEY.to_owned(),
+                HeaderValue::ShortString("original".to_owned()),
+            ),
+            ("key".to_owned(), HeaderValue::LongInt(1)),
+        ]))
+        .unwrap();
+
+        let headers = message_headers(Some(stored), "id".to_owned()).unwrap();
+
+        assert_eq!(headers.len(), 2);
+        assert_eq!(
+            headers.get(MESSAGE_ID_HEADER_KEY),
+            Some(&HeaderValue::ShortString("original".to_owned()))
+        );
+    }
+
+    #[test]
+    fn should_return_error_for_the_malformed_headers() {
+        let res = message_headers(Some(json!(["not", "headers"])), "id".to_owned());
+
+        assert!(res.is_err());
+    }
+}
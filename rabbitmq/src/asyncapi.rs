@@ -0,0 +1,149 @@
+use crate::{
+    exchange::{ExchangeDefinition, ExchangeKind, AMQP_HEADERS_DELAYED_EXCHANGE_TYPE},
+    queue::{QueueBinding, QueueDefinition},
+    topology::AmqpTopology,
+};
+use lapin::types::{AMQPValue, ShortString};
+use messaging::asyncapi::AsyncApi;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Version of the AsyncAPI AMQP bindings used in the channels.
+pub const AMQP_BINDING_VERSION: &str = "0.3.0";
+
+/// Name of the channel documenting the exchange, an exchange and a queue can share a name.
+pub fn exchange_channel(name: &str) -> String {
+    format!("exchange-{}", name)
+}
+
+/// Name of the channel documenting the queue, to be given to AsyncApi::receive.
+pub fn queue_channel(name: &str) -> String {
+    format!("queue-{}", name)
+}
+
+impl AmqpTopology<'_> {
+    /// Adds a channel to the document for each exchange and queue of the topology, with the
+    /// AMQP bindings describing them, named by exchange_channel and queue_channel. The queue
+    /// bindings go in the x-amqp-bindings extension of the queue channels.
+    pub fn asyncapi(&self, document: AsyncApi) -> AsyncApi {
+        let mut queues = self.queues.values().copied().collect::<Vec<_>>();
+        queues.sort_by_key(|queue| queue.name.as_str());
+
+        channels(document, &self.exchanges, &queues, &self.queues_binding)
+    }
+}
+
+fn channels(
+    mut document: AsyncApi,
+    exchanges: &[&ExchangeDefinition],
+    queues: &[&QueueDefinition],
+    queues_binding: &[&QueueBinding],
+) -> AsyncApi {
+    for exchange in exchanges {
+        let binding = json!({
+            "is": "routingKey",
+            "exchange": {
+                "name": exchange.name,
+                "type": exchange_type(exchange.kind, &exchange.params),
+                "durable": exchange.durable,
+                "autoDelete": exchange.delete,
+            },
+            "bindingVersion": AMQP_BINDING_VERSION,
+        });
+
+        let channel = exchange_channel(exchange.name);
+        document = document
+            .channel(channel.as_str(), exchange.name)
+            .channel_binding(channel.as_str(), "amqp", binding);
+    }
+
+    let mut queue_bindings: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+    for binding in queues_binding {
+        queue_bindings
+            .entry(binding.queue_name)
+            .or_default()
+            .push(json!({
+                "exchange": binding.exchange_name,
+                "routingKey": binding.routing_key,
+            }));
+    }
+
+    for queue in queues {
+        let name = queue.name.as_str();
+        let binding = json!({
+            "is": "queue",
+            "queue": {
+                "name": name,
+                "durable": queue.durable,
+                "exclusive": queue.exclusive,
+                "autoDelete": queue.delete,
+            },
+            "bindingVersion": AMQP_BINDING_VERSION,
+        });
+
+        let channel = queue_channel(name);
+        document = document.channel(channel.as_str(), name).channel_binding(
+            channel.as_str(),
+            "amqp",
+            binding,
+        );
+
+        if let Some(bindings) = queue_bindings.remove(name) {
+            document =
+                document.channel_extension(channel.as_str(), "x-amqp-bindings", json!(bindings));
+        }
+    }
+
+    document
+}
+
+//the delayed message exchanges route as the exchange type given in their params
+fn exchange_type(kind: &ExchangeKind, params: &BTreeMap<ShortString, AMQPValue>) -> String {
+    match kind {
+        ExchangeKind::Direct => "direct".to_owned(),
+        ExchangeKind::Fanout => "fanout".to_owned(),
+        ExchangeKind::Topic => "topic".to_owned(),
+        ExchangeKind::Headers => "headers".to_owned(),
+        ExchangeKind::XMessageDelayed => params
+            .iter()
+            .find(|(key, _)| key.as_str() == AMQP_HEADERS_DELAYED_EXCHANGE_TYPE)
+            .and_then(|(_, value)| match value {
+                AMQPValue::LongString(kind) => Some(kind.to_string()),
+                AMQPValue::ShortString(kind) => Some(kind.to_string()),
+                _ => None,
+            })
+            .unwrap_or_else(|| "direct".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_document_the_exchange_and_queue_with_the_same_name() {
+        let exchange = ExchangeDefinition::new("orders").fanout();
+        let queue = QueueDefinition::new("orders").durable();
+        let binding = QueueBinding::new("orders")
+            .exchange("orders")
+            .routing_key("order.created");
+
+        let document = channels(
+            AsyncApi::new("orders", "1.0.0"),
+            &[&exchange],
+            &[&queue],
+            &[&binding],
+        )
+        .document();
+
+        let exchange = &document["channels"]["exchange-orders"];
+        assert_eq!(exchange["address"], "orders");
+        assert_eq!(exchange["bindings"]["amqp"]["is"], "routingKey");
+        assert_eq!(exchange["bindings"]["amqp"]["exchange"]["type"], "fanout");
+
+        let queue = &document["channels"]["queue-orders"];
+        assert_eq!(queue["address"], "orders");
+        assert_eq!(queue["bindings"]["amqp"]["is"], "queue");
+        assert_eq!(queue["x-amqp-bindings"][0]["routingKey"], "order.created");
+    }
+}
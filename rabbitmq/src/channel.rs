@@ -4,6 +4,8 @@ use lapin::{types::LongString, Channel, Connection, ConnectionProperties};
 use std::sync::Arc;
 use tracing::{debug, error};
 
+pub(crate) const REPLY_SUCCESS: u16 = 200;
+
 pub async fn new_amqp_channel<T>(
     cfg: &Configs<T>,
 ) -> Result<(Arc<Connection>, Arc<Channel>), AmqpError>
@@ -36,3 +38,26 @@ where
         }
     }
 }
+
+/// Closes the channel and the connection, the unacked deliveries are requeued by the broker.
+///
+/// It should be called after the dispatchers consuming from the channel were shutdown.
+pub async fn close_amqp_channel(conn: &Connection, channel: &Channel) -> Result<(), AmqpError> {
+    debug!("closing amqp channel...");
+    if let Err(err) = channel.close(REPLY_SUCCESS, "shutdown").await {
+        error!(error = err.to_string(), "error to close the channel");
+        return Err(AmqpError::ChannelError {});
+    }
+
+    debug!("closing amqp connection...");
+    match conn.close(REPLY_SUCCESS, "shutdown").await {
+        Err(err) => {
+            error!(error = err.to_string(), "error to close the connection");
+            Err(AmqpError::ConnectionError {})
+        }
+        _ => {
+            debug!("amqp connection closed");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{
+    channel::{close_amqp_channel, REPLY_SUCCESS},
+    errors::AmqpError,
+};
+use configs::{Configs, DynamicConfigs};
+use futures_util::future::BoxFuture;
+use lapin::{types::LongString, Channel, Connection, ConnectionProperties};
+use messaging::{resilience::RetryPolicy, shutdown::CancellationToken};
+use std::{
+    future::Future,
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+use tracing::{debug, error, info, warn};
+
+/// Interval checking the connection when the broker does not report the failure.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+type Topology =
+    Arc<dyn Fn(Arc<Channel>) -> BoxFuture<'static, Result<(), AmqpError>> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Reconnecting,
+    /// The reconnection gave up after the attempts of the reconnect policy.
+    Failed,
+    Closed,
+}
+
+/// RabbitMQ connection reconnecting with backoff when the connection or its channel is
+/// closed, installing the topology again and swapping the channel of the publishers and
+/// dispatchers created from it.
+///
+/// The dispatchers re-create their consumers once the connection is back, the deliveries
+/// not acked before the failure are redelivered by the broker.
+pub struct AmqpConnection {
+    uri: String,
+    name: String,
+    reconnect: RetryPolicy,
+    topology: Option<Topology>,
+    current: RwLock<Option<(Arc<Connection>, Arc<Channel>)>>,
+    status: watch::Sender<ConnectionStatus>,
+    broken: Arc<Notify>,
+    closed: CancellationToken,
+}
+
+impl AmqpConnection {
+    pub fn new<T>(cfg: &Configs<T>) -> AmqpConnection
+    where
+        T: DynamicConfigs,
+    {
+        AmqpConnection {
+            uri: cfg.rabbitmq_uri(),
+            name: cfg.app.name.clone(),
+            reconnect: reconnect_policy(),
+            topology: None,
+            current: RwLock::new(None),
+            status: watch::Sender::new(ConnectionStatus::Connecting),
+            broken: Arc::new(Notify::new()),
+            closed: CancellationToken::new(),
+        }
+    }
+
+    /// Backoff between the reconnection attempts, and the attempts before giving up,
+    /// defaults to unlimited attempts.
+    pub fn reconnect(mut self, policy: RetryPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Installs the topology in each new channel, the AmqpTopology being built inside the
+    /// function so it is declared again after the reconnections.
+    pub fn topology<F, Fut>(mut self, install: F) -> Self
+    where
+        F: Fn(Arc<Channel>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), AmqpError>> + Send + 'static,
+    {
+        self.topology = Some(Arc::new(move |channel| Box::pin(install(channel))));
+        self
+    }
+
+    /// Connects and installs the topology, failing right away when the broker is not
+    /// reachable. The reconnections start after the first connection.
+    pub async fn connect(self) -> Result<Arc<AmqpConnection>, AmqpError> {
+        let opened = self.open().await?;
+        *self.current.write().unwrap() = Some(opened);
+        self.status.send_replace(ConnectionStatus::Connected);
+
+        let conn = Arc::new(self);
+        tokio::spawn(watch_connection(Arc::downgrade(&conn)));
+
+        Ok(conn)
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.current
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(conn, channel)| {
+                conn.status().connected() && channel.status().connected()
+            })
+    }
+
+    pub fn connection(&self) -> Arc<Connection> {
+        //there is always a connection after connect
+        self.current.read().unwrap().as_ref().unwrap().0.clone()
+    }
+
+    pub fn channel(&self) -> Arc<Channel> {
+        self.current.read().unwrap().as_ref().unwrap().1.clone()
+    }
+
+    /// Waits a connected channel other than the given one, None when the connection was
+    /// closed or gave up reconnecting.
+    pub async fn reconnected(&self, channel: &Arc<Channel>) -> Option<Arc<Channel>> {
+        let mut status = self.status.subscribe();
+
+        loop {
+            match *status.borrow_and_update() {
+                ConnectionStatus::Failed | ConnectionStatus::Closed => return None,
+                ConnectionStatus::Connected => {
+                    let current = self.channel();
+                    if !Arc::ptr_eq(&current, channel) {
+                        return Some(current);
+                    }
+                }
+                _ => {}
+            }
+
+            if status.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    /// Stops reconnecting and closes the channel and the connection.
+    pub async fn close(&self) -> Result<(), AmqpError> {
+        self.closed.cancel();
+        self.status.send_replace(ConnectionStatus::Closed);
+
+        let (conn, channel) = (self.connection(), self.channel());
+        close_amqp_channel(&conn, &channel).await
+    }
+
+    async fn open(&self) -> Result<(Arc<Connection>, Arc<Channel>), AmqpError> {
+        debug!("creating amqp connection...");
+        let options = ConnectionProperties::default()
+            .with_connection_name(LongString::from(self.name.clone()));
+
+        let conn = match Connection::connect(&self.uri, options).await {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to connect");
+                Err(AmqpError::ConnectionError)
+            }
+            Ok(conn) => Ok(conn),
+        }?;
+
+        let broken = self.broken.clone();
+        conn.on_error(move |err| {
+            warn!(error = err.to_string(), "amqp connection error");
+            broken.notify_one();
+        });
+
+        //the connection is closed when the channel is not ready, not leaking a connection
+        //on each reconnection attempt
+        let channel = match conn.create_channel().await {
+            Err(err) => {
+                error!(error = err.to_string(), "error to create the channel");
+                let _ = conn.close(REPLY_SUCCESS, "channel error").await;
+                Err(AmqpError::ChannelError)
+            }
+            Ok(channel) => Ok(Arc::new(channel)),
+        }?;
+
+        if let Some(install) = &self.topology {
+            if let Err(err) = install(channel.clone()).await {
+                let _ = conn.close(REPLY_SUCCESS, "topology error").await;
+                return Err(err);
+            }
+        }
+
+        debug!("amqp connected");
+        Ok((Arc::new(conn), channel))
+    }
+
+    /// Reconnects until it succeeds, returning false when the connection was closed or the
+    /// reconnect policy gave up.
+    async fn reopen(&self) -> bool {
+        self.status.send_replace(ConnectionStatus::Reconnecting);
+        warn!("amqp connection lost, reconnecting");
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            match self.open().await {
+                Ok(opened) => {
+                    let stale = self.current.write().unwrap().replace(opened);
+                    self.status.send_replace(ConnectionStatus::Connected);
+                    info!(attempt = attempt, "amqp reconnected");
+
+                    //the stale connection may still be open when only its channel was closed
+                    if let Some((conn, _)) = stale {
+                        if conn.status().connected() {
+                            let _ = conn.close(REPLY_SUCCESS, "reconnecting").await;
+                        }
+                    }
+                    return true;
+                }
+                Err(err) if attempt >= self.reconnect.attempts() => {
+                    error!(
+                        error = err.to_string(),
+                        attempt = attempt,
+                        "amqp reconnection failed too many times, giving up"
+                    );
+                    self.status.send_replace(ConnectionStatus::Failed);
+                    return false;
+                }
+                Err(_) => {}
+            }
+
+            let backoff = self.reconnect.backoff(attempt);
+            warn!(
+                attempt = attempt,
+                backoff_ms = backoff.as_millis() as u64,
+                "amqp reconnection failed, retrying"
+            );
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = self.closed.cancelled() => return false,
+            }
+        }
+    }
+}
+
+fn reconnect_policy() -> RetryPolicy {
+    RetryPolicy::new()
+        .max_attempts(u32::MAX)
+        .initial_backoff(Duration::from_secs(1))
+        .max_backoff(Duration::from_secs(30))
+}
+
+//holds a weak reference, stopping when the connection is dropped
+async fn watch_connection(conn: Weak<AmqpConnection>) {
+    let Some((broken, closed)) = conn
+        .upgrade()
+        .map(|conn| (conn.broken.clone(), conn.closed.clone()))
+    else {
+        return;
+    };
+
+    loop {
+        tokio::select! {
+            _ = closed.cancelled() => return,
+            _ = broken.notified() => {},
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {},
+        }
+
+        let Some(conn) = conn.upgrade() else {
+            return;
+        };
+
+        if closed.is_cancelled() || conn.is_connected() {
+            continue;
+        }
+
+        if !conn.reopen().await {
+            return;
+        }
+    }
+}
+
+/// Channel of the publishers and dispatchers, fixed or swapped by the AmqpConnection after
+/// the reconnections.
+#[derive(Clone)]
+pub enum ChannelSource {
+    Fixed(Arc<Channel>),
+    Managed(Arc<AmqpConnection>),
+}
+
+impl ChannelSource {
+    pub fn channel(&self) -> Arc<Channel> {
+        match self {
+            ChannelSource::Fixed(channel) => channel.clone(),
+            ChannelSource::Managed(conn) => conn.channel(),
+        }
+    }
+
+    pub fn connection(&self) -> Option<&Arc<AmqpConnection>> {
+        match self {
+            ChannelSource::Managed(conn) => Some(conn),
+            ChannelSource::Fixed(_) => None,
+        }
+    }
+}
+
+impl From<Arc<Channel>> for ChannelSource {
+    fn from(channel: Arc<Channel>) -> Self {
+        ChannelSource::Fixed(channel)
+    }
+}
+
+impl From<Arc<AmqpConnection>> for ChannelSource {
+    fn from(conn: Arc<AmqpConnection>) -> Self {
+        ChannelSource::Managed(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_keep_the_max_backoff_during_long_outages() {
+        let policy = reconnect_policy();
+
+        //a day of outage reconnecting every 30 seconds, and the last attempt
+        for attempt in (66..3000).chain([u32::MAX]) {
+            assert_eq!(policy.backoff(attempt), Duration::from_secs(30));
+        }
+    }
+}
@@ -1,26 +1,58 @@
-use crate::{dispatcher::RabbitMQDispatcherDefinition, errors::AmqpError, otel};
+use crate::{
+    dispatcher::{RabbitMQBatchDispatcherDefinition, RabbitMQDispatcherDefinition},
+    errors::AmqpError,
+    otel,
+    publisher::AMQP_CLOUDEVENTS_PREFIX,
+    queue::QueueDefinition,
+};
+use chrono::DateTime;
 use lapin::{
     message::Delivery,
     options::{BasicAckOptions, BasicNackOptions, BasicPublishOptions},
     protocol::basic::AMQPProperties,
-    types::FieldTable,
+    types::{AMQPValue, FieldTable, LongString, ShortString},
     Channel,
 };
-use messaging::handler::ConsumerMessage;
+use messaging::{
+    cloudevents::{self, CE_HEADER_PREFIX},
+    compression::{self, CONTENT_ENCODING_HEADER_KEY},
+    handler::{ConsumerMessage, Disposition, MessageMetadata, MESSAGE_ID_HEADER_KEY},
+    headers::{self, HeaderValue},
+    metrics::MessagingMetrics,
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
+};
 use opentelemetry::{
-    global::BoxedTracer,
+    global::{BoxedSpan, BoxedTracer},
     trace::{Span, Status},
+    Context,
 };
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
 use tracing::{debug, error, warn};
 
 pub const AMQP_HEADERS_X_DEATH: &str = "x-death";
 pub const AMQP_HEADERS_COUNT: &str = "count";
+pub const AMQP_HEADERS_DEAD_LETTER_REASON: &str = "x-dead-letter-reason";
+
+struct Received<'a> {
+    delivery: &'a Delivery,
+    msg: &'a ConsumerMessage,
+    count: i64,
+    metrics: &'a MessagingMetrics,
+}
+
+/// Disposition of the deliveries with no handler registered, using the queue of the consumer
+/// receiving them.
+pub(crate) struct Unmatched {
+    pub(crate) queue_def: QueueDefinition,
+    pub(crate) disposition: Disposition,
+}
 
 pub(crate) async fn consume<'c>(
     tracer: &BoxedTracer,
+    metrics: &MessagingMetrics,
     delivery: &Delivery,
     defs: &'c HashMap<String, RabbitMQDispatcherDefinition>,
+    unmatched: &Unmatched,
     channel: Arc<Channel>,
 ) -> Result<(), AmqpError> {
     let (msg_type, count) = extract_header_properties(&delivery.properties);
@@ -36,136 +68,373 @@ pub(crate) async fn consume<'c>(
     );
 
     let Some(dispatcher_def) = defs.get(&msg_type) else {
-        let msg = "removing message from queue - reason: unsupported msg type";
-        span.record_error(&AmqpError::ConsumerError(msg.to_string()));
-        span.set_status(Status::Error {
-            description: Cow::from(msg),
-        });
-
-        debug!(
+        metrics.received(&unmatched.queue_def.name, &msg_type);
+        warn!(
             trace.id = traces::trace_id(&ctx),
             span.id = traces::span_id(&ctx),
-            "{}",
-            msg
+            msg_type = msg_type,
+            disposition = format!("{:?}", unmatched.disposition),
+            "there is no handler registered for this msg_type"
         );
 
-        if let Err(e) = delivery.ack(BasicAckOptions { multiple: false }).await {
-            error!("error whiling ack msg");
-            span.record_error(&e);
-            span.set_status(Status::Error {
-                description: Cow::from("error to ack msg"),
-            });
+        let msg = consumer_message(&unmatched.queue_def.name, &msg_type, delivery);
+        let received = Received {
+            delivery,
+            msg: &msg,
+            count,
+            metrics,
         };
 
-        return Err(AmqpError::InternalError {});
+        return dispose(
+            &ctx,
+            &mut span,
+            &received,
+            &unmatched.queue_def,
+            unmatched.disposition.clone(),
+            channel,
+        )
+        .await;
     };
 
-    let msg = ConsumerMessage::new(
-        &dispatcher_def.queue_def.name,
-        &msg_type,
-        &delivery.data,
-        None,
-    );
+    metrics.received(&dispatcher_def.queue_def.name, &msg_type);
+    let msg = consumer_message(&dispatcher_def.queue_def.name, &msg_type, delivery);
 
-    let result = dispatcher_def.handler.exec(&ctx, &msg).await;
-    if result.is_ok() {
-        debug!("message successfully processed");
-        match delivery.ack(BasicAckOptions { multiple: false }).await {
-            Err(e) => {
-                error!(
+    let disposition = match compression::undecompressed(&msg) {
+        Some(disposition) => disposition,
+        None => match metrics
+            .exec(dispatcher_def.handler.as_ref(), &ctx, &msg)
+            .await
+        {
+            Ok(disposition) => disposition,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
                     trace.id = traces::trace_id(&ctx),
                     span.id = traces::span_id(&ctx),
-                    "error whiling ack msg"
+                    "error whiling handling msg"
                 );
-                span.record_error(&e);
-                span.set_status(Status::Error {
-                    description: Cow::from("error to ack msg"),
-                });
-                return Err(AmqpError::AckMessageError {});
+                Disposition::retry()
+            }
+        },
+    };
+
+    let received = Received {
+        delivery,
+        msg: &msg,
+        count,
+        metrics,
+    };
+
+    dispose(
+        &ctx,
+        &mut span,
+        &received,
+        &dispatcher_def.queue_def,
+        disposition,
+        channel,
+    )
+    .await
+}
+
+pub(crate) async fn consume_batch(
+    tracer: &BoxedTracer,
+    metrics: &MessagingMetrics,
+    deliveries: &[Delivery],
+    def: &RabbitMQBatchDispatcherDefinition,
+    channel: Arc<Channel>,
+    multiple_ack: bool,
+) -> Result<(), AmqpError> {
+    let Some(last) = deliveries.last() else {
+        return Ok(());
+    };
+
+    let mut spans = Vec::with_capacity(deliveries.len());
+    let mut msgs = Vec::with_capacity(deliveries.len());
+
+    for delivery in deliveries {
+        let (msg_type, count) = extract_header_properties(&delivery.properties);
+        let (ctx, span) = otel::new_span(&delivery.properties, tracer, &msg_type);
+
+        metrics.received(&def.queue_def.name, &msg_type);
+        msgs.push(consumer_message(&def.queue_def.name, &msg_type, delivery));
+        spans.push((ctx, span, count));
+    }
+
+    debug!(
+        queue = def.queue_def.name,
+        size = msgs.len(),
+        "received batch"
+    );
+
+    let ctx = &spans[0].0;
+    let results = compression::exec_batch(&msgs, |msgs| async move {
+        metrics.exec_batch(def.handler.as_ref(), ctx, &msgs).await
+    })
+    .await;
+
+    //a single ack confirms the whole batch when the channel has no other consumer
+    let all_acked = results
+        .iter()
+        .all(|res| matches!(res, Ok(Disposition::Ack) | Ok(Disposition::Reject)));
+    if multiple_ack && all_acked {
+        return match last.ack(BasicAckOptions { multiple: true }).await {
+            Err(e) => {
+                error!(error = e.to_string(), "error whiling ack batch");
+                for (_, span, _) in spans.iter_mut() {
+                    span.record_error(&e);
+                    span.set_status(Status::Error {
+                        description: Cow::from("error to ack msg"),
+                    });
+                }
+                Err(AmqpError::AckMessageError {})
             }
             _ => {
-                span.set_status(Status::Ok);
-                return Ok(());
+                for (_, span, _) in spans.iter_mut() {
+                    span.set_status(Status::Ok);
+                }
+                Ok(())
             }
-        }
+        };
     }
 
-    //ack msg and remove from queue if handler failure and there are no fallback configured or send to dlq
-    if dispatcher_def.queue_def.retry_name.is_none() {
-        match delivery
-            .nack(BasicNackOptions {
-                multiple: false,
-                requeue: false,
-            })
-            .await
-        {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                error!(
+    let mut disposed = Ok(());
+    let received = deliveries.iter().zip(&msgs).zip(spans).zip(results);
+    for (((delivery, msg), (ctx, mut span, count)), result) in received {
+        let disposition = match result {
+            Ok(disposition) => disposition,
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
                     trace.id = traces::trace_id(&ctx),
                     span.id = traces::span_id(&ctx),
-                    "error whiling nack msg"
+                    "error whiling handling msg"
                 );
-                span.record_error(&e);
-                span.set_status(Status::Error {
-                    description: Cow::from("error to nack msg"),
-                });
-                return Err(AmqpError::NackMessageError {});
+                Disposition::retry()
             }
+        };
+
+        let received = Received {
+            delivery,
+            msg,
+            count,
+            metrics,
+        };
+
+        if let Err(err) = dispose(
+            &ctx,
+            &mut span,
+            &received,
+            &def.queue_def,
+            disposition,
+            channel.clone(),
+        )
+        .await
+        {
+            disposed = Err(err);
         }
     }
 
-    //send msg to retry when handler failure and the retry count Dont active the max of the retries configured
-    if count < dispatcher_def.queue_def.retries.unwrap() as i64 {
-        warn!(
-            trace.id = traces::trace_id(&ctx),
-            span.id = traces::span_id(&ctx),
-            "error whiling handling msg, requeuing for latter"
-        );
-        match delivery
-            .nack(BasicNackOptions {
-                multiple: false,
-                requeue: false,
-            })
-            .await
-        {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                error!(
-                    trace.id = traces::trace_id(&ctx),
-                    span.id = traces::span_id(&ctx),
-                    "error whiling requeuing"
-                );
-                span.record_error(&e);
-                span.set_status(Status::Error {
-                    description: Cow::from("error to requeuing msg"),
-                });
-                return Err(AmqpError::RequeuingMessageError {});
-            }
+    disposed
+}
+
+async fn dispose(
+    ctx: &Context,
+    span: &mut BoxedSpan,
+    received: &Received<'_>,
+    queue_def: &QueueDefinition,
+    disposition: Disposition,
+    channel: Arc<Channel>,
+) -> Result<(), AmqpError> {
+    let delivery = received.delivery;
+
+    match disposition {
+        Disposition::Ack => {
+            debug!("message successfully processed");
+            ack(ctx, span, delivery).await
+        }
+        Disposition::Reject => {
+            warn!(
+                trace.id = traces::trace_id(ctx),
+                span.id = traces::span_id(ctx),
+                "message rejected by the handler, removing from queue"
+            );
+            ack(ctx, span, delivery).await
+        }
+        Disposition::Retry { after } => retry(ctx, span, received, queue_def, after, channel).await,
+        Disposition::DeadLetter { reason } => {
+            dead_letter(ctx, span, received, queue_def, &reason, channel).await
+        }
+    }
+}
+
+async fn ack(ctx: &Context, span: &mut BoxedSpan, delivery: &Delivery) -> Result<(), AmqpError> {
+    match delivery.ack(BasicAckOptions { multiple: false }).await {
+        Err(e) => {
+            error!(
+                trace.id = traces::trace_id(ctx),
+                span.id = traces::span_id(ctx),
+                "error whiling ack msg"
+            );
+            span.record_error(&e);
+            span.set_status(Status::Error {
+                description: Cow::from("error to ack msg"),
+            });
+            Err(AmqpError::AckMessageError {})
+        }
+        _ => {
+            span.set_status(Status::Ok);
+            Ok(())
+        }
+    }
+}
+
+async fn nack(ctx: &Context, span: &mut BoxedSpan, delivery: &Delivery) -> Result<(), AmqpError> {
+    match delivery
+        .nack(BasicNackOptions {
+            multiple: false,
+            requeue: false,
+        })
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(
+                trace.id = traces::trace_id(ctx),
+                span.id = traces::span_id(ctx),
+                "error whiling nack msg"
+            );
+            span.record_error(&e);
+            span.set_status(Status::Error {
+                description: Cow::from("error to nack msg"),
+            });
+            Err(AmqpError::NackMessageError {})
         }
     }
+}
+
+async fn retry(
+    ctx: &Context,
+    span: &mut BoxedSpan,
+    received: &Received<'_>,
+    queue_def: &QueueDefinition,
+    after: Option<Duration>,
+    channel: Arc<Channel>,
+) -> Result<(), AmqpError> {
+    let delivery = received.delivery;
+
+    //nack msg and remove from queue if there are no retry configured, the msg will be sent to the dlq if configured
+    let Some(retry_name) = &queue_def.retry_name else {
+        return nack(ctx, span, delivery).await;
+    };
 
     //send msg to dlq when count active the max retries
+    if received.count >= queue_def.retries.unwrap_or_default() as i64 {
+        return dead_letter(ctx, span, received, queue_def, "too many attempts", channel).await;
+    }
+
+    received.metrics.retried(received.msg);
+
+    warn!(
+        trace.id = traces::trace_id(ctx),
+        span.id = traces::span_id(ctx),
+        "requeuing msg for latter"
+    );
+
+    //the nack sends the msg to the retry queue which will requeue the msg after the retry queue ttl
+    let Some(after) = after else {
+        return match nack(ctx, span, delivery).await {
+            Err(_) => Err(AmqpError::RequeuingMessageError {}),
+            _ => Ok(()),
+        };
+    };
+
+    //publishing directly in the retry queue with a per-message ttl to respect the requested delay
+    match channel
+        .basic_publish(
+            "",
+            retry_name,
+            BasicPublishOptions::default(),
+            &delivery.data,
+            delivery
+                .properties
+                .clone()
+                .with_expiration(ShortString::from(after.as_millis().to_string())),
+        )
+        .await
+    {
+        Err(e) => {
+            error!(
+                trace.id = traces::trace_id(ctx),
+                span.id = traces::span_id(ctx),
+                "error whiling requeuing"
+            );
+            span.record_error(&e);
+            span.set_status(Status::Error {
+                description: Cow::from("error to requeuing msg"),
+            });
+            Err(AmqpError::RequeuingMessageError {})
+        }
+        _ => ack(ctx, span, delivery).await,
+    }
+}
+
+async fn dead_letter(
+    ctx: &Context,
+    span: &mut BoxedSpan,
+    received: &Received<'_>,
+    queue_def: &QueueDefinition,
+    reason: &str,
+    channel: Arc<Channel>,
+) -> Result<(), AmqpError> {
+    let delivery = received.delivery;
+
+    let Some(dlq_name) = &queue_def.dlq_name else {
+        error!(
+            trace.id = traces::trace_id(ctx),
+            span.id = traces::span_id(ctx),
+            reason = reason,
+            "there is no dlq configured, removing msg from queue"
+        );
+        return ack(ctx, span, delivery).await;
+    };
+
     error!(
-        trace.id = traces::trace_id(&ctx),
-        span.id = traces::span_id(&ctx),
-        "too many attempts, sending to dlq"
+        trace.id = traces::trace_id(ctx),
+        span.id = traces::span_id(ctx),
+        reason = reason,
+        "sending msg to dlq"
+    );
+    received.metrics.dead_lettered(received.msg);
+
+    let mut headers = delivery
+        .properties
+        .headers()
+        .clone()
+        .unwrap_or_default()
+        .inner()
+        .clone();
+    headers.insert(
+        ShortString::from(AMQP_HEADERS_DEAD_LETTER_REASON),
+        AMQPValue::LongString(LongString::from(reason)),
     );
 
     match channel
         .basic_publish(
             "",
-            &dispatcher_def.queue_def.clone().dlq_name.unwrap(),
+            dlq_name,
             BasicPublishOptions::default(),
             &delivery.data,
-            delivery.properties.clone(),
+            delivery
+                .properties
+                .clone()
+                .with_headers(FieldTable::from(headers)),
         )
         .await
     {
         Err(e) => {
             error!(
-                trace.id = traces::trace_id(&ctx),
-                span.id = traces::span_id(&ctx),
+                trace.id = traces::trace_id(ctx),
+                span.id = traces::span_id(ctx),
                 "error whiling sending to dlq"
             );
             span.record_error(&e);
@@ -175,23 +444,120 @@ pub(crate) async fn consume<'c>(
 
             Err(AmqpError::PublishingToDQLError {})
         }
-        _ => match delivery.ack(BasicAckOptions { multiple: false }).await {
-            Err(e) => {
-                error!(
-                    trace.id = traces::trace_id(&ctx),
-                    span.id = traces::span_id(&ctx),
-                    "error whiling ack msg to default queue"
-                );
-                span.record_error(&e);
-                span.set_status(Status::Error {
-                    description: Cow::from("msg was sent to dlq"),
-                });
+        _ => ack(ctx, span, delivery).await,
+    }
+}
 
-                Err(AmqpError::AckMessageError {})
-            }
-            _ => Ok(()),
-        },
+/// The header value of the AMQP field, None for the field tables and the decimals.
+fn header_value(value: &AMQPValue) -> Option<HeaderValue> {
+    let value = match value {
+        AMQPValue::LongString(v) => HeaderValue::LongString(v.to_string()),
+        AMQPValue::ShortString(v) => HeaderValue::ShortString(v.to_string()),
+        AMQPValue::ShortShortInt(v) => HeaderValue::Int(*v),
+        AMQPValue::ShortShortUInt(v) => HeaderValue::Uint(*v),
+        AMQPValue::ShortInt(v) => HeaderValue::ShortInt(*v),
+        AMQPValue::ShortUInt(v) => HeaderValue::ShortUint(*v),
+        AMQPValue::LongInt(v) => HeaderValue::LongInt(*v),
+        AMQPValue::LongUInt(v) => HeaderValue::LongUint(*v),
+        AMQPValue::LongLongInt(v) => HeaderValue::LongLongInt(*v),
+        AMQPValue::Boolean(v) => HeaderValue::Bool(*v),
+        AMQPValue::Float(v) => HeaderValue::Float(*v),
+        AMQPValue::Double(v) => HeaderValue::Double(*v),
+        AMQPValue::ByteArray(v) => HeaderValue::Bytes(v.as_slice().to_vec()),
+        AMQPValue::Timestamp(v) => {
+            HeaderValue::Timestamp(DateTime::from_timestamp(i64::try_from(*v).ok()?, 0)?)
+        }
+        AMQPValue::FieldArray(v) => HeaderValue::Array(
+            v.as_slice()
+                .iter()
+                .map(header_value)
+                .collect::<Option<Vec<_>>>()?,
+        ),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+pub(crate) fn consumer_message(
+    queue: &str,
+    msg_type: &str,
+    delivery: &Delivery,
+) -> ConsumerMessage {
+    let mut headers = HashMap::new();
+
+    let properties = [
+        (MESSAGE_ID_HEADER_KEY, delivery.properties.message_id()),
+        (
+            CORRELATION_ID_HEADER_KEY,
+            delivery.properties.correlation_id(),
+        ),
+        (REPLY_TO_HEADER_KEY, delivery.properties.reply_to()),
+        (
+            CONTENT_ENCODING_HEADER_KEY,
+            delivery.properties.content_encoding(),
+        ),
+    ];
+    for (key, value) in properties {
+        if let Some(value) = value {
+            headers.insert(key.to_owned(), value.to_string());
+        }
     }
+
+    //the headers are exposed as strings, the cloud events attributes with the ce_ prefix
+    let mut typed = HashMap::new();
+    if let Some(table) = delivery.properties.headers() {
+        for (key, value) in table.inner() {
+            let Some(value) = header_value(value) else {
+                continue;
+            };
+
+            let key = match key.as_str().strip_prefix(AMQP_CLOUDEVENTS_PREFIX) {
+                Some(attribute) => format!("{}{}", CE_HEADER_PREFIX, attribute),
+                None => key.to_string(),
+            };
+
+            typed.insert(key, value);
+        }
+    }
+
+    headers::apply_types(&mut typed);
+    for (key, value) in &typed {
+        headers.insert(key.clone(), value.clone().into());
+    }
+
+    let property = |value: &Option<ShortString>| value.as_ref().map(|v| v.to_string());
+    let (_, count) = extract_header_properties(&delivery.properties);
+    let metadata = MessageMetadata {
+        message_id: property(delivery.properties.message_id()),
+        correlation_id: property(delivery.properties.correlation_id()),
+        reply_to: property(delivery.properties.reply_to()),
+        content_type: property(delivery.properties.content_type()),
+        timestamp: delivery
+            .properties
+            .timestamp()
+            .and_then(|secs| DateTime::from_timestamp(secs as i64, 0)),
+        //the x-death count is the number of times the message went through the retry queue
+        delivery_count: count as u32 + 1,
+        redelivered: delivery.redelivered,
+        headers: typed,
+        ..Default::default()
+    };
+
+    let msg = ConsumerMessage::new(
+        queue,
+        msg_type,
+        &delivery.data,
+        (!headers.is_empty()).then_some(headers),
+    )
+    .with_metadata(metadata);
+
+    let content_type = delivery
+        .properties
+        .content_type()
+        .as_ref()
+        .map(|v| v.as_str());
+    cloudevents::parse(compression::decompress(msg), content_type)
 }
 
 fn extract_header_properties(props: &AMQPProperties) -> (String, i64) {
@@ -1,41 +1,108 @@
-use crate::{consumer::consume, queue::QueueDefinition};
+use crate::{
+    connection::ChannelSource,
+    consumer::{consume, consume_batch, Unmatched},
+    queue::QueueDefinition,
+};
 use async_trait::async_trait;
 use futures_util::{future::join_all, StreamExt};
-use lapin::{options::BasicConsumeOptions, types::FieldTable, Channel};
+use lapin::{
+    message::Delivery,
+    options::{BasicCancelOptions, BasicConsumeOptions, BasicQosOptions},
+    types::FieldTable,
+    Channel,
+};
 use messaging::{
+    batch::{self, BatchConsumerHandler},
     dispatcher::{Dispatcher, DispatcherDefinition},
     errors::MessagingError,
-    handler::ConsumerHandler,
+    handler::{ConsumerHandler, Disposition},
+    metrics::MessagingMetrics,
+    shutdown::{drain, CancellationToken, Deadline, DEFAULT_SHUTDOWN_TIMEOUT},
+    workers::Workers,
 };
 use opentelemetry::global;
-use std::{collections::HashMap, sync::Arc};
-use tracing::error;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
 
 #[derive(Clone)]
 pub struct RabbitMQDispatcherDefinition {
     pub(crate) queue_def: QueueDefinition,
     pub(crate) handler: Arc<dyn ConsumerHandler>,
+    pub(crate) concurrency: usize,
+    pub(crate) ordered: bool,
+}
+
+#[derive(Clone)]
+pub struct RabbitMQBatchDispatcherDefinition {
+    pub(crate) queue_def: QueueDefinition,
+    pub(crate) handler: Arc<dyn BatchConsumerHandler>,
+    pub(crate) size: usize,
+    pub(crate) linger: Duration,
 }
 
 pub struct RabbitMQDispatcher {
-    channel: Arc<Channel>,
+    channel: ChannelSource,
     queues_def: Vec<QueueDefinition>,
     pub(crate) dispatchers_def: HashMap<String, RabbitMQDispatcherDefinition>,
+    pub(crate) batch_defs: HashMap<String, RabbitMQBatchDispatcherDefinition>,
+    shutdown_timeout: Duration,
+    unmatched: Disposition,
+    metrics: MessagingMetrics,
+    consuming: AtomicBool,
 }
 
 impl RabbitMQDispatcher {
-    pub fn new(channel: Arc<Channel>, queues_def: Vec<QueueDefinition>) -> Self {
+    /// Consumes from the channel, or from the current channel of an AmqpConnection,
+    /// creating the consumers again after its reconnections.
+    pub fn new<C>(channel: C, queues_def: Vec<QueueDefinition>) -> Self
+    where
+        C: Into<ChannelSource>,
+    {
         RabbitMQDispatcher {
-            channel,
+            channel: channel.into(),
             queues_def,
             dispatchers_def: HashMap::default(),
+            batch_defs: HashMap::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            unmatched: Disposition::Ack,
+            metrics: MessagingMetrics::new("rabbitmq"),
+            consuming: AtomicBool::new(false),
         }
     }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Disposition of the messages with no handler registered for their msg_type,
+    /// defaults to ack, removing them from the queue.
+    pub fn unmatched(mut self, disposition: Disposition) -> Self {
+        self.unmatched = disposition;
+        self
+    }
 }
 
 #[async_trait]
 impl Dispatcher for RabbitMQDispatcher {
     fn register(mut self, def: &DispatcherDefinition, handler: Arc<dyn ConsumerHandler>) -> Self {
+        //the msg_type is the consumer tag, a single handler or a batch handler per msg_type
+        if self.batch_defs.contains_key(&def.msg_type) {
+            error!(
+                msg_type = def.msg_type,
+                "the msg_type already has a batch handler, ignoring the handler"
+            );
+            return self;
+        }
+
         let mut queue_def = QueueDefinition::default();
         for queue in &self.queues_def {
             if def.name == queue.name {
@@ -45,7 +112,45 @@ impl Dispatcher for RabbitMQDispatcher {
 
         self.dispatchers_def.insert(
             def.msg_type.clone(),
-            RabbitMQDispatcherDefinition { queue_def, handler },
+            RabbitMQDispatcherDefinition {
+                queue_def,
+                handler,
+                concurrency: def.concurrency,
+                ordered: def.ordered,
+            },
+        );
+
+        self
+    }
+
+    fn register_batch(
+        mut self,
+        def: &DispatcherDefinition,
+        handler: Arc<dyn BatchConsumerHandler>,
+    ) -> Self {
+        if self.dispatchers_def.contains_key(&def.msg_type) {
+            error!(
+                msg_type = def.msg_type,
+                "the msg_type already has a handler, ignoring the batch handler"
+            );
+            return self;
+        }
+
+        let queue_def = self
+            .queues_def
+            .iter()
+            .find(|queue| queue.name == def.name)
+            .cloned()
+            .unwrap_or_default();
+
+        self.batch_defs.insert(
+            def.msg_type.clone(),
+            RabbitMQBatchDispatcherDefinition {
+                queue_def,
+                handler,
+                size: def.batch_size,
+                linger: def.batch_linger,
+            },
         );
 
         self
@@ -54,18 +159,59 @@ impl Dispatcher for RabbitMQDispatcher {
     async fn consume_blocking(&self) -> Result<(), MessagingError> {
         self.consume_blocking_single().await
     }
+
+    async fn consume_until(&self, shutdown: CancellationToken) -> Result<(), MessagingError> {
+        self.consume(self.consumer_tags(), shutdown).await
+    }
+
+    fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::SeqCst)
+    }
 }
 
 impl RabbitMQDispatcher {
     pub async fn consume_blocking_single(&self) -> Result<(), MessagingError> {
-        let key = self.dispatchers_def.keys().next().unwrap();
-        let def = self.dispatchers_def.get(key).unwrap();
+        let key = self.consumer_tags().into_iter().next().unwrap();
+
+        self.consume(vec![key], CancellationToken::new()).await
+    }
+
+    pub async fn consume_blocking_multi(&self) -> Result<(), MessagingError> {
+        self.consume(self.consumer_tags(), CancellationToken::new())
+            .await
+    }
+
+    fn consumer_tags(&self) -> Vec<String> {
+        self.dispatchers_def
+            .keys()
+            .chain(self.batch_defs.keys())
+            .cloned()
+            .collect()
+    }
 
-        let mut consumer = match self
-            .channel
+    async fn consume_batch(
+        &self,
+        channel: Arc<Channel>,
+        consumer_tag: String,
+        def: RabbitMQBatchDispatcherDefinition,
+        shutdown: CancellationToken,
+        deadline: Deadline,
+        multiple_ack: bool,
+    ) -> Result<JoinHandle<()>, MessagingError> {
+        //the prefetch limits the unacked deliveries to the batch size for the next consumer
+        let prefetch = def.size.min(u16::MAX as usize) as u16;
+        if let Err(err) = channel
+            .basic_qos(prefetch, BasicQosOptions { global: false })
+            .await
+        {
+            error!(error = err.to_string(), "failure to configure the prefetch");
+            return Err(MessagingError::CreatingConsumerError);
+        }
+
+        let consumer = channel
             .basic_consume(
                 &def.queue_def.name,
-                key,
+                &consumer_tag,
                 BasicConsumeOptions {
                     no_local: false,
                     no_ack: false,
@@ -74,58 +220,158 @@ impl RabbitMQDispatcher {
                 },
                 FieldTable::default(),
             )
+            .await;
+
+        if let Err(err) = channel
+            .basic_qos(0, BasicQosOptions { global: false })
             .await
         {
+            error!(error = err.to_string(), "failure to reset the prefetch");
+        }
+
+        let mut consumer = match consumer {
             Err(err) => {
-                error!(error = err.to_string(), "error to create the consumer");
+                error!(error = err.to_string(), "failure to create the consumer");
                 Err(MessagingError::CreatingConsumerError)
             }
             Ok(c) => Ok(c),
         }?;
 
-        let defs = self.dispatchers_def.clone();
-        let channel = self.channel.clone();
+        let metrics = self.metrics.clone();
 
-        let spawned = tokio::spawn({
-            async move {
-                while let Some(result) = consumer.next().await {
-                    match result {
-                        Ok(delivery) => {
-                            if let Err(err) = consume(
-                                &global::tracer("amqp consumer"),
-                                &delivery,
-                                &defs,
-                                channel.clone(),
-                            )
-                            .await
-                            {
-                                error!(error = err.to_string(), "error consume msg")
-                            }
+        Ok(tokio::spawn(async move {
+            let tracer = global::tracer("amqp consumer");
+
+            loop {
+                let received = tokio::select! {
+                    biased;
+                    _ = shutdown.cancelled() => break,
+                    received = batch::collect(&mut consumer, def.size, def.linger) => received,
+                };
+
+                let Some(received) = received else {
+                    break;
+                };
+
+                let deliveries: Vec<Delivery> = received
+                    .into_iter()
+                    .filter_map(|result| match result {
+                        Ok(delivery) => Some(delivery),
+                        Err(err) => {
+                            error!(error = err.to_string(), "errors consume msg");
+                            None
                         }
+                    })
+                    .collect();
 
-                        Err(err) => error!(error = err.to_string(), "errors consume msg"),
-                    }
+                if deliveries.is_empty() && !channel.status().connected() {
+                    break;
+                }
+
+                let consumed = drain(
+                    &deadline,
+                    consume_batch(
+                        &tracer,
+                        &metrics,
+                        &deliveries,
+                        &def,
+                        channel.clone(),
+                        multiple_ack,
+                    ),
+                )
+                .await;
+
+                match consumed {
+                    Some(Err(err)) => error!(error = err.to_string(), "error consume batch"),
+                    None => break,
+                    _ => {}
                 }
             }
-        })
-        .await;
 
-        if spawned.is_err() {
-            return Err(MessagingError::ConsumerError("some error occur".to_owned()));
-        }
+            // stop receiving new deliveries, the prefetched ones are requeued when the channel is closed
+            if let Err(err) = channel
+                .basic_cancel(&consumer_tag, BasicCancelOptions::default())
+                .await
+            {
+                error!(error = err.to_string(), "failure to cancel the consumer");
+            }
 
-        Ok(())
+            debug!(consumer_tag = consumer_tag, "batch consumer stopped");
+        }))
     }
 
-    pub async fn consume_blocking_multi(&self) -> Result<(), MessagingError> {
+    async fn consume(
+        &self,
+        consumer_tags: Vec<String>,
+        shutdown: CancellationToken,
+    ) -> Result<(), MessagingError> {
+        let mut channel = self.channel.channel();
+        //the consumers of every channel share the shutdown timeout
+        let deadline = Deadline::new(&shutdown, self.shutdown_timeout);
+
+        loop {
+            let result = self
+                .consume_channel(
+                    channel.clone(),
+                    consumer_tags.clone(),
+                    shutdown.clone(),
+                    deadline.clone(),
+                )
+                .await;
+            self.consuming.store(false, Ordering::SeqCst);
+
+            //the consumers stop with the channel, being created again in the reconnected one
+            let Some(conn) = self.channel.connection() else {
+                return result;
+            };
+            if shutdown.is_cancelled() || channel.status().connected() {
+                return result;
+            }
+
+            warn!("amqp channel closed, waiting the reconnection to consume again");
+            channel = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                reconnected = conn.reconnected(&channel) => match reconnected {
+                    Some(channel) => channel,
+                    None => return Err(MessagingError::ConnectionError),
+                },
+            };
+        }
+    }
+
+    async fn consume_channel(
+        &self,
+        channel: Arc<Channel>,
+        consumer_tags: Vec<String>,
+        shutdown: CancellationToken,
+        deadline: Deadline,
+    ) -> Result<(), MessagingError> {
         let mut spawns = vec![];
+        //multiple acks are only safe when the channel has no other consumer
+        let multiple_ack = consumer_tags.len() == 1;
+
+        for consumer_tag in consumer_tags {
+            if let Some(def) = self.batch_defs.get(&consumer_tag) {
+                spawns.push(
+                    self.consume_batch(
+                        channel.clone(),
+                        consumer_tag,
+                        def.clone(),
+                        shutdown.clone(),
+                        deadline.clone(),
+                        multiple_ack,
+                    )
+                    .await?,
+                );
+                continue;
+            }
+
+            let def = self.dispatchers_def.get(&consumer_tag).unwrap();
 
-        for (consumer_tag, def) in &self.dispatchers_def {
-            let mut consumer = match self
-                .channel
+            let mut consumer = match channel
                 .basic_consume(
                     &def.queue_def.name,
-                    consumer_tag,
+                    &consumer_tag,
                     BasicConsumeOptions {
                         no_local: false,
                         no_ack: false,
@@ -144,32 +390,99 @@ impl RabbitMQDispatcher {
             }?;
 
             let defs = self.dispatchers_def.clone();
-            let channel = self.channel.clone();
+            let channel = channel.clone();
+            let shutdown = shutdown.clone();
+            let deadline = deadline.clone();
+            let metrics = self.metrics.clone();
+            let unmatched = Unmatched {
+                queue_def: def.queue_def.clone(),
+                disposition: self.unmatched.clone(),
+            };
+            let (concurrency, ordered) = (def.concurrency, def.ordered);
 
             spawns.push(tokio::spawn({
                 async move {
-                    while let Some(result) = consumer.next().await {
-                        match result {
-                            Ok(delivery) => {
-                                if let Err(err) = consume(
-                                    &global::tracer("amqp consumer"),
-                                    &delivery,
-                                    &defs,
-                                    channel.clone(),
-                                )
-                                .await
-                                {
-                                    error!(error = err.to_string(), "error consume msg");
+                    let tracer = global::tracer("amqp consumer");
+                    let mut workers = Workers::new();
+
+                    loop {
+                        // backpressure, waits a free worker before receiving the next delivery
+                        let reserved =
+                            drain(&deadline, workers.reserve(&consumer_tag, concurrency, None))
+                                .await;
+                        if reserved.is_none() {
+                            break;
+                        }
+
+                        let result = tokio::select! {
+                            biased;
+                            _ = shutdown.cancelled() => break,
+                            Some(_) = workers.next() => continue,
+                            result = consumer.next() => result,
+                        };
+
+                        let Some(result) = result else {
+                            break;
+                        };
+
+                        let delivery = match result {
+                            Ok(delivery) => delivery,
+                            Err(err) => {
+                                error!(error = err.to_string(), "errors consume msg");
+                                if !channel.status().connected() {
+                                    break;
                                 }
+                                continue;
                             }
+                        };
 
-                            Err(err) => error!(error = err.to_string(), "errors consume msg"),
+                        let key = ordered.then(|| delivery.routing_key.to_string());
+                        let reserved = drain(
+                            &deadline,
+                            workers.reserve(&consumer_tag, concurrency, key.as_deref()),
+                        )
+                        .await;
+                        if reserved.is_none() {
+                            break;
                         }
+
+                        let (tracer, metrics, defs, unmatched, channel) =
+                            (&tracer, &metrics, &defs, &unmatched, &channel);
+                        workers.spawn(&consumer_tag, key.as_deref(), async move {
+                            if let Err(err) = consume(
+                                tracer,
+                                metrics,
+                                &delivery,
+                                defs,
+                                unmatched,
+                                channel.clone(),
+                            )
+                            .await
+                            {
+                                error!(error = err.to_string(), "error consume msg")
+                            }
+                        });
+                    }
+
+                    // the abandoned deliveries are not acked, being requeued when the channel is closed
+                    drain(&deadline, workers.wait_all()).await;
+
+                    // stop receiving new deliveries, the prefetched ones are requeued when the channel is closed
+                    if let Err(err) = channel
+                        .basic_cancel(&consumer_tag, BasicCancelOptions::default())
+                        .await
+                    {
+                        error!(error = err.to_string(), "failure to cancel the consumer");
                     }
+
+                    debug!(consumer_tag = consumer_tag, "consumer stopped");
                 }
             }));
         }
 
+        //all the consumers are created, the deliveries are being received
+        self.consuming.store(true, Ordering::SeqCst);
+
         let spawned = join_all(spawns).await;
         for res in spawned {
             if res.is_err() {
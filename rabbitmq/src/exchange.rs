@@ -3,6 +3,10 @@ use lapin::types::{AMQPValue, LongString, ShortString};
 use std::collections::BTreeMap;
 
 pub const AMQP_HEADERS_DELAYED_EXCHANGE_TYPE: &str = "x-delayed-type";
+pub const AMQP_HEADERS_DELAY: &str = "x-delay";
+
+/// Suffix of the exchange and queue holding the delayed messages until their expiration.
+pub const DELAY_QUEUE_SUFFIX: &str = "-delay";
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum ExchangeKind {
@@ -40,6 +44,7 @@ pub struct ExchangeDefinition<'ex> {
     pub(crate) internal: bool,
     pub(crate) no_wait: bool,
     pub(crate) params: BTreeMap<ShortString, AMQPValue>,
+    pub(crate) delay_queue: bool,
 }
 
 impl<'ex> ExchangeDefinition<'ex> {
@@ -53,6 +58,7 @@ impl<'ex> ExchangeDefinition<'ex> {
             internal: false,
             no_wait: false,
             params: BTreeMap::default(),
+            delay_queue: false,
         }
     }
 
@@ -89,6 +95,13 @@ impl<'ex> ExchangeDefinition<'ex> {
         self
     }
 
+    /// Declares the `{name}-delay` exchange and queue used to delay the messages with TTL and
+    /// dead lettering, for the brokers without the delayed message exchange plugin.
+    pub fn delay_queue(mut self) -> Self {
+        self.delay_queue = true;
+        self
+    }
+
     pub fn params(mut self, params: BTreeMap<ShortString, AMQPValue>) -> Self {
         self.params = params;
         self
@@ -1,10 +1,15 @@
 mod consumer;
 mod otel;
 
+#[cfg(feature = "asyncapi")]
+pub mod asyncapi;
+
 pub mod channel;
+pub mod connection;
 pub mod dispatcher;
 pub mod errors;
 pub mod exchange;
 pub mod publisher;
 pub mod queue;
+pub mod request_reply;
 pub mod topology;
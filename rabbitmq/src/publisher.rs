@@ -1,15 +1,24 @@
-use crate::otel::RabbitMQTracePropagator;
+use crate::{
+    connection::ChannelSource,
+    exchange::{AMQP_HEADERS_DELAY, DELAY_QUEUE_SUFFIX},
+    otel::RabbitMQTracePropagator,
+};
 use async_trait::async_trait;
 use lapin::{
-    options::BasicPublishOptions,
-    types::{
-        AMQPValue, FieldTable, LongInt, LongLongInt, LongString, LongUInt, ShortInt, ShortString,
-    },
-    BasicProperties, Channel,
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, ByteArray, FieldArray, FieldTable, LongLongInt, LongString, ShortString},
+    BasicProperties,
 };
 use messaging::{
+    cloudevents::CE_HEADER_PREFIX,
+    compression::{self, CONTENT_ENCODING_HEADER_KEY},
     errors::MessagingError,
-    publisher::{HeaderValues, PublishMessage, Publisher},
+    handler::MESSAGE_ID_HEADER_KEY,
+    headers::HEADER_TYPES_KEY,
+    metrics::MessagingMetrics,
+    publisher::{HeaderValue, PublishMessage, Publisher},
+    request_reply::{CORRELATION_ID_HEADER_KEY, REPLY_TO_HEADER_KEY},
 };
 use opentelemetry::{global, Context};
 use std::{
@@ -21,19 +30,122 @@ use uuid::Uuid;
 
 pub const JSON_CONTENT_TYPE: &str = "application/json";
 
+/// Prefix of the CloudEvents attributes in the AMQP application properties.
+pub const AMQP_CLOUDEVENTS_PREFIX: &str = "cloudEvents:";
+
+/// Pseudo queue used by the RabbitMQ direct reply-to.
+pub const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+/// Header overriding the confirm mode of the publisher for the message, with the values
+/// none, confirm or mandatory. It is not sent to the broker.
+pub const CONFIRM_HEADER_KEY: &str = "rabbitmq-confirm";
+
+/// How the scheduled messages are delayed until their deliver_at time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DelayStrategy {
+    /// Sets the `x-delay` header, requiring the destination exchange to be a delayed
+    /// message exchange (ExchangeDefinition::direct_delead or fanout_delead).
+    #[default]
+    DelayedExchange,
+    /// Publishes in the `{to}-delay` exchange with the message expiration, requiring the
+    /// destination exchange to be declared with ExchangeDefinition::delay_queue.
+    ///
+    /// Messages only expire at the head of the delay queue, so a message is not delivered
+    /// before the messages published earlier with a longer delay.
+    DelayQueue,
+}
+
+/// Whether the publish waits the broker confirmation, enabling the confirm mode of the
+/// channel the first time it is needed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConfirmMode {
+    /// Returns once the message is written in the channel.
+    #[default]
+    None,
+    /// Waits the broker ack, failing with MessagingError::PublishNackedError when the
+    /// broker nacks the message.
+    Confirm,
+    /// Waits the broker ack of a mandatory publish, failing with
+    /// MessagingError::UnroutableError when no queue is bound to the routing key.
+    ///
+    /// The delayed message exchanges return all the mandatory messages, the delayed
+    /// messages are only confirmed.
+    Mandatory,
+}
+
+impl ConfirmMode {
+    pub fn parse(value: &str) -> Option<ConfirmMode> {
+        match value {
+            "none" => Some(ConfirmMode::None),
+            "confirm" => Some(ConfirmMode::Confirm),
+            "mandatory" => Some(ConfirmMode::Mandatory),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublisherOptions {
+    pub delay: DelayStrategy,
+    pub confirm: ConfirmMode,
+}
+
 pub struct RabbitMQPublisher {
-    channel: Arc<Channel>,
+    channel: ChannelSource,
+    metrics: MessagingMetrics,
+    delay: DelayStrategy,
+    confirm: ConfirmMode,
 }
 
 impl RabbitMQPublisher {
-    pub fn new(channel: Arc<Channel>) -> Arc<RabbitMQPublisher> {
-        Arc::new(RabbitMQPublisher { channel })
+    /// Publishes in the channel, or in the current channel of an AmqpConnection, following
+    /// its reconnections.
+    pub fn new<C>(channel: C) -> Arc<RabbitMQPublisher>
+    where
+        C: Into<ChannelSource>,
+    {
+        RabbitMQPublisher::with_delay_strategy(channel, DelayStrategy::default())
+    }
+
+    pub fn with_delay_strategy<C>(channel: C, delay: DelayStrategy) -> Arc<RabbitMQPublisher>
+    where
+        C: Into<ChannelSource>,
+    {
+        RabbitMQPublisher::with_options(
+            channel,
+            PublisherOptions {
+                delay,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_options<C>(channel: C, options: PublisherOptions) -> Arc<RabbitMQPublisher>
+    where
+        C: Into<ChannelSource>,
+    {
+        Arc::new(RabbitMQPublisher {
+            channel: channel.into(),
+            metrics: MessagingMetrics::new("rabbitmq"),
+            delay: options.delay,
+            confirm: options.confirm,
+        })
     }
 }
 
 #[async_trait]
 impl Publisher for RabbitMQPublisher {
     async fn publish(&self, ctx: &Context, infos: &PublishMessage) -> Result<(), MessagingError> {
+        self.metrics.publish(infos, self.send(ctx, infos)).await
+    }
+}
+
+impl RabbitMQPublisher {
+    async fn send(&self, ctx: &Context, infos: &PublishMessage) -> Result<(), MessagingError> {
+        let compressed = compression::compress(infos)?;
+        let infos = compressed.as_ref();
+        let delay = infos.delay();
+
         let mut btree = BTreeMap::<ShortString, AMQPValue>::default();
 
         global::get_text_map_propagator(|propagator| {
@@ -44,21 +156,92 @@ impl Publisher for RabbitMQPublisher {
             self.btree_map(&infos.headers.clone().unwrap(), &mut btree);
         }
 
-        match self
-            .channel
+        if let (Some(delay), DelayStrategy::DelayedExchange) = (delay, self.delay) {
+            btree.insert(
+                ShortString::from(AMQP_HEADERS_DELAY),
+                AMQPValue::LongLongInt(delay.as_millis() as LongLongInt),
+            );
+        }
+
+        let content_type = match &infos.content_type {
+            Some(content_type) => content_type.as_str(),
+            _ => JSON_CONTENT_TYPE,
+        };
+
+        let header = |key: &str| -> Option<String> {
+            infos
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(key))
+                .map(|value| value.clone().into())
+        };
+
+        let message_id =
+            header(MESSAGE_ID_HEADER_KEY).unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let mut properties = BasicProperties::default()
+            .with_content_type(ShortString::from(content_type))
+            .with_kind(ShortString::from(infos.msg_type.clone()))
+            .with_message_id(ShortString::from(message_id))
+            .with_headers(FieldTable::from(btree));
+
+        if let Some(correlation_id) = header(CORRELATION_ID_HEADER_KEY) {
+            properties = properties.with_correlation_id(ShortString::from(correlation_id));
+        }
+
+        if let Some(reply_to) = header(REPLY_TO_HEADER_KEY) {
+            properties = properties.with_reply_to(ShortString::from(reply_to));
+        }
+
+        if let Some(encoding) = header(CONTENT_ENCODING_HEADER_KEY) {
+            properties = properties.with_content_encoding(ShortString::from(encoding));
+        }
+
+        //the direct reply-to replies are published in the default exchange
+        let (mut exchange, routing_key) = match infos.to.starts_with(DIRECT_REPLY_TO) {
+            true => (String::new(), infos.to.as_str()),
+            _ => (infos.to.clone(), infos.key.as_str()),
+        };
+
+        if let (Some(delay), DelayStrategy::DelayQueue) = (delay, self.delay) {
+            exchange = format!("{}{}", exchange, DELAY_QUEUE_SUFFIX);
+            properties =
+                properties.with_expiration(ShortString::from(delay.as_millis().to_string()));
+        }
+
+        let confirm = confirm_mode(
+            header(CONFIRM_HEADER_KEY),
+            self.confirm,
+            delay.is_some() && self.delay == DelayStrategy::DelayedExchange,
+        );
+
+        let channel = self.channel.channel();
+        if confirm != ConfirmMode::None && !channel.status().confirm() {
+            match channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await
+            {
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        "failure to enable the publisher confirms"
+                    );
+                    Err(MessagingError::PublisherError)
+                }
+                Ok(()) => Ok(()),
+            }?;
+        }
+
+        let confirmation = match channel
             .basic_publish(
-                &infos.to,
-                &infos.key,
+                &exchange,
+                routing_key,
                 BasicPublishOptions {
                     immediate: false,
-                    mandatory: false,
+                    mandatory: confirm == ConfirmMode::Mandatory,
                 },
                 &infos.data,
-                BasicProperties::default()
-                    .with_content_type(ShortString::from(JSON_CONTENT_TYPE))
-                    .with_kind(ShortString::from(infos.msg_type.clone()))
-                    .with_message_id(ShortString::from(Uuid::new_v4().to_string()))
-                    .with_headers(FieldTable::from(btree)),
+                properties,
             )
             .await
         {
@@ -66,30 +249,233 @@ impl Publisher for RabbitMQPublisher {
                 error!(error = err.to_string(), "error publishing message");
                 Err(MessagingError::PublisherError)
             }
-            _ => Ok(()),
+            Ok(confirmation) => Ok(confirmation),
+        }?;
+
+        if confirm == ConfirmMode::None {
+            return Ok(());
+        }
+
+        match confirmation.await {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "error waiting the publisher confirm"
+                );
+                Err(MessagingError::PublisherError)
+            }
+            Ok(confirmation) => Confirmed::from(confirmation).result(&exchange, routing_key),
         }
     }
-}
 
-impl RabbitMQPublisher {
     fn btree_map(
         &self,
-        hash_map: &HashMap<String, HeaderValues>,
+        hash_map: &HashMap<String, HeaderValue>,
         btree: &mut BTreeMap<ShortString, AMQPValue>,
     ) {
+        let mut types = BTreeMap::new();
+
         for (key, value) in hash_map.clone() {
-            let amqp_value = match value {
-                HeaderValues::ShortString(v) => AMQPValue::ShortString(ShortString::from(v)),
-                HeaderValues::LongString(v) => AMQPValue::LongString(LongString::from(v)),
-                HeaderValues::Int(v) => AMQPValue::ShortInt(ShortInt::from(v)),
-                HeaderValues::LongInt(v) => AMQPValue::LongInt(LongInt::from(v)),
-                HeaderValues::LongLongInt(v) => AMQPValue::LongLongInt(LongLongInt::from(v)),
-                HeaderValues::Uint(v) => AMQPValue::LongUInt(LongUInt::from(v)),
-                HeaderValues::LongUint(v) => AMQPValue::LongUInt(LongUInt::from(v)),
-                HeaderValues::LongLongUint(v) => AMQPValue::LongUInt(LongUInt::from(v as u32)),
+            if key == MESSAGE_ID_HEADER_KEY
+                || key == CORRELATION_ID_HEADER_KEY
+                || key == REPLY_TO_HEADER_KEY
+                || key == CONTENT_ENCODING_HEADER_KEY
+                || key == CONFIRM_HEADER_KEY
+            {
+                continue;
+            }
+
+            //the values with no AMQP equivalent go as strings typed by the header-types header
+            let amqp_value = match amqp_value(&value) {
+                Some(amqp_value) => amqp_value,
+                None => {
+                    if let Some(type_name) = value.type_name() {
+                        types.insert(key.clone(), type_name);
+                    }
+                    AMQPValue::LongString(LongString::from(String::from(value)))
+                }
+            };
+
+            let key = match key.strip_prefix(CE_HEADER_PREFIX) {
+                Some(attribute) => format!("{}{}", AMQP_CLOUDEVENTS_PREFIX, attribute),
+                _ => key,
             };
 
             btree.insert(ShortString::from(key), amqp_value);
         }
+
+        if !types.is_empty() {
+            let types = serde_json::to_string(&types).unwrap_or_default();
+            btree.insert(
+                ShortString::from(HEADER_TYPES_KEY),
+                AMQPValue::LongString(LongString::from(types)),
+            );
+        }
+    }
+}
+
+/// The confirm mode of the message, the delayed message exchanges return all the mandatory
+/// messages so the delayed messages are only confirmed.
+fn confirm_mode(header: Option<String>, default: ConfirmMode, delayed: bool) -> ConfirmMode {
+    let confirm = header
+        .and_then(|mode| ConfirmMode::parse(&mode))
+        .unwrap_or(default);
+
+    match (confirm, delayed) {
+        (ConfirmMode::Mandatory, true) => ConfirmMode::Confirm,
+        _ => confirm,
+    }
+}
+
+/// Outcome of the broker confirmation of a message.
+#[derive(Debug, PartialEq)]
+enum Confirmed {
+    Acked,
+    Nacked,
+    Returned { reply_code: u16, reply_text: String },
+}
+
+impl From<Confirmation> for Confirmed {
+    fn from(confirmation: Confirmation) -> Self {
+        match confirmation {
+            Confirmation::Nack(_) => Confirmed::Nacked,
+            //the unroutable mandatory messages are returned before being acked
+            Confirmation::Ack(Some(returned)) => Confirmed::Returned {
+                reply_code: returned.reply_code,
+                reply_text: returned.reply_text.to_string(),
+            },
+            _ => Confirmed::Acked,
+        }
+    }
+}
+
+impl Confirmed {
+    fn result(self, exchange: &str, routing_key: &str) -> Result<(), MessagingError> {
+        match self {
+            Confirmed::Acked => Ok(()),
+            Confirmed::Nacked => {
+                error!(
+                    exchange = exchange,
+                    routing_key = routing_key,
+                    "message nacked by the broker"
+                );
+                Err(MessagingError::PublishNackedError)
+            }
+            Confirmed::Returned {
+                reply_code,
+                reply_text,
+            } => {
+                error!(
+                    exchange = exchange,
+                    routing_key = routing_key,
+                    reply_code = reply_code,
+                    reply_text = reply_text,
+                    "message returned by the broker"
+                );
+                Err(MessagingError::UnroutableError(reply_text))
+            }
+        }
+    }
+}
+
+/// The AMQP field value carrying the header with no loss, None when there is none.
+fn amqp_value(value: &HeaderValue) -> Option<AMQPValue> {
+    let amqp_value = match value {
+        HeaderValue::ShortString(v) => AMQPValue::ShortString(ShortString::from(v.clone())),
+        HeaderValue::LongString(v) => AMQPValue::LongString(LongString::from(v.clone())),
+        HeaderValue::Int(v) => AMQPValue::ShortShortInt(*v),
+        HeaderValue::ShortInt(v) => AMQPValue::ShortInt(*v),
+        HeaderValue::LongInt(v) => AMQPValue::LongInt(*v),
+        HeaderValue::LongLongInt(v) => AMQPValue::LongLongInt(*v),
+        HeaderValue::Uint(v) => AMQPValue::ShortShortUInt(*v),
+        HeaderValue::ShortUint(v) => AMQPValue::ShortUInt(*v),
+        HeaderValue::LongUint(v) => AMQPValue::LongUInt(*v),
+        HeaderValue::Bool(v) => AMQPValue::Boolean(*v),
+        HeaderValue::Float(v) => AMQPValue::Float(*v),
+        HeaderValue::Double(v) => AMQPValue::Double(*v),
+        HeaderValue::Bytes(v) => AMQPValue::ByteArray(ByteArray::from(v.clone())),
+        //AMQP timestamps are whole seconds since the epoch
+        HeaderValue::Timestamp(v) if v.timestamp_subsec_nanos() == 0 => {
+            AMQPValue::Timestamp(u64::try_from(v.timestamp()).ok()?)
+        }
+        HeaderValue::Array(values) => {
+            let values = values.iter().map(amqp_value).collect::<Option<Vec<_>>>()?;
+            AMQPValue::FieldArray(FieldArray::from(values))
+        }
+        //RabbitMQ has no unsigned 64 bits field type
+        HeaderValue::LongLongUint(_) | HeaderValue::Timestamp(_) => return None,
+    };
+
+    Some(amqp_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_override_the_confirm_mode_with_the_header() {
+        let header = |mode: &str| Some(mode.to_owned());
+
+        assert_eq!(
+            confirm_mode(header("mandatory"), ConfirmMode::None, false),
+            ConfirmMode::Mandatory
+        );
+        assert_eq!(
+            confirm_mode(header("none"), ConfirmMode::Confirm, false),
+            ConfirmMode::None
+        );
+        assert_eq!(
+            confirm_mode(header("unknown"), ConfirmMode::Confirm, false),
+            ConfirmMode::Confirm
+        );
+        assert_eq!(
+            confirm_mode(None, ConfirmMode::Confirm, false),
+            ConfirmMode::Confirm
+        );
+    }
+
+    #[test]
+    fn should_only_confirm_the_mandatory_delayed_messages() {
+        assert_eq!(
+            confirm_mode(None, ConfirmMode::Mandatory, true),
+            ConfirmMode::Confirm
+        );
+        assert_eq!(
+            confirm_mode(Some("mandatory".to_owned()), ConfirmMode::None, true),
+            ConfirmMode::Confirm
+        );
+    }
+
+    #[test]
+    fn should_succeed_when_the_broker_acks() {
+        let confirmed = Confirmed::from(Confirmation::Ack(None));
+
+        assert_eq!(confirmed, Confirmed::Acked);
+        assert!(confirmed.result("orders", "created").is_ok());
+    }
+
+    #[test]
+    fn should_fail_when_the_broker_nacks() {
+        let confirmed = Confirmed::from(Confirmation::Nack(None));
+
+        assert_eq!(confirmed, Confirmed::Nacked);
+        assert!(matches!(
+            confirmed.result("orders", "created"),
+            Err(MessagingError::PublishNackedError)
+        ));
+    }
+
+    #[test]
+    fn should_fail_when_the_broker_returns_the_message() {
+        let confirmed = Confirmed::Returned {
+            reply_code: 312,
+            reply_text: "NO_ROUTE".to_owned(),
+        };
+
+        assert!(matches!(
+            confirmed.result("orders", "created"),
+            Err(MessagingError::UnroutableError(reply_text)) if reply_text == "NO_ROUTE"
+        ));
     }
 }
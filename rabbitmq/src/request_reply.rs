@@ -0,0 +1,144 @@
+use crate::{
+    connection::ChannelSource,
+    consumer::consumer_message,
+    publisher::{RabbitMQPublisher, DIRECT_REPLY_TO},
+};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use lapin::{options::BasicConsumeOptions, types::FieldTable, Channel, Consumer};
+use messaging::{
+    errors::MessagingError,
+    handler::ConsumerMessage,
+    publisher::PublishMessage,
+    request_reply::{PendingReplies, RequestReplyClient},
+};
+use opentelemetry::Context;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+/// RequestReplyClient using the RabbitMQ direct reply-to, with no reply queue to be declared.
+///
+/// The channel must be exclusive to the client, the replies are only delivered to the
+/// channel which published the requests. With an AmqpConnection the connection must be
+/// exclusive to the client, and the reply consumer is created again in the channel of each
+/// reconnection.
+pub struct RabbitMQRequestReplyClient {
+    publisher: Arc<RabbitMQPublisher>,
+    pending: Arc<PendingReplies>,
+}
+
+impl RabbitMQRequestReplyClient {
+    pub async fn new<C>(channel: C) -> Result<Arc<Self>, MessagingError>
+    where
+        C: Into<ChannelSource>,
+    {
+        let source = channel.into();
+        let channel = source.channel();
+
+        //the direct reply-to consumer must be created before the first request
+        let consumer = reply_consumer(&channel).await?;
+        let pending = PendingReplies::new();
+
+        tokio::spawn(consume_replies(
+            source.clone(),
+            channel,
+            consumer,
+            pending.clone(),
+        ));
+
+        Ok(Arc::new(RabbitMQRequestReplyClient {
+            publisher: RabbitMQPublisher::new(source),
+            pending,
+        }))
+    }
+}
+
+//in no ack mode, as required by the direct reply-to
+async fn reply_consumer(channel: &Channel) -> Result<Consumer, MessagingError> {
+    match channel
+        .basic_consume(
+            DIRECT_REPLY_TO,
+            "",
+            BasicConsumeOptions {
+                no_local: false,
+                no_ack: true,
+                exclusive: false,
+                nowait: false,
+            },
+            FieldTable::default(),
+        )
+        .await
+    {
+        Err(err) => {
+            error!(error = err.to_string(), "failure to consume the replies");
+            Err(MessagingError::CreatingConsumerError)
+        }
+        Ok(c) => Ok(c),
+    }
+}
+
+async fn consume_replies(
+    source: ChannelSource,
+    mut channel: Arc<Channel>,
+    mut consumer: Consumer,
+    pending: Arc<PendingReplies>,
+) {
+    loop {
+        while let Some(result) = consumer.next().await {
+            match result {
+                Ok(delivery) => {
+                    let msg_type = delivery
+                        .properties
+                        .kind()
+                        .as_ref()
+                        .map(|kind| kind.to_string())
+                        .unwrap_or_default();
+
+                    pending.complete(consumer_message(DIRECT_REPLY_TO, &msg_type, &delivery));
+                }
+                Err(err) => error!(error = err.to_string(), "errors consume reply"),
+            }
+        }
+
+        //the consumer stops with the channel, being created again in the reconnected one,
+        //the requests waiting in the closed channel time out
+        let Some(conn) = source.connection() else {
+            break;
+        };
+        if channel.status().connected() {
+            break;
+        }
+
+        warn!("amqp channel closed, waiting the reconnection to consume the replies");
+        loop {
+            channel = match conn.reconnected(&channel).await {
+                Some(channel) => channel,
+                None => {
+                    debug!("reply consumer stopped");
+                    return;
+                }
+            };
+
+            if let Ok(reconnected) = reply_consumer(&channel).await {
+                consumer = reconnected;
+                break;
+            }
+        }
+    }
+
+    debug!("reply consumer stopped");
+}
+
+#[async_trait]
+impl RequestReplyClient for RabbitMQRequestReplyClient {
+    async fn request(
+        &self,
+        ctx: &Context,
+        msg: &PublishMessage,
+        timeout: Duration,
+    ) -> Result<ConsumerMessage, MessagingError> {
+        self.pending
+            .request(self.publisher.as_ref(), ctx, msg, DIRECT_REPLY_TO, timeout)
+            .await
+    }
+}
@@ -1,11 +1,11 @@
 use crate::{
     errors::AmqpError,
-    exchange::{ExchangeBinding, ExchangeDefinition},
+    exchange::{ExchangeBinding, ExchangeDefinition, DELAY_QUEUE_SUFFIX},
     queue::{QueueBinding, QueueDefinition},
 };
 use async_trait::async_trait;
 use lapin::{
-    options::{QueueBindOptions, QueueDeclareOptions},
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
     types::{AMQPValue, FieldTable, LongInt, LongString, ShortString},
     Channel,
 };
@@ -88,7 +88,7 @@ impl<'tp> AmqpTopology<'tp> {
                 .exchange_declare(
                     exch.name,
                     exch.kind.clone().try_into().unwrap(),
-                    lapin::options::ExchangeDeclareOptions {
+                    ExchangeDeclareOptions {
                         passive: exch.passive,
                         durable: exch.durable,
                         auto_delete: exch.delete,
@@ -111,11 +111,97 @@ impl<'tp> AmqpTopology<'tp> {
             }?;
 
             debug!("exchange: {} was created", exch.name);
+
+            if exch.delay_queue {
+                self.declare_delay_queue(exch).await?;
+            }
         }
 
         Ok(())
     }
 
+    /// The delayed messages are published in the delay exchange with the message expiration,
+    /// and when expired they are dead lettered to the exchange keeping their routing key.
+    async fn declare_delay_queue(&self, exch: &ExchangeDefinition<'_>) -> Result<(), AmqpError> {
+        let delay_name = format!("{}{}", exch.name, DELAY_QUEUE_SUFFIX);
+
+        match self
+            .channel
+            .exchange_declare(
+                &delay_name,
+                lapin::ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    passive: exch.passive,
+                    durable: exch.durable,
+                    auto_delete: exch.delete,
+                    internal: false,
+                    nowait: exch.no_wait,
+                },
+                FieldTable::default(),
+            )
+            .await
+        {
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    name = delay_name,
+                    "error to declare the delay exchange"
+                );
+                Err(AmqpError::DeclareExchangeError(err.to_string()))
+            }
+            _ => Ok(()),
+        }?;
+
+        let mut args = BTreeMap::new();
+        args.insert(
+            ShortString::from(AMQP_HEADERS_DEAD_LETTER_EXCHANGE),
+            AMQPValue::LongString(LongString::from(exch.name)),
+        );
+
+        match self
+            .channel
+            .queue_declare(
+                &delay_name,
+                QueueDeclareOptions {
+                    passive: exch.passive,
+                    durable: exch.durable,
+                    exclusive: false,
+                    auto_delete: exch.delete,
+                    nowait: exch.no_wait,
+                },
+                FieldTable::from(args),
+            )
+            .await
+        {
+            Err(err) => {
+                error!(error = err.to_string(), "failure to declare delay queue");
+                Err(AmqpError::DeclareQueueError(delay_name.clone()))
+            }
+            _ => Ok(()),
+        }?;
+
+        match self
+            .channel
+            .queue_bind(
+                &delay_name,
+                &delay_name,
+                "",
+                QueueBindOptions { nowait: false },
+                FieldTable::default(),
+            )
+            .await
+        {
+            Err(err) => {
+                error!(error = err.to_string(), "error to bind the delay queue");
+                Err(AmqpError::BindingExchangeToQueueError(
+                    delay_name.clone(),
+                    delay_name,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
     async fn install_queue(&self) -> Result<(), AmqpError> {
         for (name, def) in self.queues.clone() {
             debug!("creating queue: {}", name);